@@ -0,0 +1,440 @@
+use crate::bytecode::Program;
+use crate::diagnostic::Diagnostic;
+use crate::lex::lex_with_spans;
+use crate::opcode::OpCode;
+use crate::parse::{parse_float, parse_int};
+use crate::span::Span;
+use crate::token::Token;
+
+// Textual form of op codes, one instruction per line:
+//
+//   load_int r1, 5
+//   store s0, r1
+//   add r1, r2, r3
+//   call_host r1, "sqrt", r1, 1
+//   jump_if_false r1, 7
+//
+// Registers are written rN and variable slots sN, and jumps go to the index
+// of an op code. Literals and `//` comments are the same as in bee source.
+pub fn disassemble(op_codes: &[OpCode]) -> String {
+    let mut output = String::new();
+    for op_code in op_codes {
+        output.push_str(&disassemble_op_code(op_code));
+        output.push('\n');
+    }
+    output
+}
+
+fn disassemble_op_code(op_code: &OpCode) -> String {
+    match op_code {
+        OpCode::And { arg1, arg2, arg3 } => format!("and r{}, r{}, r{}", arg1, arg2, arg3),
+        OpCode::Or { arg1, arg2, arg3 } => format!("or r{}, r{}, r{}", arg1, arg2, arg3),
+        OpCode::Not { value } => format!("not r{}", value),
+        OpCode::Add { arg1, arg2, arg3 } => format!("add r{}, r{}, r{}", arg1, arg2, arg3),
+        OpCode::Sub { arg1, arg2, arg3 } => format!("sub r{}, r{}, r{}", arg1, arg2, arg3),
+        OpCode::Concat { arg1, arg2, arg3 } => format!("concat r{}, r{}, r{}", arg1, arg2, arg3),
+        OpCode::Load { arg1, arg2 } => format!("load r{}, s{}", arg1, arg2),
+        OpCode::LoadIntConst { arg1, arg2 } => format!("load_int r{}, {}", arg1, arg2),
+        OpCode::LoadFloatConst { arg1, arg2 } => format!("load_float r{}, {:?}", arg1, arg2),
+        OpCode::LoadStringConst { arg1, arg2 } => {
+            format!("load_string r{}, {}", arg1, escape(arg2))
+        }
+        OpCode::LoadBoolConst { arg1, arg2 } => format!("load_bool r{}, {}", arg1, bool(*arg2)),
+        OpCode::Move { arg1, arg2 } => format!("move r{}, r{}", arg1, arg2),
+        OpCode::Store { arg1, arg2 } => format!("store s{}, r{}", arg1, arg2),
+        OpCode::StoreIntConst { arg1, arg2 } => format!("store_int s{}, {}", arg1, arg2),
+        OpCode::StoreFloatConst { arg1, arg2 } => format!("store_float s{}, {:?}", arg1, arg2),
+        OpCode::StoreStringConst { arg1, arg2 } => {
+            format!("store_string s{}, {}", arg1, escape(arg2))
+        }
+        OpCode::StoreBoolConst { arg1, arg2 } => format!("store_bool s{}, {}", arg1, bool(*arg2)),
+        OpCode::Print { arg1 } => format!("print r{}", arg1),
+        OpCode::Jump { target } => format!("jump {}", target),
+        OpCode::JumpIfFalse { arg1, target } => format!("jump_if_false r{}, {}", arg1, target),
+        OpCode::JumpIfTrue { arg1, target } => format!("jump_if_true r{}, {}", arg1, target),
+        OpCode::CallHost {
+            name,
+            arg1,
+            arg2,
+            arg3,
+        } => format!("call_host r{}, {}, r{}, {}", arg1, escape(name), arg2, arg3),
+        OpCode::Halt => "halt".to_owned(),
+    }
+}
+
+fn bool(value: bool) -> &'static str {
+    if value {
+        "True"
+    } else {
+        "False"
+    }
+}
+
+// Unlike the formatter, newlines are escaped to keep one instruction per line
+fn escape(value: &str) -> String {
+    let mut output = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\t' => output.push_str("\\t"),
+            '\r' => output.push_str("\\r"),
+            c if c.is_control() => output.push_str(&format!("\\u{{{:X}}}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+struct Assembler<'a> {
+    tokens: &'a [Token],
+    spans: &'a [Span],
+    position: usize,
+}
+
+impl<'a> Assembler<'a> {
+    // Comments are skipped wherever they appear
+    fn next(&mut self) -> Option<&'a Token> {
+        while let Some(token) = self.tokens.get(self.position) {
+            self.position += 1;
+            if !matches!(token, Token::Comment { .. }) {
+                return Some(token);
+            }
+        }
+        None
+    }
+
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens[self.position..]
+            .iter()
+            .find(|token| !matches!(token, Token::Comment { .. }))
+    }
+
+    // The lexer has no minus sign, so one before a number is an unexpected
+    // grapheme
+    fn minus(&mut self) -> bool {
+        let minus =
+            matches!(self.peek(), Some(Token::UnexpectedGrapheme(grapheme)) if grapheme == "-");
+        if minus {
+            self.next();
+        }
+        minus
+    }
+
+    fn span(&self) -> Span {
+        let index = self.position.saturating_sub(1);
+        self.spans.get(index).copied().unwrap_or_default()
+    }
+
+    fn error(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(message).with_span(self.span())
+    }
+
+    fn comma(&mut self) -> Result<(), Diagnostic> {
+        match self.next() {
+            Some(Token::Comma) => Ok(()),
+            _ => Err(self.error("Expected a comma between operands")),
+        }
+    }
+
+    // An operand such as r1 or s0
+    fn numbered(&mut self, prefix: char, kind: &str) -> Result<usize, Diagnostic> {
+        match self.next() {
+            Some(Token::Name { name }) if name.starts_with(prefix) => name[1..]
+                .parse()
+                .map_err(|_| self.error(format!("Invalid {} {}", kind, name))),
+            _ => Err(self.error(format!("Expected a {} like {}0", kind, prefix))),
+        }
+    }
+
+    fn register(&mut self) -> Result<usize, Diagnostic> {
+        self.numbered('r', "register")
+    }
+
+    fn slot(&mut self) -> Result<usize, Diagnostic> {
+        self.numbered('s', "variable slot")
+    }
+
+    fn int(&mut self) -> Result<usize, Diagnostic> {
+        if self.minus() {
+            return Err(self.error("Integers can't be negative"));
+        }
+        match self.next() {
+            Some(Token::Int { value }) => parse_int(value).map_err(|d| d.with_span(self.span())),
+            _ => Err(self.error("Expected an integer")),
+        }
+    }
+
+    // Besides bee literals, takes what `{:?}` writes for floats bee can't
+    // spell: negative numbers, inf, -inf and NaN
+    fn float(&mut self) -> Result<f64, Diagnostic> {
+        let negative = self.minus();
+        let value = match self.next() {
+            Some(Token::Float { value } | Token::Int { value }) => {
+                parse_float(value).map_err(|d| d.with_span(self.span()))?
+            }
+            Some(Token::Name { name }) if name == "inf" => f64::INFINITY,
+            Some(Token::UpName { name }) if name == "NaN" && !negative => f64::NAN,
+            _ => return Err(self.error("Expected a float")),
+        };
+        Ok(if negative { -value } else { value })
+    }
+
+    fn string(&mut self) -> Result<Box<str>, Diagnostic> {
+        match self.next() {
+            Some(Token::String { value }) => Ok(value.as_str().into()),
+            Some(token) if token.error().is_some() => Err(self.error(token.error().unwrap())),
+            _ => Err(self.error("Expected a string")),
+        }
+    }
+
+    fn bool(&mut self) -> Result<bool, Diagnostic> {
+        match self.next() {
+            Some(Token::UpName { name }) if name == "True" => Ok(true),
+            Some(Token::UpName { name }) if name == "False" => Ok(false),
+            _ => Err(self.error("Expected True or False")),
+        }
+    }
+
+    fn three_registers(&mut self) -> Result<(usize, usize, usize), Diagnostic> {
+        let arg1 = self.register()?;
+        self.comma()?;
+        let arg2 = self.register()?;
+        self.comma()?;
+        Ok((arg1, arg2, self.register()?))
+    }
+
+    fn op_code(&mut self, mnemonic: &str) -> Result<OpCode, Diagnostic> {
+        let op_code = match mnemonic {
+            "and" | "or" | "add" | "sub" | "concat" => {
+                let (arg1, arg2, arg3) = self.three_registers()?;
+                match mnemonic {
+                    "and" => OpCode::And { arg1, arg2, arg3 },
+                    "or" => OpCode::Or { arg1, arg2, arg3 },
+                    "add" => OpCode::Add { arg1, arg2, arg3 },
+                    "sub" => OpCode::Sub { arg1, arg2, arg3 },
+                    _ => OpCode::Concat { arg1, arg2, arg3 },
+                }
+            }
+            "not" => OpCode::Not {
+                value: self.register()?,
+            },
+            "load" | "load_int" | "load_float" | "load_string" | "load_bool" | "move" => {
+                let arg1 = self.register()?;
+                self.comma()?;
+                match mnemonic {
+                    "load" => OpCode::Load {
+                        arg1,
+                        arg2: self.slot()?,
+                    },
+                    "load_int" => OpCode::LoadIntConst {
+                        arg1,
+                        arg2: self.int()?,
+                    },
+                    "load_float" => OpCode::LoadFloatConst {
+                        arg1,
+                        arg2: self.float()?,
+                    },
+                    "load_string" => OpCode::LoadStringConst {
+                        arg1,
+                        arg2: self.string()?,
+                    },
+                    "load_bool" => OpCode::LoadBoolConst {
+                        arg1,
+                        arg2: self.bool()?,
+                    },
+                    _ => OpCode::Move {
+                        arg1,
+                        arg2: self.register()?,
+                    },
+                }
+            }
+            "store" | "store_int" | "store_float" | "store_string" | "store_bool" => {
+                let arg1 = self.slot()?;
+                self.comma()?;
+                match mnemonic {
+                    "store" => OpCode::Store {
+                        arg1,
+                        arg2: self.register()?,
+                    },
+                    "store_int" => OpCode::StoreIntConst {
+                        arg1,
+                        arg2: self.int()?,
+                    },
+                    "store_float" => OpCode::StoreFloatConst {
+                        arg1,
+                        arg2: self.float()?,
+                    },
+                    "store_string" => OpCode::StoreStringConst {
+                        arg1,
+                        arg2: self.string()?,
+                    },
+                    _ => OpCode::StoreBoolConst {
+                        arg1,
+                        arg2: self.bool()?,
+                    },
+                }
+            }
+            "print" => OpCode::Print {
+                arg1: self.register()?,
+            },
+            "jump" => OpCode::Jump {
+                target: self.int()?,
+            },
+            "jump_if_false" | "jump_if_true" => {
+                let arg1 = self.register()?;
+                self.comma()?;
+                let target = self.int()?;
+                match mnemonic {
+                    "jump_if_false" => OpCode::JumpIfFalse { arg1, target },
+                    _ => OpCode::JumpIfTrue { arg1, target },
+                }
+            }
+            "call_host" => {
+                let arg1 = self.register()?;
+                self.comma()?;
+                let name = self.string()?;
+                self.comma()?;
+                let arg2 = self.register()?;
+                self.comma()?;
+                OpCode::CallHost {
+                    name,
+                    arg1,
+                    arg2,
+                    arg3: self.int()?,
+                }
+            }
+            "halt" => OpCode::Halt,
+            _ => return Err(self.error(format!("Unknown instruction: {}", mnemonic))),
+        };
+        Ok(op_code)
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Vec<OpCode>, Diagnostic> {
+    let (tokens, spans) = lex_with_spans(source);
+    let mut assembler = Assembler {
+        tokens: &tokens,
+        spans: &spans,
+        position: 0,
+    };
+    let mut op_codes = Vec::new();
+    let mut line = None;
+    while let Some(token) = assembler.next() {
+        let span = assembler.span();
+        if line == Some(span.start.line) {
+            return Err(assembler.error("Expected one instruction per line"));
+        }
+        let Token::Name { name } = token else {
+            return Err(assembler.error("Expected an instruction"));
+        };
+        op_codes.push(assembler.op_code(name)?);
+        line = Some(assembler.span().end.line);
+    }
+    Ok(op_codes)
+}
+
+pub fn assemble_program(source: &str) -> Result<Program, Diagnostic> {
+    Ok(Program::from_op_codes(assemble(source)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm;
+    use crate::compile_source_with_host_functions;
+    use crate::opcode::OpCode;
+    use crate::optimize::OptLevel;
+    use crate::testing::compile_unoptimized;
+
+    #[test]
+    fn assembly_round_trip() {
+        let program = compile_unoptimized(
+            r#"
+            const greeting: String = "say \"hi\"\n"
+
+            fn main() {
+                let half: Float = 0.5
+                let text: String = concat(greeting, "!")
+                let yes: Bool = and(True, False)
+                let two: Integer = add(1, 1)
+                print_integer(two)
+            }"#,
+        )
+        .unwrap();
+        let text = asm::disassemble(program.op_codes());
+        assert!(text.contains("store_float s0, 0.5\n"));
+        assert!(text.contains("load_string r1, \"say \\\"hi\\\"\\n\"\n"));
+        assert!(text.ends_with("load r1, s3\nprint r1\nhalt\n"));
+        assert_eq!(asm::assemble(&text), Ok(program.op_codes().to_vec()));
+    }
+
+    #[test]
+    fn assembly_round_trip_of_optimized_code() {
+        // Folding makes floats bee has no literal for
+        let program = compile_source_with_host_functions(
+            "fn main() {\n  print_float(sub_float(0.5, 2.0))\n}",
+            Vec::new(),
+            OptLevel::O1,
+        )
+        .unwrap();
+        let text = asm::disassemble(program.op_codes());
+        assert!(text.contains("load_float r1, -1.5\n"));
+        assert_eq!(asm::assemble(&text), Ok(program.op_codes().to_vec()));
+    }
+
+    #[test]
+    fn assembly_of_non_finite_floats() {
+        let text = "load_float r1, inf\nstore_float s0, -inf\nload_float r2, NaN\nhalt\n";
+        let op_codes = asm::assemble(text).unwrap();
+        assert_eq!(asm::disassemble(&op_codes), text);
+        assert!(matches!(
+            op_codes[2],
+            OpCode::LoadFloatConst { arg2, .. } if arg2.is_nan()
+        ));
+    }
+
+    #[test]
+    fn assembly_errors() {
+        let error = |source: &str| asm::assemble(source).unwrap_err().to_string();
+        assert_eq!(error("halt\nmul r1"), "2:1: Unknown instruction: mul");
+        assert_eq!(
+            error("add r1, r2"),
+            "1:9: Expected a comma between operands"
+        );
+        assert_eq!(
+            error("load r1, r2"),
+            "1:10: Expected a variable slot like s0"
+        );
+        assert_eq!(error("load_int r1, 1.5"), "1:14: Expected an integer");
+        assert_eq!(error("load_int r1, -1"), "1:14: Integers can't be negative");
+        assert_eq!(error("load_float r1, -NaN"), "1:17: Expected a float");
+        assert_eq!(error("print rx"), "1:7: Invalid register rx");
+        assert_eq!(error("halt halt"), "1:6: Expected one instruction per line");
+        assert_eq!(error("5"), "1:1: Expected an instruction");
+    }
+
+    #[test]
+    fn assembly_round_trip_of_host_calls() {
+        let text = "load_int r1, 404\ncall_host r1, \"http_status\", r1, 1\ncall_host r2, \"now\", r3, 0\nhalt\n";
+        let op_codes = asm::assemble(text).unwrap();
+        assert!(matches!(
+            &op_codes[1],
+            OpCode::CallHost { name, arg1: 1, arg2: 1, arg3: 1 } if &**name == "http_status"
+        ));
+        assert_eq!(asm::disassemble(&op_codes), text);
+    }
+
+    #[test]
+    fn assembly_round_trip_of_bools() {
+        let program = compile_unoptimized(
+            "fn main() {\n  let yes: Bool = True\n  print_bool(and(yes, not(False)))\n  print_bool(or(False, yes))\n}",
+        )
+        .unwrap();
+        let text = asm::disassemble(program.op_codes());
+        assert!(text.contains("jump_if_false r1, "));
+        assert!(text.contains("jump_if_true r1, "));
+        assert_eq!(asm::assemble(&text), Ok(program.op_codes().to_vec()));
+    }
+}
@@ -1,18 +1,51 @@
+use crate::span::Span;
+
 #[derive(Debug, Clone)]
 pub struct Document {
     pub constants: Vec<Constant>,
     pub functions: Vec<Function>,
+    pub externals: Vec<External>,
+    // Comments after the last definition
+    pub comments: Vec<String>,
 }
 #[derive(Debug, Clone)]
 pub struct Function {
     pub name: String,
+    pub args: Vec<String>,
     pub body: Vec<AbstractSyntaxTree>,
+    pub comments: Vec<String>,
+    // A comment on the same line as the end of the definition
+    pub trailing_comment: Option<String>,
+    // Span of the function's name
+    pub span: Span,
+}
+// A function without a body that is provided by the host, declared with
+// @external(rust, "module", "function")
+#[derive(Debug, Clone)]
+pub struct External {
+    pub name: String,
+    // Names and types of the parameters
+    pub params: Vec<(String, String)>,
+    pub returns: String,
+    pub target: String,
+    pub module: String,
+    pub function: String,
+    pub comments: Vec<String>,
+    // A comment on the same line as the end of the definition
+    pub trailing_comment: Option<String>,
+    // Span of the function's name
+    pub span: Span,
 }
 #[derive(Debug, Clone)]
 pub struct Constant {
     pub name: String,
     pub type_annot: String,
     pub value: AbstractSyntaxTree,
+    pub comments: Vec<String>,
+    // A comment on the same line as the end of the definition
+    pub trailing_comment: Option<String>,
+    // Span of the constant's name
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
@@ -21,12 +54,17 @@ pub enum AbstractSyntaxTree {
         name: String,
         type_annot: String,
         value: Box<AbstractSyntaxTree>,
+        span: Span,
     },
+    // Number literals keep their source text so the formatter can reproduce
+    // hex, octal, binary and underscored forms
     Int {
         value: usize,
+        literal: String,
     },
     Float {
         value: f64,
+        literal: String,
     },
     String {
         value: String,
@@ -40,8 +78,26 @@ pub enum AbstractSyntaxTree {
     Call {
         name: String,
         args: Vec<AbstractSyntaxTree>,
+        span: Span,
     },
     Block {
         statements: Vec<AbstractSyntaxTree>,
     },
+    Comment {
+        value: String,
+        // Written after the statement before it rather than on its own line
+        trailing: bool,
+    },
+}
+
+impl AbstractSyntaxTree {
+    // Only statements carry a span
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            AbstractSyntaxTree::Let { span, .. } | AbstractSyntaxTree::Call { span, .. } => {
+                Some(*span)
+            }
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,310 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::bytecode::Program;
+use crate::json::Json;
+use crate::vm::{RunOptions, RuntimeError, Vm};
+
+// Counts the allocations made on each thread. A binary has to install it
+// as its #[global_allocator] for measurements to see any allocations.
+pub struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+// The thread local is gone while the thread shuts down, and those
+// allocations aren't anyone's to count
+fn count_allocation() {
+    let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+}
+
+// Allocations made on this thread so far
+pub fn allocations() -> u64 {
+    ALLOCATIONS.try_with(Cell::get).unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    pub op_codes: u64,
+    pub allocations: u64,
+    pub op_codes_per_second: f64,
+}
+
+// Runs the program over and over for about the given duration. Op codes and
+// allocations are counted over a single run so they come out the same every
+// time, while the speed depends on the machine and is only reported. Only
+// the runs themselves are timed, not setting up a VM for each of them.
+// Printed output is thrown away.
+pub fn measure(program: &Program, duration: Duration) -> Result<Measurement, RuntimeError> {
+    let options = RunOptions::default();
+    // Encoded up front so it isn't counted. The first run is a warm up, so
+    // it isn't the one counted either.
+    program.code()?;
+    run(program, &options)?;
+    let counted = run(program, &options)?;
+
+    let start = Instant::now();
+    let mut executed = 0;
+    let mut running = Duration::ZERO;
+    while executed == 0 || start.elapsed() < duration {
+        let timed = run(program, &options)?;
+        executed += timed.op_codes;
+        running += timed.elapsed;
+    }
+    Ok(Measurement {
+        op_codes: counted.op_codes,
+        allocations: counted.allocations,
+        op_codes_per_second: executed as f64 / running.as_secs_f64(),
+    })
+}
+
+struct Run {
+    op_codes: u64,
+    allocations: u64,
+    elapsed: Duration,
+}
+
+// Counts the op codes executed and allocations made by the run itself, not
+// by encoding the program or setting up the VM
+fn run(program: &Program, options: &RunOptions) -> Result<Run, RuntimeError> {
+    let mut vm = Vm::new();
+    vm.output = Rc::new(RefCell::new(io::sink()));
+    let before = allocations();
+    let start = Instant::now();
+    vm.run(program, options)?;
+    let elapsed = start.elapsed();
+    Ok(Run {
+        op_codes: vm.executed,
+        allocations: allocations() - before,
+        elapsed,
+    })
+}
+
+// The counts a benchmark is expected to stay within
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Baseline {
+    pub op_codes: u64,
+    pub allocations: u64,
+}
+
+impl From<&Measurement> for Baseline {
+    fn from(measurement: &Measurement) -> Self {
+        Baseline {
+            op_codes: measurement.op_codes,
+            allocations: measurement.allocations,
+        }
+    }
+}
+
+impl Baseline {
+    // What got worse since the baseline, e.g. "allocations 4 -> 6"
+    pub fn regressions(&self, measurement: &Measurement) -> Vec<String> {
+        let mut regressions = Vec::new();
+        if measurement.op_codes > self.op_codes {
+            regressions.push(format!(
+                "op codes {} -> {}",
+                self.op_codes, measurement.op_codes
+            ));
+        }
+        if measurement.allocations > self.allocations {
+            regressions.push(format!(
+                "allocations {} -> {}",
+                self.allocations, measurement.allocations
+            ));
+        }
+        regressions
+    }
+}
+
+// Baselines are kept as a JSON object from benchmark name to counts, one
+// benchmark per line so changes to them diff well:
+//
+//   {
+//   "strings -O1":{"op_codes":12,"allocations":4}
+//   }
+pub fn parse_baselines(text: &str) -> Result<BTreeMap<String, Baseline>, String> {
+    let Json::Object(fields) = Json::parse(text)? else {
+        return Err("baselines should be an object".to_owned());
+    };
+    fields
+        .iter()
+        .map(|(name, value)| {
+            let count = |key| {
+                value
+                    .get(key)
+                    .and_then(Json::as_usize)
+                    .map(|count| count as u64)
+                    .ok_or_else(|| format!("{} is missing a count of {}", name, key))
+            };
+            let baseline = Baseline {
+                op_codes: count("op_codes")?,
+                allocations: count("allocations")?,
+            };
+            Ok((name.clone(), baseline))
+        })
+        .collect()
+}
+
+pub fn format_baselines(baselines: &BTreeMap<String, Baseline>) -> String {
+    let lines: Vec<String> = baselines
+        .iter()
+        .map(|(name, baseline)| {
+            let counts = Json::object([
+                ("op_codes", (baseline.op_codes as usize).into()),
+                ("allocations", (baseline.allocations as usize).into()),
+            ]);
+            format!("{}:{}", Json::from(name.as_str()), counts)
+        })
+        .collect();
+    format!("{{\n{}\n}}\n", lines.join(",\n"))
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>10} {:>12} {:>14.0}",
+            self.op_codes, self.allocations, self.op_codes_per_second
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::compile_unoptimized;
+
+    // Only this crate's tests run with it, and the bee binary installs its
+    // own
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn bench_counts_op_codes_and_allocations() {
+        let source = r#"
+            fn main() {
+                let a: String = concat("bee", "s")
+                print_string(concat(a, a))
+            }"#;
+        let program = compile_unoptimized(source).unwrap();
+        let measurement = measure(&program, Duration::from_millis(1)).unwrap();
+        assert_eq!(measurement.op_codes, program.op_codes().len() as u64);
+        // The program's strings are copied and concatenated on every run
+        assert!(measurement.allocations > 0);
+        assert!(measurement.op_codes_per_second > 0.0);
+        let again = measure(&program, Duration::from_millis(1)).unwrap();
+        assert_eq!(again.op_codes, measurement.op_codes);
+        assert_eq!(again.allocations, measurement.allocations);
+    }
+
+    #[test]
+    fn baselines_report_regressions() {
+        let measurement = Measurement {
+            op_codes: 12,
+            allocations: 4,
+            op_codes_per_second: 1.0,
+        };
+        let baseline = Baseline::from(&measurement);
+        assert!(baseline.regressions(&measurement).is_empty());
+        // Doing better isn't a regression
+        let fewer = Measurement {
+            op_codes: 11,
+            allocations: 3,
+            ..measurement
+        };
+        assert!(baseline.regressions(&fewer).is_empty());
+        let more = Measurement {
+            op_codes: 13,
+            allocations: 6,
+            ..measurement
+        };
+        assert_eq!(
+            baseline.regressions(&more),
+            ["op codes 12 -> 13", "allocations 4 -> 6"]
+        );
+    }
+
+    #[test]
+    fn baselines_round_trip() {
+        let baselines = [
+            (
+                "strings -O0".to_owned(),
+                Baseline {
+                    op_codes: 12,
+                    allocations: 4,
+                },
+            ),
+            (
+                "strings -O1".to_owned(),
+                Baseline {
+                    op_codes: 7,
+                    allocations: 2,
+                },
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let text = format_baselines(&baselines);
+        assert_eq!(parse_baselines(&text), Ok(baselines));
+        assert!(parse_baselines("[]").is_err());
+        assert!(parse_baselines(r#"{"a":{"op_codes":1}}"#).is_err());
+    }
+
+    #[test]
+    fn optimized_benchmarks_execute_fewer_op_codes() {
+        use crate::compile_source_with_host_functions;
+        use crate::optimize::OptLevel;
+
+        let directory = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("benches");
+        let mut benchmarks = 0;
+        for entry in std::fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|extension| extension != "bee") {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            let executed = |opt_level| {
+                let program =
+                    compile_source_with_host_functions(&source, Vec::new(), opt_level).unwrap();
+                measure(&program, Duration::ZERO).unwrap().op_codes
+            };
+            let (unoptimized, optimized) = (executed(OptLevel::O0), executed(OptLevel::O1));
+            assert!(
+                optimized < unoptimized,
+                "{}: {} op codes at -O1, {} at -O0",
+                path.display(),
+                optimized,
+                unoptimized
+            );
+            benchmarks += 1;
+        }
+        assert!(benchmarks > 0);
+    }
+}
@@ -0,0 +1,797 @@
+use std::fmt;
+
+use crate::code::{Code, CodeCache};
+use crate::code_gen::HostSignature;
+use crate::opcode::OpCode;
+use crate::span::{Position, Span};
+use crate::vm::RuntimeError;
+
+// Layout of a .beec file, all integers little endian:
+//
+//   magic      b"BEEC"
+//   version    u16
+//   constants  u32 count, then per constant a u8 tag followed by
+//              a f64 (tag 0) or a u32 length and UTF-8 bytes (tag 1)
+//   functions  u32 count, then per function its name (u32 length and UTF-8
+//              bytes), u32 first op code and u32 variable count
+//   imports    u32 count, then per host function its name and binding,
+//              u32 parameter count and the parameter and return types,
+//              all strings as a u32 length and UTF-8 bytes
+//   op codes   u32 count, then per op code a u8 tag and its operands.
+//              Registers and variable slots are u32, integer constants u64
+//              and float and string constants u32 indexes into the pool.
+//              Bools are a u8 and jump targets a u32 op code index.
+//   lines      u32 count, then per entry a u32 op code index and the u32
+//              start line, start column, end line and end column
+const MAGIC: &[u8; 4] = b"BEEC";
+const VERSION: u16 = 4;
+
+const FLOAT_CONSTANT: u8 = 0;
+const STRING_CONSTANT: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub functions: Vec<FunctionEntry>,
+    // Declared signatures of the host functions the program calls, checked
+    // against the registered functions before it runs
+    pub host_functions: Vec<HostSignature>,
+    // Private, like the line table, because the code is encoded from them:
+    // every change goes through a method that drops the encoded code
+    op_codes: Vec<OpCode>,
+    // Ordered by pc, each entry covers the op codes up to the next one
+    line_table: Vec<LineEntry>,
+    // What the VM runs, see Program::code
+    code: CodeCache,
+}
+
+impl Program {
+    pub fn new(
+        functions: Vec<FunctionEntry>,
+        host_functions: Vec<HostSignature>,
+        op_codes: Vec<OpCode>,
+        line_table: Vec<LineEntry>,
+    ) -> Self {
+        Program {
+            functions,
+            host_functions,
+            op_codes,
+            line_table,
+            code: CodeCache::default(),
+        }
+    }
+
+    // A single main function without source locations, using every variable
+    // slot its code mentions
+    pub fn from_op_codes(op_codes: Vec<OpCode>) -> Self {
+        let variable_count = op_codes
+            .iter()
+            .filter_map(|op_code| match op_code {
+                OpCode::Load { arg2: slot, .. }
+                | OpCode::Store { arg1: slot, .. }
+                | OpCode::StoreIntConst { arg1: slot, .. }
+                | OpCode::StoreFloatConst { arg1: slot, .. }
+                | OpCode::StoreStringConst { arg1: slot, .. }
+                | OpCode::StoreBoolConst { arg1: slot, .. } => Some(slot + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        Program::new(
+            vec![FunctionEntry {
+                name: "main".to_owned(),
+                start: 0,
+                variable_count,
+            }],
+            Vec::new(),
+            op_codes,
+            Vec::new(),
+        )
+    }
+
+    // The op codes encoded for the VM. They are encoded once, when the
+    // program is compiled or first runs, and kept for later runs.
+    pub fn code(&self) -> Result<&Code, RuntimeError> {
+        self.code.get(self)
+    }
+
+    pub fn op_codes(&self) -> &[OpCode] {
+        &self.op_codes
+    }
+
+    pub fn op_codes_mut(&mut self) -> &mut Vec<OpCode> {
+        self.code = CodeCache::default();
+        &mut self.op_codes
+    }
+
+    pub fn line_table(&self) -> &[LineEntry] {
+        &self.line_table
+    }
+
+    pub fn line_table_mut(&mut self) -> &mut Vec<LineEntry> {
+        self.code = CodeCache::default();
+        &mut self.line_table
+    }
+
+    pub fn function_at(&self, pc: usize) -> Option<&FunctionEntry> {
+        self.functions
+            .iter()
+            .filter(|function| function.start <= pc)
+            .max_by_key(|function| function.start)
+    }
+
+    pub fn span_at(&self, pc: usize) -> Option<Span> {
+        self.line_table
+            .iter()
+            .take_while(|entry| entry.pc <= pc)
+            .last()
+            .map(|entry| entry.span)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionEntry {
+    pub name: String,
+    // Index of the function's first op code
+    pub start: usize,
+    pub variable_count: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineEntry {
+    pub pc: usize,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    InvalidUtf8,
+    InvalidConstantTag(u8),
+    InvalidOpCode(u8),
+    InvalidBool(u8),
+    // The constant is out of range or of the wrong kind
+    InvalidConstant(u32),
+    InvalidFunctionStart(String),
+    // Variables are addressed with 16 bits once the program is encoded
+    TooManyVariables(String, usize),
+    TrailingBytes,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "not a bee bytecode file"),
+            DecodeError::UnsupportedVersion(version) => write!(
+                f,
+                "bytecode version {} is not supported, expected version {}",
+                version, VERSION
+            ),
+            DecodeError::Truncated => write!(f, "bytecode file is truncated"),
+            DecodeError::InvalidUtf8 => write!(f, "string in bytecode file is not valid UTF-8"),
+            DecodeError::InvalidConstantTag(tag) => write!(f, "invalid constant tag {}", tag),
+            DecodeError::InvalidOpCode(tag) => write!(f, "invalid op code {}", tag),
+            DecodeError::InvalidBool(value) => write!(f, "invalid bool {}", value),
+            DecodeError::InvalidConstant(index) => write!(f, "invalid constant index {}", index),
+            DecodeError::InvalidFunctionStart(name) => {
+                write!(f, "function {} starts outside of the code", name)
+            }
+            DecodeError::TooManyVariables(name, count) => write!(
+                f,
+                "function {} has {} variables, at most {} are supported",
+                name,
+                count,
+                u16::MAX
+            ),
+            DecodeError::TrailingBytes => write!(f, "unexpected bytes after the line table"),
+        }
+    }
+}
+
+// A program too large for the file format
+#[derive(Debug, Clone, PartialEq)]
+pub enum EncodeError {
+    // A count, length, operand or index over u32::MAX
+    TooLarge(usize),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::TooLarge(value) => write!(
+                f,
+                "{} doesn't fit in the 32 bits bytecode files store it in",
+                value
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Constant<'a> {
+    Float(f64),
+    String(&'a str),
+}
+
+// Constants are deduplicated as they are added
+#[derive(Default)]
+struct ConstantPool<'a> {
+    constants: Vec<Constant<'a>>,
+}
+
+impl<'a> ConstantPool<'a> {
+    fn add(&mut self, constant: Constant<'a>) -> usize {
+        let existing = self.constants.iter().position(|c| match (c, constant) {
+            (Constant::Float(a), Constant::Float(b)) => a.to_bits() == b.to_bits(),
+            (Constant::String(a), Constant::String(b)) => *a == b,
+            _ => false,
+        });
+        existing.unwrap_or_else(|| {
+            self.constants.push(constant);
+            self.constants.len() - 1
+        })
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) -> Result<(), EncodeError> {
+    let value = u32::try_from(value).map_err(|_| EncodeError::TooLarge(value))?;
+    bytes.extend_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+fn write_u64(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend_from_slice(&(value as u64).to_le_bytes());
+}
+
+fn write_str(bytes: &mut Vec<u8>, value: &str) -> Result<(), EncodeError> {
+    write_u32(bytes, value.len())?;
+    bytes.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+pub fn encode(program: &Program) -> Result<Vec<u8>, EncodeError> {
+    let mut pool = ConstantPool::default();
+    let mut code = Vec::new();
+    for op_code in &program.op_codes {
+        match op_code {
+            OpCode::And { arg1, arg2, arg3 } => {
+                encode_registers(&mut code, 0, &[arg1, arg2, arg3])?
+            }
+            OpCode::Or { arg1, arg2, arg3 } => encode_registers(&mut code, 1, &[arg1, arg2, arg3])?,
+            OpCode::Not { value } => encode_registers(&mut code, 2, &[value])?,
+            OpCode::Add { arg1, arg2, arg3 } => {
+                encode_registers(&mut code, 3, &[arg1, arg2, arg3])?
+            }
+            OpCode::Sub { arg1, arg2, arg3 } => {
+                encode_registers(&mut code, 4, &[arg1, arg2, arg3])?
+            }
+            OpCode::Concat { arg1, arg2, arg3 } => {
+                encode_registers(&mut code, 5, &[arg1, arg2, arg3])?
+            }
+            OpCode::Load { arg1, arg2 } => encode_registers(&mut code, 6, &[arg1, arg2])?,
+            OpCode::LoadIntConst { arg1, arg2 } => {
+                encode_registers(&mut code, 7, &[arg1])?;
+                write_u64(&mut code, *arg2);
+            }
+            OpCode::LoadFloatConst { arg1, arg2 } => {
+                let index = pool.add(Constant::Float(*arg2));
+                encode_registers(&mut code, 8, &[arg1, &index])?;
+            }
+            OpCode::LoadStringConst { arg1, arg2 } => {
+                let index = pool.add(Constant::String(arg2));
+                encode_registers(&mut code, 9, &[arg1, &index])?;
+            }
+            OpCode::Move { arg1, arg2 } => encode_registers(&mut code, 10, &[arg1, arg2])?,
+            OpCode::Store { arg1, arg2 } => encode_registers(&mut code, 11, &[arg1, arg2])?,
+            OpCode::StoreIntConst { arg1, arg2 } => {
+                encode_registers(&mut code, 12, &[arg1])?;
+                write_u64(&mut code, *arg2);
+            }
+            OpCode::StoreFloatConst { arg1, arg2 } => {
+                let index = pool.add(Constant::Float(*arg2));
+                encode_registers(&mut code, 13, &[arg1, &index])?;
+            }
+            OpCode::StoreStringConst { arg1, arg2 } => {
+                let index = pool.add(Constant::String(arg2));
+                encode_registers(&mut code, 14, &[arg1, &index])?;
+            }
+            OpCode::Print { arg1 } => encode_registers(&mut code, 15, &[arg1])?,
+            OpCode::CallHost {
+                name,
+                arg1,
+                arg2,
+                arg3,
+            } => {
+                let index = pool.add(Constant::String(name));
+                encode_registers(&mut code, 17, &[arg1, &index, arg2, arg3])?;
+            }
+            OpCode::LoadBoolConst { arg1, arg2 } => {
+                encode_registers(&mut code, 18, &[arg1])?;
+                code.push(*arg2 as u8);
+            }
+            OpCode::StoreBoolConst { arg1, arg2 } => {
+                encode_registers(&mut code, 19, &[arg1])?;
+                code.push(*arg2 as u8);
+            }
+            OpCode::Jump { target } => encode_registers(&mut code, 20, &[target])?,
+            OpCode::JumpIfFalse { arg1, target } => {
+                encode_registers(&mut code, 21, &[arg1, target])?
+            }
+            OpCode::JumpIfTrue { arg1, target } => {
+                encode_registers(&mut code, 22, &[arg1, target])?
+            }
+            OpCode::Halt => code.push(16),
+        }
+    }
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    write_u32(&mut bytes, pool.constants.len())?;
+    for constant in &pool.constants {
+        match constant {
+            Constant::Float(value) => {
+                bytes.push(FLOAT_CONSTANT);
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            Constant::String(value) => {
+                bytes.push(STRING_CONSTANT);
+                write_str(&mut bytes, value)?;
+            }
+        }
+    }
+    write_u32(&mut bytes, program.functions.len())?;
+    for function in &program.functions {
+        write_str(&mut bytes, &function.name)?;
+        write_u32(&mut bytes, function.start)?;
+        write_u32(&mut bytes, function.variable_count)?;
+    }
+    write_u32(&mut bytes, program.host_functions.len())?;
+    for signature in &program.host_functions {
+        write_str(&mut bytes, &signature.name)?;
+        write_str(&mut bytes, &signature.binding)?;
+        write_u32(&mut bytes, signature.params.len())?;
+        for param in &signature.params {
+            write_str(&mut bytes, param)?;
+        }
+        write_str(&mut bytes, &signature.returns)?;
+    }
+    write_u32(&mut bytes, program.op_codes.len())?;
+    bytes.extend_from_slice(&code);
+    write_u32(&mut bytes, program.line_table.len())?;
+    for entry in &program.line_table {
+        write_u32(&mut bytes, entry.pc)?;
+        for position in [entry.span.start, entry.span.end] {
+            write_u32(&mut bytes, position.line)?;
+            write_u32(&mut bytes, position.column)?;
+        }
+    }
+    Ok(bytes)
+}
+
+fn encode_registers(code: &mut Vec<u8>, tag: u8, operands: &[&usize]) -> Result<(), EncodeError> {
+    code.push(tag);
+    for operand in operands {
+        write_u32(code, **operand)?;
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < count {
+            return Err(DecodeError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(DecodeError::InvalidBool(value)),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, DecodeError> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str, DecodeError> {
+        let length = self.usize()?;
+        std::str::from_utf8(self.take(length)?).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    // Reads the number of items that follow, each taking at least
+    // `item_size` bytes, so a count the rest of the input can't hold fails
+    // before anything is allocated for it
+    fn count(&mut self, item_size: usize) -> Result<usize, DecodeError> {
+        let count = self.usize()?;
+        if count.saturating_mul(item_size) > self.bytes.len() {
+            return Err(DecodeError::Truncated);
+        }
+        Ok(count)
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Program, DecodeError> {
+    let mut reader = Reader { bytes };
+    if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+        return Err(DecodeError::BadMagic);
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    // A tag and at least a string length
+    let count = reader.count(5)?;
+    let mut constants = Vec::with_capacity(count);
+    for _ in 0..count {
+        match reader.u8()? {
+            FLOAT_CONSTANT => constants.push(Constant::Float(f64::from_le_bytes(
+                reader.take(8)?.try_into().unwrap(),
+            ))),
+            STRING_CONSTANT => constants.push(Constant::String(reader.str()?)),
+            tag => return Err(DecodeError::InvalidConstantTag(tag)),
+        }
+    }
+    let float = |index: u32| match constants.get(index as usize) {
+        Some(Constant::Float(value)) => Ok(*value),
+        _ => Err(DecodeError::InvalidConstant(index)),
+    };
+    let string = |index: u32| match constants.get(index as usize) {
+        Some(Constant::String(value)) => Ok(Box::<str>::from(*value)),
+        _ => Err(DecodeError::InvalidConstant(index)),
+    };
+
+    // A name length, the start and the variable count
+    let count = reader.count(12)?;
+    let mut functions = Vec::with_capacity(count);
+    for _ in 0..count {
+        let name = reader.str()?.to_owned();
+        let start = reader.usize()?;
+        let variable_count = reader.usize()?;
+        if variable_count > u16::MAX as usize {
+            return Err(DecodeError::TooManyVariables(name, variable_count));
+        }
+        functions.push(FunctionEntry {
+            name,
+            start,
+            variable_count,
+        });
+    }
+
+    // The lengths of the name, binding and return type, and a param count
+    let count = reader.count(16)?;
+    let mut host_functions = Vec::with_capacity(count);
+    for _ in 0..count {
+        let name = reader.str()?.to_owned();
+        let binding = reader.str()?.to_owned();
+        let count = reader.count(4)?;
+        let mut params = Vec::with_capacity(count);
+        for _ in 0..count {
+            params.push(reader.str()?.to_owned());
+        }
+        host_functions.push(HostSignature {
+            name,
+            binding,
+            params,
+            returns: reader.str()?.to_owned(),
+        });
+    }
+
+    // Halt is a tag on its own
+    let count = reader.count(1)?;
+    let mut op_codes = Vec::with_capacity(count);
+    for _ in 0..count {
+        let op_code = match reader.u8()? {
+            0 => OpCode::And {
+                arg1: reader.usize()?,
+                arg2: reader.usize()?,
+                arg3: reader.usize()?,
+            },
+            1 => OpCode::Or {
+                arg1: reader.usize()?,
+                arg2: reader.usize()?,
+                arg3: reader.usize()?,
+            },
+            2 => OpCode::Not {
+                value: reader.usize()?,
+            },
+            3 => OpCode::Add {
+                arg1: reader.usize()?,
+                arg2: reader.usize()?,
+                arg3: reader.usize()?,
+            },
+            4 => OpCode::Sub {
+                arg1: reader.usize()?,
+                arg2: reader.usize()?,
+                arg3: reader.usize()?,
+            },
+            5 => OpCode::Concat {
+                arg1: reader.usize()?,
+                arg2: reader.usize()?,
+                arg3: reader.usize()?,
+            },
+            6 => OpCode::Load {
+                arg1: reader.usize()?,
+                arg2: reader.usize()?,
+            },
+            7 => OpCode::LoadIntConst {
+                arg1: reader.usize()?,
+                arg2: reader.u64()? as usize,
+            },
+            8 => OpCode::LoadFloatConst {
+                arg1: reader.usize()?,
+                arg2: float(reader.u32()?)?,
+            },
+            9 => OpCode::LoadStringConst {
+                arg1: reader.usize()?,
+                arg2: string(reader.u32()?)?,
+            },
+            10 => OpCode::Move {
+                arg1: reader.usize()?,
+                arg2: reader.usize()?,
+            },
+            11 => OpCode::Store {
+                arg1: reader.usize()?,
+                arg2: reader.usize()?,
+            },
+            12 => OpCode::StoreIntConst {
+                arg1: reader.usize()?,
+                arg2: reader.u64()? as usize,
+            },
+            13 => OpCode::StoreFloatConst {
+                arg1: reader.usize()?,
+                arg2: float(reader.u32()?)?,
+            },
+            14 => OpCode::StoreStringConst {
+                arg1: reader.usize()?,
+                arg2: string(reader.u32()?)?,
+            },
+            15 => OpCode::Print {
+                arg1: reader.usize()?,
+            },
+            16 => OpCode::Halt,
+            17 => OpCode::CallHost {
+                arg1: reader.usize()?,
+                name: string(reader.u32()?)?,
+                arg2: reader.usize()?,
+                arg3: reader.usize()?,
+            },
+            18 => OpCode::LoadBoolConst {
+                arg1: reader.usize()?,
+                arg2: reader.bool()?,
+            },
+            19 => OpCode::StoreBoolConst {
+                arg1: reader.usize()?,
+                arg2: reader.bool()?,
+            },
+            20 => OpCode::Jump {
+                target: reader.usize()?,
+            },
+            21 => OpCode::JumpIfFalse {
+                arg1: reader.usize()?,
+                target: reader.usize()?,
+            },
+            22 => OpCode::JumpIfTrue {
+                arg1: reader.usize()?,
+                target: reader.usize()?,
+            },
+            tag => return Err(DecodeError::InvalidOpCode(tag)),
+        };
+        op_codes.push(op_code);
+    }
+    // A pc and two positions
+    let count = reader.count(20)?;
+    let mut line_table = Vec::with_capacity(count);
+    for _ in 0..count {
+        let pc = reader.usize()?;
+        let mut position = || -> Result<Position, DecodeError> {
+            Ok(Position {
+                line: reader.usize()?,
+                column: reader.usize()?,
+            })
+        };
+        let span = Span {
+            start: position()?,
+            end: position()?,
+        };
+        line_table.push(LineEntry { pc, span });
+    }
+    if !reader.bytes.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    if let Some(function) = functions.iter().find(|f| f.start >= op_codes.len()) {
+        return Err(DecodeError::InvalidFunctionStart(function.name.clone()));
+    }
+
+    Ok(Program::new(
+        functions,
+        host_functions,
+        op_codes,
+        line_table,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode;
+    use crate::bytecode::{DecodeError, Program};
+    use crate::compile_source;
+
+    // Functions, constants, op codes and the line table all make it through
+    fn compiled() -> Program {
+        let source = r#"
+            const greeting: String = "hi"
+
+            fn main() {
+                let half: Float = 0.5
+                let one: Float = add_float(half, 0.5)
+                let text: String = concat(greeting, "\u{1F41D}")
+                let two: Integer = add(1, 1)
+                print_float(one)
+                print_string(text)
+                print_integer(two)
+            }"#;
+        compile_source(source).unwrap()
+    }
+
+    #[test]
+    fn bytecode_round_trip() {
+        let program = compiled();
+        assert_eq!(program.functions[0].name, "main");
+        assert_eq!(program.functions[0].variable_count, 4);
+        assert!(!program.line_table().is_empty());
+        let bytes = bytecode::encode(&program).unwrap();
+        assert_eq!(bytecode::decode(&bytes), Ok(program));
+    }
+
+    #[test]
+    fn bytecode_rejects_invalid_files() {
+        let program = compiled();
+        let bytes = bytecode::encode(&program).unwrap();
+        assert_eq!(bytecode::decode(b"BEE"), Err(DecodeError::BadMagic));
+        assert_eq!(bytecode::decode(b"PNG\0\x01\0"), Err(DecodeError::BadMagic));
+        assert_eq!(
+            bytecode::decode(b"BEEC\x01\0"),
+            Err(DecodeError::UnsupportedVersion(1))
+        );
+        for length in 4..bytes.len() {
+            assert_eq!(
+                bytecode::decode(&bytes[..length]),
+                Err(DecodeError::Truncated),
+                "length {}",
+                length
+            );
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(bytecode::decode(&trailing), Err(DecodeError::TrailingBytes));
+        // Without a line table the halt is followed by just the entry count
+        let mut without_lines = program;
+        without_lines.line_table_mut().clear();
+        let mut bad_op_code = bytecode::encode(&without_lines).unwrap();
+        let halt = bad_op_code.len() - 5;
+        bad_op_code[halt] = 200;
+        assert_eq!(
+            bytecode::decode(&bad_op_code),
+            Err(DecodeError::InvalidOpCode(200))
+        );
+        assert_eq!(
+            DecodeError::Truncated.to_string(),
+            "bytecode file is truncated"
+        );
+    }
+
+    #[test]
+    fn decode_bounds_counts_before_allocating() {
+        // Counts larger than the rest of the file fail before allocating
+        let mut huge_count = bytecode::MAGIC.to_vec();
+        huge_count.extend_from_slice(&bytecode::VERSION.to_le_bytes());
+        huge_count.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(bytecode::decode(&huge_count), Err(DecodeError::Truncated));
+
+        let mut too_many_variables = compiled();
+        too_many_variables.functions[0].variable_count = u32::MAX as usize;
+        assert_eq!(
+            bytecode::decode(&bytecode::encode(&too_many_variables).unwrap()),
+            Err(DecodeError::TooManyVariables(
+                "main".to_owned(),
+                u32::MAX as usize
+            ))
+        );
+    }
+
+    #[test]
+    fn encode_rejects_values_over_32_bits() {
+        use crate::bytecode::EncodeError;
+        use crate::opcode::OpCode;
+
+        let too_far = 1 << 32;
+        let program = Program::from_op_codes(vec![OpCode::Jump { target: too_far }]);
+        assert_eq!(
+            bytecode::encode(&program),
+            Err(EncodeError::TooLarge(too_far))
+        );
+        let mut program = Program::from_op_codes(vec![OpCode::Halt]);
+        program.functions[0].variable_count = too_far;
+        assert_eq!(
+            bytecode::encode(&program).unwrap_err().to_string(),
+            "4294967296 doesn't fit in the 32 bits bytecode files store it in"
+        );
+    }
+
+    #[test]
+    fn bytecode_round_trip_of_host_calls() {
+        use crate::opcode::OpCode;
+
+        let program = Program::from_op_codes(vec![
+            OpCode::CallHost {
+                name: "http_status".into(),
+                arg1: 1,
+                arg2: 2,
+                arg3: 3,
+            },
+            OpCode::Halt,
+        ]);
+        let bytes = bytecode::encode(&program).unwrap();
+        assert_eq!(bytecode::decode(&bytes), Ok(program));
+    }
+
+    #[test]
+    fn bytecode_round_trip_of_external_functions() {
+        let program = compile_source(
+            "@external(rust, \"math\", \"sqrt\")\nfn sqrt(x: Float) -> Float\nfn main() {\n  let root: Float = sqrt(2.25)\n}",
+        )
+        .unwrap();
+        assert_eq!(program.host_functions.len(), 1);
+        let bytes = bytecode::encode(&program).unwrap();
+        assert_eq!(bytecode::decode(&bytes), Ok(program));
+    }
+
+    #[test]
+    fn bytecode_round_trip_of_bools() {
+        let program = compile_source(
+            "fn main() {\n  let yes: Bool = True\n  print_bool(and(yes, not(False)))\n  print_bool(or(False, yes))\n}",
+        )
+        .unwrap();
+        let bytes = bytecode::encode(&program).unwrap();
+        assert_eq!(bytecode::decode(&bytes), Ok(program));
+    }
+
+    #[test]
+    fn programs_keep_their_code_until_it_changes() {
+        use crate::code::Code;
+
+        let mut program = compiled();
+        let encoded: *const Code = program.code().unwrap();
+        assert!(std::ptr::eq(encoded, program.code().unwrap()));
+        program.op_codes_mut().truncate(1);
+        assert_eq!(program.code().unwrap().instructions.len(), 1);
+    }
+}
@@ -0,0 +1,374 @@
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::bytecode::Program;
+use crate::opcode::OpCode;
+use crate::vm::{ErrorKind, RuntimeError, Value, REGISTER_COUNT};
+
+// The form the VM executes. OpCode stays the representation everything
+// else works with, and each op code is encoded into exactly one fixed width
+// instruction so pcs, jump targets and the line table carry over unchanged.
+//
+// Registers are a u8 in a or a u16 in b and c. Variable slots, constant
+// pool and host function indexes are a u16, and jump targets are b and c
+// together as a u32:
+//
+//   add, sub, and, or, concat   a = b op c
+//   not                         a = not a
+//   load, move                  a = slot b, a = register b
+//   load_const, load_bool       a = constants[b], a = b != 0
+//   store                       slot b = a
+//   store_const, store_bool     slot b = constants[c], slot b = c != 0
+//   print                       a
+//   call_host                   a = host_names[b](c & 0xff registers from
+//                               c >> 8)
+//   jump, jump_if_false/true    to target, on the Bool in a
+//   halt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    And,
+    Or,
+    Not,
+    Concat,
+    Load,
+    LoadConst,
+    LoadBool,
+    Move,
+    Store,
+    StoreConst,
+    StoreBool,
+    Print,
+    CallHost,
+    Jump,
+    JumpIfFalse,
+    JumpIfTrue,
+    Halt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub op: Op,
+    pub a: u8,
+    pub b: u16,
+    pub c: u16,
+}
+
+impl Instruction {
+    pub fn target(self) -> usize {
+        usize::from(self.b) | usize::from(self.c) << 16
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Code {
+    pub instructions: Vec<Instruction>,
+    // Integer, float and string constants, each stored once. Strings are
+    // shared with every register and slot they're loaded into.
+    pub constants: Vec<Value>,
+    // Bindings of the host functions called, each stored once
+    pub host_names: Vec<Box<str>>,
+}
+
+// A program's code, encoded the first time it is asked for. It follows from
+// the op codes, so it is left out of comparisons and debug output.
+#[derive(Clone, Default)]
+pub struct CodeCache(OnceCell<Result<Code, RuntimeError>>);
+
+impl CodeCache {
+    pub fn get(&self, program: &Program) -> Result<&Code, RuntimeError> {
+        self.0
+            .get_or_init(|| encode(program))
+            .as_ref()
+            .map_err(Clone::clone)
+    }
+}
+
+impl PartialEq for CodeCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for CodeCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CodeCache").finish_non_exhaustive()
+    }
+}
+
+// Registers are checked against the VM's register count here so the VM can
+// index them directly. Slots are checked as they are used, because how
+// many there are depends on the VM.
+pub fn encode(program: &Program) -> Result<Code, RuntimeError> {
+    let mut code = Code {
+        instructions: Vec::with_capacity(program.op_codes().len()),
+        constants: Vec::new(),
+        host_names: Vec::new(),
+    };
+    let mut constants = HashMap::new();
+    for (pc, op_code) in program.op_codes().iter().enumerate() {
+        let instruction =
+            encode_op_code(&mut code, &mut constants, op_code).map_err(|message| RuntimeError {
+                kind: ErrorKind::InvalidBytecode(message),
+                pc,
+                function: program
+                    .function_at(pc)
+                    .map_or_else(String::new, |function| function.name.clone()),
+                span: program.span_at(pc),
+            })?;
+        code.instructions.push(instruction);
+    }
+    Ok(code)
+}
+
+fn encode_op_code(
+    code: &mut Code,
+    constants: &mut HashMap<ConstantKey, usize>,
+    op_code: &OpCode,
+) -> Result<Instruction, String> {
+    let instruction = match op_code {
+        OpCode::Add { arg1, arg2, arg3 } => binary(Op::Add, *arg1, *arg2, *arg3)?,
+        OpCode::Sub { arg1, arg2, arg3 } => binary(Op::Sub, *arg1, *arg2, *arg3)?,
+        OpCode::And { arg1, arg2, arg3 } => binary(Op::And, *arg1, *arg2, *arg3)?,
+        OpCode::Or { arg1, arg2, arg3 } => binary(Op::Or, *arg1, *arg2, *arg3)?,
+        OpCode::Concat { arg1, arg2, arg3 } => binary(Op::Concat, *arg1, *arg2, *arg3)?,
+        OpCode::Not { value } => instruction(Op::Not, register(*value)?, 0, 0),
+        OpCode::Load { arg1, arg2 } => instruction(Op::Load, register(*arg1)?, slot(*arg2)?, 0),
+        OpCode::Move { arg1, arg2 } => {
+            instruction(Op::Move, register(*arg1)?, u16::from(register(*arg2)?), 0)
+        }
+        OpCode::LoadIntConst { arg1, arg2 } => {
+            load_constant(code, constants, *arg1, Value::Int(*arg2))?
+        }
+        OpCode::LoadFloatConst { arg1, arg2 } => {
+            load_constant(code, constants, *arg1, Value::Float(*arg2))?
+        }
+        OpCode::LoadStringConst { arg1, arg2 } => {
+            load_constant(code, constants, *arg1, Value::String((**arg2).into()))?
+        }
+        OpCode::LoadBoolConst { arg1, arg2 } => {
+            instruction(Op::LoadBool, register(*arg1)?, u16::from(*arg2), 0)
+        }
+        OpCode::Store { arg1, arg2 } => instruction(Op::Store, register(*arg2)?, slot(*arg1)?, 0),
+        OpCode::StoreIntConst { arg1, arg2 } => {
+            store_constant(code, constants, *arg1, Value::Int(*arg2))?
+        }
+        OpCode::StoreFloatConst { arg1, arg2 } => {
+            store_constant(code, constants, *arg1, Value::Float(*arg2))?
+        }
+        OpCode::StoreStringConst { arg1, arg2 } => {
+            store_constant(code, constants, *arg1, Value::String((**arg2).into()))?
+        }
+        OpCode::StoreBoolConst { arg1, arg2 } => {
+            instruction(Op::StoreBool, 0, slot(*arg1)?, u16::from(*arg2))
+        }
+        OpCode::Print { arg1 } => instruction(Op::Print, register(*arg1)?, 0, 0),
+        OpCode::CallHost {
+            name,
+            arg1,
+            arg2,
+            arg3,
+        } => {
+            // The arguments have to fit in the registers, which also keeps
+            // the first and the count to a byte each
+            if arg2
+                .checked_add(*arg3)
+                .is_none_or(|end| end > REGISTER_COUNT)
+            {
+                return Err(format!(
+                    "register r{} is out of range",
+                    arg2.saturating_add(*arg3)
+                ));
+            }
+            let index = match code.host_names.iter().position(|n| n == name) {
+                Some(index) => index,
+                None => {
+                    code.host_names.push(name.clone());
+                    code.host_names.len() - 1
+                }
+            };
+            let args = (*arg3 << 8 | *arg2) as u16;
+            instruction(
+                Op::CallHost,
+                register(*arg1)?,
+                index_u16(index, "host functions")?,
+                args,
+            )
+        }
+        OpCode::Jump { target } => jump(Op::Jump, 0, *target)?,
+        OpCode::JumpIfFalse { arg1, target } => jump(Op::JumpIfFalse, register(*arg1)?, *target)?,
+        OpCode::JumpIfTrue { arg1, target } => jump(Op::JumpIfTrue, register(*arg1)?, *target)?,
+        OpCode::Halt => instruction(Op::Halt, 0, 0, 0),
+    };
+    Ok(instruction)
+}
+
+fn instruction(op: Op, a: u8, b: u16, c: u16) -> Instruction {
+    Instruction { op, a, b, c }
+}
+
+fn binary(op: Op, arg1: usize, arg2: usize, arg3: usize) -> Result<Instruction, String> {
+    Ok(instruction(
+        op,
+        register(arg1)?,
+        u16::from(register(arg2)?),
+        u16::from(register(arg3)?),
+    ))
+}
+
+fn load_constant(
+    code: &mut Code,
+    constants: &mut HashMap<ConstantKey, usize>,
+    register_index: usize,
+    value: Value,
+) -> Result<Instruction, String> {
+    let index = constant(code, constants, value)?;
+    Ok(instruction(
+        Op::LoadConst,
+        register(register_index)?,
+        index,
+        0,
+    ))
+}
+
+fn store_constant(
+    code: &mut Code,
+    constants: &mut HashMap<ConstantKey, usize>,
+    slot_index: usize,
+    value: Value,
+) -> Result<Instruction, String> {
+    let index = constant(code, constants, value)?;
+    Ok(instruction(Op::StoreConst, 0, slot(slot_index)?, index))
+}
+
+fn jump(op: Op, a: u8, target: usize) -> Result<Instruction, String> {
+    let target = u32::try_from(target).map_err(|_| format!("jump target {} is too far", target))?;
+    Ok(instruction(op, a, target as u16, (target >> 16) as u16))
+}
+
+// Floats are told apart by their bits so that 0.0 and -0.0 both get stored
+// and NaN is stored once
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Int(usize),
+    Float(u64),
+    String(Rc<str>),
+    Bool(bool),
+}
+
+fn constant(
+    code: &mut Code,
+    constants: &mut HashMap<ConstantKey, usize>,
+    value: Value,
+) -> Result<u16, String> {
+    let key = match &value {
+        Value::Int(value) => ConstantKey::Int(*value),
+        Value::Float(value) => ConstantKey::Float(value.to_bits()),
+        Value::String(value) => ConstantKey::String(value.clone()),
+        Value::Bool(value) => ConstantKey::Bool(*value),
+    };
+    let index = *constants.entry(key).or_insert_with(|| {
+        code.constants.push(value);
+        code.constants.len() - 1
+    });
+    index_u16(index, "constants")
+}
+
+fn register(index: usize) -> Result<u8, String> {
+    if index < REGISTER_COUNT {
+        Ok(index as u8)
+    } else {
+        Err(format!("register r{} is out of range", index))
+    }
+}
+
+fn slot(slot: usize) -> Result<u16, String> {
+    u16::try_from(slot).map_err(|_| format!("variable slot s{} is out of range", slot))
+}
+
+fn index_u16(index: usize, of: &str) -> Result<u16, String> {
+    u16::try_from(index).map_err(|_| format!("too many {}", of))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::opcode::OpCode;
+    use crate::vm::Value;
+
+    #[test]
+    fn encodes_op_codes_into_fixed_width_instructions() {
+        assert_eq!(std::mem::size_of::<Instruction>(), 6);
+        let program = asm::assemble_program(
+            r#"
+            load_string r1, "bee"
+            store_string s0, "bee"
+            load_float r2, 1.5
+            store_int s1, 1
+            jump_if_true r1, 5
+            halt
+            "#,
+        )
+        .unwrap();
+        let code = encode(&program).unwrap();
+        assert_eq!(code.instructions.len(), program.op_codes().len());
+        // Constants used twice are only stored once
+        assert_eq!(
+            code.constants,
+            [Value::from("bee"), Value::Float(1.5), Value::Int(1)]
+        );
+        assert_eq!(
+            code.instructions[1],
+            Instruction {
+                op: Op::StoreConst,
+                a: 0,
+                b: 0,
+                c: 0
+            }
+        );
+        assert_eq!(code.instructions[4].target(), 5);
+    }
+
+    #[test]
+    fn far_jumps_are_encoded() {
+        let far = Program::from_op_codes(vec![OpCode::Jump { target: 70_000 }]);
+        assert_eq!(encode(&far).unwrap().instructions[0].target(), 70_000);
+    }
+
+    #[test]
+    fn registers_must_fit_in_instructions() {
+        let error = encode(&Program::from_op_codes(vec![OpCode::Print { arg1: 40 }])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid bytecode: register r40 is out of range (in main at pc 0)"
+        );
+        let overflowing = Program::from_op_codes(vec![OpCode::CallHost {
+            name: "log".into(),
+            arg1: 1,
+            arg2: usize::MAX,
+            arg3: 2,
+        }]);
+        assert_eq!(
+            encode(&overflowing).unwrap_err().to_string(),
+            format!(
+                "invalid bytecode: register r{} is out of range (in main at pc 0)",
+                usize::MAX
+            )
+        );
+    }
+
+    #[test]
+    fn float_constants_are_told_apart_by_their_bits() {
+        let floats = asm::assemble_program(
+            "load_float r1, 0.0\nload_float r2, -0.0\nload_float r3, NaN\nload_float r4, NaN\nhalt",
+        )
+        .unwrap();
+        let constants = encode(&floats).unwrap().constants;
+        assert_eq!(constants.len(), 3);
+        assert!(matches!(constants[1], Value::Float(value) if value.is_sign_negative()));
+    }
+}
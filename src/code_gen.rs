@@ -1,965 +1,770 @@
-use crate::ast::{AbstractSyntaxTree, Document};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::ast::{self, AbstractSyntaxTree, Document};
+use crate::bytecode::LineEntry;
+use crate::diagnostic::Diagnostic;
+use crate::ir::{self, BinaryOp, BlockId, Instruction, Temp, Terminator};
+use crate::lower::lower_function;
 use crate::opcode::OpCode;
+use crate::span::Span;
+use crate::vm::{Value, REGISTER_COUNT};
 
 #[derive(Debug, Clone)]
 pub struct SymbolTable {
     pub functions: Vec<Function>,
     pub constants: Vec<Constant>,
+    pub host_functions: Vec<HostSignature>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Constant {
     pub name: String,
     pub type_annot: String,
-    pub value: String,
+    // Worked out when compiling, see constant::evaluate_constants
+    pub value: Value,
+    pub span: Span,
 }
 #[derive(Debug, Clone)]
 pub struct Variable {
     pub name: String,
     pub type_annot: String,
+    pub span: Span,
+}
+// A function provided by the program embedding bee
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostSignature {
+    // The name bee code calls it by
+    pub name: String,
+    // The name it is registered under with the engine
+    pub binding: String,
+    pub params: Vec<String>,
+    pub returns: String,
+}
+impl fmt::Display for HostSignature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "fn({}) -> {}", self.params.join(", "), self.returns)
+    }
 }
 #[derive(Debug, Clone)]
 pub struct Function {
     pub name: String,
     pub variables: Vec<Variable>,
+    pub span: Span,
 }
 
+// Host calls take their arguments in consecutive registers, so they can
+// have at most as many as there are temporary registers
+pub const MAX_HOST_ARGS: usize = TEMPORARY_REGISTERS;
+
 pub fn code_gen_document(
     document: Document,
     symbol_table: &mut SymbolTable,
     op_codes: &mut Vec<OpCode>,
-) {
-    // There should only be one function(main) in the document for now
-    let main_function = document.functions.first().unwrap();
+) -> Result<(), Diagnostic> {
+    let main_function = main_function(&document)?;
+    let function = lower_function(&main_function.name, &main_function.body, symbol_table)?;
+    code_gen_function(&function, symbol_table, op_codes, &mut Vec::new())
+}
 
-    for statement in &main_function.body {
-        code_gen(statement, symbol_table, op_codes);
+// Analysis accepts any number of functions, so editors can work with them,
+// but only main is compiled until calls between functions are supported.
+// Being the only function, it's also the last one in the symbol table,
+// which is where lowering and code generation look up variables.
+pub fn main_function(document: &Document) -> Result<&ast::Function, Diagnostic> {
+    let Some(main) = document.functions.iter().find(|f| f.name == "main") else {
+        return Err(Diagnostic::new("Expected a main function"));
+    };
+    if let Some(function) = document.functions.iter().find(|f| f.name != "main") {
+        return Err(Diagnostic::new(format!(
+            "Only a main function is supported for now, found: {}",
+            function.name
+        ))
+        .with_span(function.span));
     }
-
-    // Again, for now, we just halt the program after the main function
-    op_codes.push(OpCode::Halt);
+    Ok(main)
 }
 
+// Compiles one statement as a program of its own, ending in a halt, which
+// is how the repl runs each input
 pub fn code_gen(
     ast: &AbstractSyntaxTree,
     symbol_table: &mut SymbolTable,
     op_codes: &mut Vec<OpCode>,
-) {
-    match ast {
-        AbstractSyntaxTree::Let {
-            name,
-            type_annot: _,
-            value,
-        } => match *value.to_owned() {
-            AbstractSyntaxTree::Name {
-                name: var_or_const_name,
-            } => {
-                match symbol_table
-                    .constants
-                    .iter()
-                    .find(|v| v.name == var_or_const_name)
-                {
-                    Some(constant) => {
-                        let target_index = symbol_table
-                            .functions
-                            .last()
-                            .unwrap()
-                            .variables
-                            .iter()
-                            .position(|v| v.name == name.to_owned())
-                            .unwrap();
-                        op_codes.push(OpCode::LoadIntConst {
-                            arg1: 1,
-                            arg2: constant.value.parse().unwrap(),
-                        });
-                        op_codes.push(OpCode::Store {
-                            arg1: target_index,
-                            arg2: 1,
-                        });
-                    }
-                    None => {
-                        let var_index = symbol_table
-                            .functions
-                            .last()
-                            .unwrap()
-                            .variables
-                            .iter()
-                            .position(|v| v.name == var_or_const_name)
-                            .unwrap();
-                        let target_index = symbol_table
-                            .functions
-                            .last()
-                            .unwrap()
-                            .variables
-                            .iter()
-                            .position(|v| v.name == name.to_owned())
-                            .unwrap();
-                        op_codes.push(OpCode::Load {
-                            arg1: 1,
-                            arg2: var_index,
-                        });
-                        op_codes.push(OpCode::Store {
-                            arg1: target_index,
-                            arg2: 1,
-                        });
-                    }
+) -> Result<(), Diagnostic> {
+    let function = lower_function("main", std::slice::from_ref(ast), symbol_table)?;
+    code_gen_function(&function, symbol_table, op_codes, &mut Vec::new())
+}
+
+// Spill slots are added to the last function of the symbol table
+pub fn code_gen_function(
+    function: &ir::Function,
+    symbol_table: &mut SymbolTable,
+    op_codes: &mut Vec<OpCode>,
+    line_table: &mut Vec<LineEntry>,
+) -> Result<(), Diagnostic> {
+    let mut first_args = HashMap::new();
+    for block in &function.blocks {
+        for instruction in &block.instructions {
+            if let Instruction::CallHost { args, .. } = instruction {
+                if let Some(first) = args.first() {
+                    first_args.insert(*first, args.len());
                 }
             }
-            AbstractSyntaxTree::UpName { name: upname } => match upname.as_str() {
-                "True" => {
-                    let target_index = symbol_table
-                        .functions
-                        .last()
-                        .unwrap()
-                        .variables
-                        .iter()
-                        .position(|v| v.name == name.to_owned())
-                        .unwrap();
-                    op_codes.push(OpCode::StoreIntConst {
-                        arg1: target_index,
-                        arg2: 1,
-                    });
-                }
-                "False" => {
-                    let target_index = symbol_table
-                        .functions
-                        .last()
-                        .unwrap()
-                        .variables
-                        .iter()
-                        .position(|v| v.name == name.to_owned())
-                        .unwrap();
-                    op_codes.push(OpCode::StoreIntConst {
-                        arg1: target_index,
-                        arg2: 0,
-                    });
-                }
-                _ => panic!("Invalid value"),
-            },
-            AbstractSyntaxTree::Int { value } => {
-                let target_index = symbol_table
-                    .functions
-                    .last()
-                    .unwrap()
-                    .variables
-                    .iter()
-                    .position(|v| v.name == name.to_owned())
-                    .unwrap();
-                op_codes.push(OpCode::StoreIntConst {
-                    arg1: target_index,
-                    arg2: value,
-                });
+        }
+    }
+    let mut generator = Generator {
+        live_in: function.live_in(),
+        first_args,
+        registers: Registers::default(),
+        positions: HashMap::new(),
+        entry_states: HashMap::new(),
+        variables: symbol_table
+            .functions
+            .last_mut()
+            .map(|function| &mut function.variables)
+            .ok_or_else(|| Diagnostic::new("Function not found"))?,
+        op_codes,
+    };
+    let mut starts = Vec::new();
+    let mut jumps = Vec::new();
+    let mut falls_through = false;
+    for (index, block) in function.blocks.iter().enumerate() {
+        let id = BlockId(index);
+        if !falls_through {
+            if let Some(state) = generator.entry_states.get(&id) {
+                generator.registers = state.clone();
             }
-            AbstractSyntaxTree::Float { value } => {
-                let target_index = symbol_table
-                    .functions
-                    .last()
-                    .unwrap()
-                    .variables
-                    .iter()
-                    .position(|v| v.name == name.to_owned())
-                    .unwrap();
-                op_codes.push(OpCode::StoreFloatConst {
-                    arg1: target_index,
-                    arg2: value,
+        }
+        let live = generator.live_in[index].clone();
+        generator.free_dead(&live);
+        starts.push(generator.op_codes.len());
+
+        let live_after = function.live_after(index, &generator.live_in);
+        let mut skip = false;
+        for position in 0..=block.instructions.len() {
+            for (_, span) in block.lines.iter().filter(|(start, _)| *start == position) {
+                line_table.push(LineEntry {
+                    pc: generator.op_codes.len(),
+                    span: *span,
                 });
             }
-            AbstractSyntaxTree::String { value } => {
-                let target_index = symbol_table
-                    .functions
-                    .last()
-                    .unwrap()
-                    .variables
-                    .iter()
-                    .position(|v| v.name == name.to_owned())
-                    .unwrap();
-                op_codes.push(OpCode::StoreStringConst {
-                    arg1: target_index,
-                    arg2: value.into(),
-                });
+            let Some(instruction) = block.instructions.get(position) else {
+                break;
+            };
+            if skip {
+                skip = false;
+                continue;
             }
-            AbstractSyntaxTree::Call {
-                name: calling_fn_name,
-                args,
+            // Constants that are only stored don't need a register
+            if let (
+                Instruction::Const { dest, value },
+                Some(Instruction::Store {
+                    variable,
+                    value: stored,
+                }),
+            ) = (instruction, block.instructions.get(position + 1))
+            {
+                if dest == stored && !live_after[position + 1].contains(dest) {
+                    generator.op_codes.push(store_constant(*variable, value));
+                    skip = true;
+                    continue;
+                }
+            }
+            generator.instruction(instruction, &live_after[position])?;
+        }
+
+        let next = BlockId(index + 1);
+        falls_through = match &block.terminator {
+            Terminator::Halt => {
+                generator.op_codes.push(OpCode::Halt);
+                false
+            }
+            Terminator::Jump(target) => {
+                generator.enter(*target);
+                if *target != next {
+                    jumps.push((generator.op_codes.len(), *target));
+                    generator.op_codes.push(OpCode::Jump { target: 0 });
+                }
+                *target == next
+            }
+            Terminator::Branch {
+                condition,
+                if_true,
+                if_false,
             } => {
-                // if builtin function then generate code for it, if not then panic
-                match calling_fn_name.as_str() {
-                    "add" => {
-                        let arg1 = match args.first().unwrap() {
-                            AbstractSyntaxTree::Int { value } => *value,
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => {
-                                        let constant_value = constant.value.parse().unwrap();
-                                        op_codes.push(OpCode::LoadIntConst {
-                                            arg1: 1,
-                                            arg2: constant_value,
-                                        });
-                                        1
-                                    }
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 1,
-                                            arg2: var_index,
-                                        });
-                                        1
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let arg2 = match args.last().unwrap() {
-                            AbstractSyntaxTree::Int { value } => *value,
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => {
-                                        let constant_value = constant.value.parse().unwrap();
-                                        op_codes.push(OpCode::LoadIntConst {
-                                            arg1: 2,
-                                            arg2: constant_value,
-                                        });
-                                        2
-                                    }
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 2,
-                                            arg2: var_index,
-                                        });
-                                        2
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let target_index = symbol_table
-                            .functions
-                            .last()
-                            .unwrap()
-                            .variables
-                            .iter()
-                            .position(|v| v.name == name.to_owned())
-                            .unwrap();
-                        op_codes.push(OpCode::Add {
-                            arg1: 3,
-                            arg2: arg1,
-                            arg3: arg2,
-                        });
-                        op_codes.push(OpCode::Store {
-                            arg1: target_index,
-                            arg2: 3,
-                        });
-                    }
-                    "sub" => {
-                        let arg1 = match args.first().unwrap() {
-                            AbstractSyntaxTree::Int { value } => *value,
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => constant.value.parse().unwrap(),
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 1,
-                                            arg2: var_index,
-                                        });
-                                        1
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let arg2 = match args.last().unwrap() {
-                            AbstractSyntaxTree::Int { value } => *value,
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => constant.value.parse().unwrap(),
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 2,
-                                            arg2: var_index,
-                                        });
-                                        2
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let target_index = symbol_table
-                            .functions
-                            .last()
-                            .unwrap()
-                            .variables
-                            .iter()
-                            .position(|v| v.name == name.to_owned())
-                            .unwrap();
-                        op_codes.push(OpCode::Sub {
-                            arg1: 3,
-                            arg2: arg1,
-                            arg3: arg2,
-                        });
-                        op_codes.push(OpCode::Store {
-                            arg1: target_index,
-                            arg2: 3,
-                        });
-                    }
-                    "add_float" => {
-                        let arg1 = match args.first().unwrap() {
-                            AbstractSyntaxTree::Float { value } => {
-                                op_codes.push(OpCode::LoadFloatConst {
-                                    arg1: 1,
-                                    arg2: *value,
-                                });
-                                1
-                            }
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => {
-                                        op_codes.push(OpCode::LoadFloatConst {
-                                            arg1: 1,
-                                            arg2: constant.value.parse().unwrap(),
-                                        });
-                                        1
-                                    }
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 1,
-                                            arg2: var_index,
-                                        });
-                                        1
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let arg2 = match args.last().unwrap() {
-                            AbstractSyntaxTree::Float { value } => {
-                                op_codes.push(OpCode::LoadFloatConst {
-                                    arg1: 2,
-                                    arg2: *value,
-                                });
-                                2
-                            }
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => {
-                                        op_codes.push(OpCode::LoadFloatConst {
-                                            arg1: 2,
-                                            arg2: constant.value.parse().unwrap(),
-                                        });
-                                        2
-                                    }
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 2,
-                                            arg2: var_index,
-                                        });
-                                        2
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let target_index = symbol_table
-                            .functions
-                            .last()
-                            .unwrap()
-                            .variables
-                            .iter()
-                            .position(|v| v.name == name.to_owned())
-                            .unwrap();
-                        op_codes.push(OpCode::Add {
-                            arg1: 3,
-                            arg2: arg1,
-                            arg3: arg2,
-                        });
-                        op_codes.push(OpCode::Store {
-                            arg1: target_index,
-                            arg2: 3,
-                        });
-                    }
-                    "sub_float" => {
-                        let arg1 = match args.first().unwrap() {
-                            AbstractSyntaxTree::Float { value } => {
-                                op_codes.push(OpCode::LoadFloatConst {
-                                    arg1: 1,
-                                    arg2: *value,
-                                });
-                                1
-                            }
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => {
-                                        op_codes.push(OpCode::LoadFloatConst {
-                                            arg1: 1,
-                                            arg2: constant.value.parse().unwrap(),
-                                        });
-                                        1
-                                    }
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 1,
-                                            arg2: var_index,
-                                        });
-                                        1
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let arg2 = match args.last().unwrap() {
-                            AbstractSyntaxTree::Float { value } => {
-                                op_codes.push(OpCode::LoadFloatConst {
-                                    arg1: 2,
-                                    arg2: *value,
-                                });
-                                2
-                            }
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => {
-                                        op_codes.push(OpCode::LoadFloatConst {
-                                            arg1: 2,
-                                            arg2: constant.value.parse().unwrap(),
-                                        });
-                                        2
-                                    }
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 2,
-                                            arg2: var_index,
-                                        });
-                                        2
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let target_index = symbol_table
-                            .functions
-                            .last()
-                            .unwrap()
-                            .variables
-                            .iter()
-                            .position(|v| v.name == name.to_owned())
-                            .unwrap();
-                        op_codes.push(OpCode::Sub {
-                            arg1: 3,
-                            arg2: arg1,
-                            arg3: arg2,
-                        });
-                        op_codes.push(OpCode::Store {
-                            arg1: target_index,
-                            arg2: 3,
-                        });
-                    }
-                    "concat" => {
-                        let arg1 = match args.first().unwrap() {
-                            AbstractSyntaxTree::String { value } => {
-                                op_codes.push(OpCode::LoadStringConst {
-                                    arg1: 1,
-                                    arg2: value.clone().into_boxed_str(),
-                                });
-                                1
-                            }
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => {
-                                        op_codes.push(OpCode::LoadStringConst {
-                                            arg1: 1,
-                                            arg2: constant.value.clone().into_boxed_str(),
-                                        });
-                                        1
-                                    }
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 1,
-                                            arg2: var_index,
-                                        });
-                                        1
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let arg2 = match args.last().unwrap() {
-                            AbstractSyntaxTree::String { value } => {
-                                op_codes.push(OpCode::LoadStringConst {
-                                    arg1: 2,
-                                    arg2: value.clone().into_boxed_str(),
-                                });
-                                2
-                            }
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => {
-                                        op_codes.push(OpCode::LoadStringConst {
-                                            arg1: 2,
-                                            arg2: constant.value.clone().into_boxed_str(),
-                                        });
-                                        2
-                                    }
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 2,
-                                            arg2: var_index,
-                                        });
-                                        2
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let target_index = symbol_table
-                            .functions
-                            .last()
-                            .unwrap()
-                            .variables
-                            .iter()
-                            .position(|v| v.name == name.to_owned())
-                            .unwrap();
-                        op_codes.push(OpCode::Concat {
-                            arg1: 3,
-                            arg2: arg1,
-                            arg3: arg2,
-                        });
-                        op_codes.push(OpCode::Store {
-                            arg1: target_index,
-                            arg2: 3,
-                        });
-                    }
-                    "and" => {
-                        let arg1 = match args.first().unwrap() {
-                            AbstractSyntaxTree::UpName { name } => match name.as_str() {
-                                "True" => 1,
-                                "False" => 0,
-                                _ => panic!("Invalid value"),
-                            },
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => constant.value.parse().unwrap(),
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 1,
-                                            arg2: var_index,
-                                        });
-                                        1
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let arg2 = match args.last().unwrap() {
-                            AbstractSyntaxTree::UpName { name } => match name.as_str() {
-                                "True" => 1,
-                                "False" => 0,
-                                _ => panic!("Invalid value"),
-                            },
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => constant.value.parse().unwrap(),
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 2,
-                                            arg2: var_index,
-                                        });
-                                        2
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let target_index = symbol_table
-                            .functions
-                            .last()
-                            .unwrap()
-                            .variables
-                            .iter()
-                            .position(|v| v.name == name.to_owned())
-                            .unwrap();
-                        op_codes.push(OpCode::And {
-                            arg1: 3,
-                            arg2: arg1,
-                            arg3: arg2,
-                        });
-                        op_codes.push(OpCode::Store {
-                            arg1: target_index,
-                            arg2: 3,
-                        });
-                    }
-                    "or" => {
-                        let arg1 = match args.first().unwrap() {
-                            AbstractSyntaxTree::UpName { name } => match name.as_str() {
-                                "True" => 1,
-                                "False" => 0,
-                                _ => panic!("Invalid value"),
-                            },
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => constant.value.parse().unwrap(),
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 1,
-                                            arg2: var_index,
-                                        });
-                                        1
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let arg2 = match args.last().unwrap() {
-                            AbstractSyntaxTree::UpName { name } => match name.as_str() {
-                                "True" => 1,
-                                "False" => 0,
-                                _ => panic!("Invalid value"),
-                            },
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => constant.value.parse().unwrap(),
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 2,
-                                            arg2: var_index,
-                                        });
-                                        2
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let target_index = symbol_table
-                            .functions
-                            .last()
-                            .unwrap()
-                            .variables
-                            .iter()
-                            .position(|v| v.name == name.to_owned())
-                            .unwrap();
-                        op_codes.push(OpCode::Or {
-                            arg1: 3,
-                            arg2: arg1,
-                            arg3: arg2,
-                        });
-                        op_codes.push(OpCode::Store {
-                            arg1: target_index,
-                            arg2: 3,
-                        });
-                    }
-                    "not" => {
-                        let value = match args.first().unwrap() {
-                            AbstractSyntaxTree::UpName { name } => match name.as_str() {
-                                "True" => 1,
-                                "False" => 0,
-                                _ => panic!("Invalid value"),
-                            },
-                            AbstractSyntaxTree::Name { name } => {
-                                match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                    Some(constant) => constant.value.parse().unwrap(),
-                                    None => {
-                                        let var_index = symbol_table
-                                            .functions
-                                            .last()
-                                            .unwrap()
-                                            .variables
-                                            .iter()
-                                            .position(|v| v.name == *name)
-                                            .unwrap();
-                                        op_codes.push(OpCode::Load {
-                                            arg1: 1,
-                                            arg2: var_index,
-                                        });
-                                        1
-                                    }
-                                }
-                            }
-                            _ => panic!("Invalid value"),
-                        };
-                        let target_index = symbol_table
-                            .functions
-                            .last()
-                            .unwrap()
-                            .variables
-                            .iter()
-                            .position(|v| v.name == name.to_owned())
-                            .unwrap();
-                        op_codes.push(OpCode::Not { value });
-                        op_codes.push(OpCode::Store {
-                            arg1: target_index,
-                            arg2: 1,
-                        });
-                    }
-                    _ => panic!("Invalid function name"),
+                let position = generator.positions[condition];
+                let arg1 = generator.reload(position);
+                let (op_code, taken) = if *if_true == next {
+                    (OpCode::JumpIfFalse { arg1, target: 0 }, *if_false)
+                } else {
+                    (OpCode::JumpIfTrue { arg1, target: 0 }, *if_true)
+                };
+                jumps.push((generator.op_codes.len(), taken));
+                generator.op_codes.push(op_code);
+                generator.enter(taken);
+                let other = if taken == *if_true {
+                    *if_false
+                } else {
+                    *if_true
+                };
+                generator.enter(other);
+                if other != next {
+                    jumps.push((generator.op_codes.len(), other));
+                    generator.op_codes.push(OpCode::Jump { target: 0 });
                 }
+                other == next
             }
-            value => panic!("Invalid value: {:?}", value),
+        };
+    }
+    for (pc, block) in jumps {
+        if let OpCode::Jump { target }
+        | OpCode::JumpIfFalse { target, .. }
+        | OpCode::JumpIfTrue { target, .. } = &mut generator.op_codes[pc]
+        {
+            *target = starts[block.0];
+        }
+    }
+    Ok(())
+}
+
+fn store_constant(variable: usize, value: &Value) -> OpCode {
+    match value {
+        Value::Int(value) => OpCode::StoreIntConst {
+            arg1: variable,
+            arg2: *value,
+        },
+        Value::Float(value) => OpCode::StoreFloatConst {
+            arg1: variable,
+            arg2: *value,
+        },
+        Value::String(value) => OpCode::StoreStringConst {
+            arg1: variable,
+            arg2: (**value).into(),
+        },
+        Value::Bool(value) => OpCode::StoreBoolConst {
+            arg1: variable,
+            arg2: *value,
+        },
+    }
+}
+
+fn load_constant(register: usize, value: &Value) -> OpCode {
+    match value {
+        Value::Int(value) => OpCode::LoadIntConst {
+            arg1: register,
+            arg2: *value,
+        },
+        Value::Float(value) => OpCode::LoadFloatConst {
+            arg1: register,
+            arg2: *value,
+        },
+        Value::String(value) => OpCode::LoadStringConst {
+            arg1: register,
+            arg2: (**value).into(),
         },
-        AbstractSyntaxTree::Block { statements } => {
-            for statement in statements {
-                code_gen(statement, symbol_table, op_codes);
+        Value::Bool(value) => OpCode::LoadBoolConst {
+            arg1: register,
+            arg2: *value,
+        },
+    }
+}
+
+struct Generator<'a> {
+    live_in: Vec<HashSet<Temp>>,
+    // Host calls need their arguments in consecutive registers, so their
+    // first argument has to leave room for the rest
+    first_args: HashMap<Temp, usize>,
+    registers: Registers,
+    // Where each temporary lives. A temporary assigned on more than one
+    // path keeps the same position on all of them.
+    positions: HashMap<Temp, usize>,
+    // The registers on entry to blocks that have been jumped to but not
+    // reached yet
+    entry_states: HashMap<BlockId, Registers>,
+    // The function's variables, which spill slots are added to
+    variables: &'a mut Vec<Variable>,
+    op_codes: &'a mut Vec<OpCode>,
+}
+
+impl Generator<'_> {
+    fn instruction(
+        &mut self,
+        instruction: &Instruction,
+        live_after: &HashSet<Temp>,
+    ) -> Result<(), Diagnostic> {
+        let uses = instruction.uses();
+        let registers: Vec<usize> = uses
+            .iter()
+            .map(|temp| self.reload(self.positions[temp]))
+            .collect();
+        for temp in &uses {
+            if !live_after.contains(temp) && Some(*temp) != instruction.dest() {
+                self.registers.free(self.positions[temp]);
             }
         }
-        AbstractSyntaxTree::Call { name, args } => match name.as_str() {
-            "print_integer" => match args.first() {
-                Some(arg) => match arg {
-                    AbstractSyntaxTree::Int { value } => {
-                        op_codes.push(OpCode::LoadIntConst {
-                            arg1: 1,
-                            arg2: *value,
-                        });
-                        op_codes.push(OpCode::Print { arg1: 1 });
-                    }
-                    AbstractSyntaxTree::Name { name } => {
-                        match symbol_table.constants.iter().find(|v| v.name == *name) {
-                            Some(constant) => {
-                                op_codes.push(OpCode::LoadIntConst {
-                                    arg1: 1,
-                                    arg2: constant.value.parse().unwrap(),
-                                });
-                                op_codes.push(OpCode::Print { arg1: 1 });
-                            }
-                            None => {
-                                let var_index = symbol_table
-                                    .functions
-                                    .last()
-                                    .unwrap()
-                                    .variables
-                                    .iter()
-                                    .position(|v| v.name == *name)
-                                    .unwrap();
-                                op_codes.push(OpCode::Load {
-                                    arg1: 1,
-                                    arg2: var_index,
-                                });
-                                op_codes.push(OpCode::Print { arg1: 1 });
-                            }
-                        }
-                    }
-                    _ => panic!("Invalid value"),
-                },
-                None => panic!("No args"),
-            },
-            "print_float" => match args.first() {
-                Some(arg) => match arg {
-                    AbstractSyntaxTree::Float { value } => {
-                        op_codes.push(OpCode::LoadFloatConst {
-                            arg1: 1,
-                            arg2: *value,
-                        });
-                        op_codes.push(OpCode::Print { arg1: 1 });
-                    }
-                    AbstractSyntaxTree::Name { name } => {
-                        match symbol_table.constants.iter().find(|v| v.name == *name) {
-                            Some(constant) => {
-                                op_codes.push(OpCode::LoadFloatConst {
-                                    arg1: 1,
-                                    arg2: constant.value.parse().unwrap(),
-                                });
-                                op_codes.push(OpCode::Print { arg1: 1 });
-                            }
-                            None => {
-                                let var_index = symbol_table
-                                    .functions
-                                    .last()
-                                    .unwrap()
-                                    .variables
-                                    .iter()
-                                    .position(|v| v.name == *name)
-                                    .unwrap();
-                                op_codes.push(OpCode::Load {
-                                    arg1: 1,
-                                    arg2: var_index,
-                                });
-                                op_codes.push(OpCode::Print { arg1: 1 });
-                            }
-                        }
-                    }
-                    _ => panic!("Invalid value"),
-                },
-                None => panic!("No args"),
+        let op_code = match instruction {
+            Instruction::Const { dest, value } => load_constant(self.define(*dest)?, value),
+            Instruction::Load { dest, variable } => OpCode::Load {
+                arg1: self.define(*dest)?,
+                arg2: *variable,
             },
-            "print_bool" => match args.first() {
-                Some(arg) => match arg {
-                    AbstractSyntaxTree::UpName { name } => match name.as_str() {
-                        "True" => {
-                            op_codes.push(OpCode::Print { arg1: 1 });
-                        }
-                        "False" => {
-                            op_codes.push(OpCode::Print { arg1: 0 });
-                        }
-                        _ => panic!("Invalid value"),
-                    },
-                    AbstractSyntaxTree::Name { name } => {
-                        match symbol_table.constants.iter().find(|v| v.name == *name) {
-                            Some(constant) => {
-                                op_codes.push(OpCode::LoadIntConst {
-                                    arg1: 1,
-                                    arg2: constant.value.parse().unwrap(),
-                                });
-                                op_codes.push(OpCode::Print { arg1: 1 });
-                            }
-                            None => {
-                                let var_index = symbol_table
-                                    .functions
-                                    .last()
-                                    .unwrap()
-                                    .variables
-                                    .iter()
-                                    .position(|v| v.name == *name)
-                                    .unwrap();
-                                op_codes.push(OpCode::Load {
-                                    arg1: 1,
-                                    arg2: var_index,
-                                });
-                                op_codes.push(OpCode::Print { arg1: 1 });
-                            }
-                        }
-                    }
-                    _ => panic!("Invalid value"),
-                },
-                None => panic!("No args"),
+            Instruction::Store { variable, .. } => OpCode::Store {
+                arg1: *variable,
+                arg2: registers[0],
             },
-            "print_string" => match args.first() {
-                Some(arg) => match arg {
-                    AbstractSyntaxTree::String { value } => {
-                        op_codes.push(OpCode::LoadStringConst {
-                            arg1: 1,
-                            arg2: value.clone().into_boxed_str(),
-                        });
-                        op_codes.push(OpCode::Print { arg1: 1 });
+            Instruction::Binary { op, dest, .. } => {
+                let (arg1, arg2, arg3) = (self.define(*dest)?, registers[0], registers[1]);
+                match op {
+                    BinaryOp::Add => OpCode::Add { arg1, arg2, arg3 },
+                    BinaryOp::Sub => OpCode::Sub { arg1, arg2, arg3 },
+                    BinaryOp::Concat => OpCode::Concat { arg1, arg2, arg3 },
+                }
+            }
+            // Not works in place
+            Instruction::Not { dest, .. } => {
+                let value = self.define(*dest)?;
+                if value != registers[0] {
+                    self.op_codes.push(OpCode::Move {
+                        arg1: value,
+                        arg2: registers[0],
+                    });
+                }
+                OpCode::Not { value }
+            }
+            Instruction::CallHost {
+                dest,
+                binding,
+                args,
+            } => {
+                if !registers.windows(2).all(|pair| pair[1] == pair[0] + 1) {
+                    return Err(Diagnostic::new(
+                        "Arguments are not in consecutive registers",
+                    ));
+                }
+                let dest = self.define(*dest)?;
+                OpCode::CallHost {
+                    name: binding.clone(),
+                    arg1: dest,
+                    arg2: registers.first().copied().unwrap_or(dest),
+                    arg3: args.len(),
+                }
+            }
+            Instruction::Print { .. } => OpCode::Print { arg1: registers[0] },
+        };
+        self.op_codes.push(op_code);
+        Ok(())
+    }
+
+    // Gives the temporary a position, claiming its register, and returns
+    // the register
+    fn define(&mut self, temp: Temp) -> Result<usize, Diagnostic> {
+        let position = match self.positions.get(&temp) {
+            Some(position) => *position,
+            None => {
+                let mut position = self.registers.depth();
+                if let Some(&count) = self.first_args.get(&temp) {
+                    if count > MAX_HOST_ARGS {
+                        return Err(Diagnostic::new(format!(
+                            "Host functions can take at most {} arguments",
+                            MAX_HOST_ARGS
+                        )));
                     }
-                    AbstractSyntaxTree::Name { name } => {
-                        let var_index = symbol_table
-                            .functions
-                            .last()
-                            .unwrap()
-                            .variables
-                            .iter()
-                            .position(|v| v.name == *name)
-                            .unwrap();
-                        op_codes.push(OpCode::Load {
-                            arg1: 1,
-                            arg2: var_index,
-                        });
-                        op_codes.push(OpCode::Print { arg1: 1 });
+                    if position % TEMPORARY_REGISTERS + count > TEMPORARY_REGISTERS {
+                        position = position.next_multiple_of(TEMPORARY_REGISTERS);
                     }
-                    _ => panic!("Invalid value"),
-                },
-                None => panic!("No args"),
+                }
+                self.positions.insert(temp, position);
+                position
+            }
+        };
+        Ok(self
+            .registers
+            .occupy(position, self.variables, self.op_codes))
+    }
+
+    fn reload(&mut self, position: usize) -> usize {
+        self.registers
+            .reload(position, self.variables, self.op_codes)
+    }
+
+    // Positions of temporaries that aren't needed any more are given up
+    fn free_dead(&mut self, live: &HashSet<Temp>) {
+        let live: HashSet<usize> = live
+            .iter()
+            .filter_map(|temp| self.positions.get(temp).copied())
+            .collect();
+        for position in (0..self.registers.depth()).rev() {
+            if !live.contains(&position) {
+                self.registers.free(position);
+            }
+        }
+    }
+
+    // Called before control moves to a block. The first path to get there
+    // decides where values are on entry, and the others move values to
+    // match. Values spilled on either path are spilled on entry.
+    fn enter(&mut self, target: BlockId) {
+        let Some(state) = self.entry_states.get(&target).cloned() else {
+            self.entry_states.insert(target, self.registers.clone());
+            return;
+        };
+        let mut positions: Vec<usize> = self.live_in[target.0]
+            .iter()
+            .filter_map(|temp| self.positions.get(temp).copied())
+            .collect();
+        positions.sort();
+        for position in positions {
+            match (
+                state.homes.get(position),
+                self.registers.homes.get(position),
+            ) {
+                (Some(Home::Register), Some(Home::Spilled)) => {
+                    self.reload(position);
+                }
+                (Some(Home::Spilled), Some(Home::Register)) => {
+                    self.op_codes.push(OpCode::Store {
+                        arg1: spill_slot(self.variables, position),
+                        arg2: Registers::register(position),
+                    });
+                    self.registers.homes[position] = Home::Spilled;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// Registers r1 onwards are used as a stack of temporaries: a new
+// temporary takes the next position, and positions are given up once their
+// temporary is no longer needed. Position p lives in r(1 + p % 31), so once
+// more than 31 values are live a position shares its register with the
+// one 31 below it, which is then spilled to a hidden variable slot until
+// it's needed again. r0 is left for the program's result.
+const TEMPORARY_REGISTERS: usize = REGISTER_COUNT - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Home {
+    Register,
+    Spilled,
+    // Skipped so that host call arguments are in consecutive registers, or
+    // given up
+    Empty,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Registers {
+    // One entry per live position
+    homes: Vec<Home>,
+    // The position whose value each register holds
+    owners: [Option<usize>; TEMPORARY_REGISTERS],
+}
+
+impl Registers {
+    fn register(position: usize) -> usize {
+        1 + position % TEMPORARY_REGISTERS
+    }
+
+    fn depth(&self) -> usize {
+        self.homes.len()
+    }
+
+    // Claims the register of a position, spilling whoever was using it
+    fn occupy(
+        &mut self,
+        position: usize,
+        variables: &mut Vec<Variable>,
+        op_codes: &mut Vec<OpCode>,
+    ) -> usize {
+        self.skip_to(position + 1);
+        self.homes[position] = Home::Register;
+        self.claim(position, variables, op_codes)
+    }
+
+    fn claim(
+        &mut self,
+        position: usize,
+        variables: &mut Vec<Variable>,
+        op_codes: &mut Vec<OpCode>,
+    ) -> usize {
+        let register = Registers::register(position);
+        if let Some(owner) = self.owners[register - 1] {
+            if owner != position && self.homes.get(owner) == Some(&Home::Register) {
+                op_codes.push(OpCode::Store {
+                    arg1: spill_slot(variables, owner),
+                    arg2: register,
+                });
+                self.homes[owner] = Home::Spilled;
+            }
+        }
+        self.owners[register - 1] = Some(position);
+        register
+    }
+
+    // Skips positions without giving them a value
+    fn skip_to(&mut self, position: usize) {
+        while self.homes.len() < position {
+            self.homes.push(Home::Empty);
+        }
+    }
+
+    // Makes sure the value at the position is back in its register
+    fn reload(
+        &mut self,
+        position: usize,
+        variables: &mut Vec<Variable>,
+        op_codes: &mut Vec<OpCode>,
+    ) -> usize {
+        if self.homes[position] != Home::Spilled {
+            return Registers::register(position);
+        }
+        let register = self.claim(position, variables, op_codes);
+        op_codes.push(OpCode::Load {
+            arg1: register,
+            arg2: spill_slot(variables, position),
+        });
+        self.homes[position] = Home::Register;
+        register
+    }
+
+    // Also drops any empty positions left on top
+    fn free(&mut self, position: usize) {
+        if let Some(home) = self.homes.get_mut(position) {
+            *home = Home::Empty;
+        }
+        while self.homes.last() == Some(&Home::Empty) {
+            self.homes.pop();
+        }
+    }
+}
+
+// Spill slots are variables that can't clash with a name in bee source
+fn spill_slot(variables: &mut Vec<Variable>, position: usize) -> usize {
+    let name = format!("$spill{}", position);
+    match variables.iter().position(|v| v.name == name) {
+        Some(index) => index,
+        None => {
+            variables.push(Variable {
+                name,
+                type_annot: String::new(),
+                span: Span::default(),
+            });
+            variables.len() - 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::optimize::OptLevel;
+    use crate::testing::Captured;
+    use crate::{asm, testing, verify, Engine, Value};
+
+    #[test]
+    fn bools_are_values_and_short_circuit() {
+        let (engine, output) = testing::engine(OptLevel::O0);
+        let program = engine
+            .compile(
+                r#"
+                const yes: Bool = True
+                fn main() {
+                    let no: Bool = False
+                    let both: Bool = and(no, yes)
+                    let either: Bool = or(yes, no)
+                    let neither: Bool = not(either)
+                    print_bool(both)
+                    print_bool(either)
+                    print_bool(neither)
+                    print_bool(True)
+                }"#,
+            )
+            .unwrap();
+        assert_eq!(verify::verify(&program), Ok(()));
+        engine.run(&program).unwrap();
+        assert_eq!(output.take(), "False\nTrue\nFalse\nTrue\n");
+        // and jumps past its second argument when the first is False, and or
+        // when it's True
+        let text = asm::disassemble(program.op_codes());
+        assert_eq!(
+            text.lines().take(6).collect::<Vec<_>>(),
+            [
+                "store_bool s0, False",
+                "load r1, s0",
+                "jump_if_false r1, 4",
+                "load_bool r1, True",
+                "store s1, r1",
+                "load_bool r1, True",
+            ]
+        );
+        assert!(text.contains("jump_if_true r1, 8\n"));
+    }
+
+    #[test]
+    fn host_calls_fit_in_the_temporary_registers() {
+        let external = |count: usize| {
+            let params: Vec<String> = (0..count).map(|i| format!("a{}: Integer", i)).collect();
+            let args = vec!["1"; count].join(", ");
+            format!(
+                "@external(rust, \"math\", \"sum\")\nfn sum({}) -> Integer\nfn main() {{\n  print_integer(sum({}))\n  sum({})\n}}",
+                params.join(", "),
+                args,
+                args
+            )
+        };
+        let (mut engine, output) = testing::engine(OptLevel::O0);
+        engine.register_fn("math.sum", &["Integer"; 31], "Integer", |args| {
+            Ok(Value::Int(
+                args.iter().filter(|arg| **arg == Value::Int(1)).count(),
+            ))
+        });
+        let program = engine.compile(&external(31)).unwrap();
+        assert_eq!(verify::verify(&program), Ok(()));
+        engine.run(&program).unwrap();
+        assert_eq!(output.take(), "31\n");
+
+        assert_eq!(
+            engine.compile(&external(40)).unwrap_err().to_string(),
+            "4:17: sum is called with 40 arguments but host functions can take at most 31"
+        );
+        // Calls made for their effect are checked too
+        let lines: Vec<String> = external(40).lines().map(str::to_owned).collect();
+        let statement_only = [&lines[..3], &lines[4..]].concat().join("\n");
+        assert_eq!(
+            engine.compile(&statement_only).unwrap_err().to_string(),
+            "4:3: sum is called with 40 arguments but host functions can take at most 31"
+        );
+    }
+
+    fn sum3_engine() -> (Engine, Captured) {
+        let (mut engine, output) = testing::engine(OptLevel::O1);
+        engine.register_fn(
+            "sum3",
+            &["Integer", "Integer", "Integer"],
+            "Integer",
+            |args| match args {
+                [Value::Int(a), Value::Int(b), Value::Int(c)] => Ok(Value::Int(a + b + c)),
+                _ => unreachable!(),
             },
-            _ => panic!("Invalid function name"),
-        },
-        _ => {
-            panic!("Invalid code");
+        );
+        (engine, output)
+    }
+
+    fn run(engine: &Engine, output: &Captured, source: &str) -> String {
+        let program = engine.compile(source).unwrap();
+        assert_eq!(verify::verify(&program), Ok(()));
+        engine.run(&program).unwrap();
+        output.take()
+    }
+
+    #[test]
+    fn nested_expressions() {
+        let (engine, output) = sum3_engine();
+        assert_eq!(
+            run(
+                &engine,
+                &output,
+                r#"fn main() {
+                    let a: Integer = 10
+                    print_integer(add(sub(a, 1), 2))
+                    print_string(concat(concat("a", "b"), concat("c", "d")))
+                    print_bool(and(not(False), or(False, True)))
+                    print_integer(sum3(1, add(2, 3), sum3(4, 5, 6)))
+                }"#
+            ),
+            "11\nabcd\nTrue\n21\n"
+        );
+    }
+
+    #[test]
+    fn and_or_only_run_what_they_need() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let (mut engine, output) = testing::engine(OptLevel::O1);
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let log = calls.clone();
+        engine.register_fn("check", &["String"], "Bool", move |args| {
+            log.borrow_mut().push(args[0].clone());
+            Ok(Value::Bool(true))
+        });
+        assert_eq!(
+            run(
+                &engine,
+                &output,
+                r#"fn main() {
+                    let a: Bool = and(False, check("and"))
+                    let b: Bool = or(True, check("or"))
+                    let c: Bool = and(True, check("ran"))
+                    print_bool(c)
+                }"#
+            ),
+            "True\n"
+        );
+        assert_eq!(*calls.borrow(), vec![Value::from("ran")]);
+    }
+
+    #[test]
+    fn deep_expressions_spill_to_slots() {
+        // Forty levels deep needs more than the 31 temporary registers
+        let mut deep = "1".to_owned();
+        let mut calls = "sum3(1, 1, 1)".to_owned();
+        for _ in 0..40 {
+            deep = format!("add(1, {})", deep);
+            calls = format!("add(1, sum3(1, {}, 1))", calls);
         }
+        let source = format!(
+            "fn main() {{\n  print_integer({})\n  print_integer({})\n  print_bool(and(True, or(False, not({}))))\n}}",
+            deep,
+            calls,
+            deep.replace("add(1, 1)", "and(True, True)")
+                .replace("add(1, ", "and(True, ")
+        );
+        let (engine, output) = sum3_engine();
+        assert_eq!(run(&engine, &output, &source), "41\n123\nFalse\n");
+        let program = engine.compile(&source).unwrap();
+        assert!(asm::disassemble(program.op_codes()).contains("store s0, r1\n"));
+        assert!(program.functions[0].variable_count > 1);
+    }
+
+    #[test]
+    fn code_gen_keeps_short_circuit_results_in_one_register() {
+        let source = r#"
+            const two: Integer = 2
+            fn main() {
+                let a: Integer = 1
+                let b: Bool = and(True, not(False))
+                print_integer(add(a, two))
+                print_bool(b)
+            }"#;
+        // The branch and the not leave the result in the same register
+        let program = testing::compile_unoptimized(source).unwrap();
+        assert_eq!(
+            asm::disassemble(program.op_codes()),
+            [
+                "store_int s0, 1",
+                "load_bool r1, True",
+                "jump_if_false r1, 5",
+                "load_bool r1, False",
+                "not r1",
+                "store s1, r1",
+                "load r1, s0",
+                "load_int r2, 2",
+                "add r1, r1, r2",
+                "print r1",
+                "load r1, s1",
+                "print r1",
+                "halt",
+                "",
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            program
+                .line_table()
+                .iter()
+                .map(|entry| entry.pc)
+                .collect::<Vec<_>>(),
+            [0, 1, 6, 10]
+        );
     }
 }
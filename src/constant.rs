@@ -0,0 +1,255 @@
+use crate::ast::{self, AbstractSyntaxTree};
+use crate::code_gen::{Constant, SymbolTable};
+use crate::diagnostic::Diagnostic;
+use crate::vm::{Operation, Value};
+use crate::BUILTINS;
+
+// The types a constant can have. List, tuple and record constants are left
+// for when those types exist in the language, see todo.
+const CONSTANT_TYPES: &[&str] = &["Integer", "Float", "String", "Bool"];
+
+// Constants are evaluated while compiling. They can refer to constants
+// defined later in the file, but not to themselves through any chain.
+pub fn evaluate_constants(
+    constants: &[ast::Constant],
+    symbol_table: &mut SymbolTable,
+) -> Result<(), Diagnostic> {
+    for (i, constant) in constants.iter().enumerate() {
+        if !CONSTANT_TYPES.contains(&constant.type_annot.as_str()) {
+            return Err(Diagnostic::new(format!(
+                "Constants of type {} are not supported, only Integer, Float, String and Bool",
+                constant.type_annot
+            ))
+            .with_span(constant.span));
+        }
+        if constants[..i].iter().any(|c| c.name == constant.name) {
+            return Err(
+                Diagnostic::new(format!("Duplicate constant: {}", constant.name))
+                    .with_span(constant.span),
+            );
+        }
+    }
+    let mut evaluator = Evaluator {
+        constants,
+        values: vec![None; constants.len()],
+        evaluating: vec![false; constants.len()],
+        symbol_table,
+    };
+    for index in 0..constants.len() {
+        evaluator.constant(index)?;
+    }
+    let values: Vec<Value> = evaluator.values.into_iter().map(Option::unwrap).collect();
+    for (constant, value) in constants.iter().zip(values) {
+        // Redefining a constant in the repl replaces it
+        symbol_table.constants.retain(|c| c.name != constant.name);
+        symbol_table.constants.push(Constant {
+            name: constant.name.clone(),
+            type_annot: constant.type_annot.clone(),
+            value,
+            span: constant.span,
+        });
+    }
+    Ok(())
+}
+
+struct Evaluator<'a> {
+    constants: &'a [ast::Constant],
+    values: Vec<Option<Value>>,
+    // Set while a constant's value is being worked out, to find cycles
+    evaluating: Vec<bool>,
+    // Constants defined earlier, by the repl
+    symbol_table: &'a SymbolTable,
+}
+
+impl Evaluator<'_> {
+    fn constant(&mut self, index: usize) -> Result<Value, Diagnostic> {
+        if let Some(value) = &self.values[index] {
+            return Ok(value.clone());
+        }
+        let constant = &self.constants[index];
+        if self.evaluating[index] {
+            return Err(
+                Diagnostic::new(format!("Constant {} depends on itself", constant.name))
+                    .with_span(constant.span),
+            );
+        }
+        self.evaluating[index] = true;
+        let value = self
+            .expression(&constant.value)
+            .map_err(|diagnostic| diagnostic.with_span(constant.span))?;
+        if value.type_name() != constant.type_annot {
+            return Err(Diagnostic::new("Type mismatch").with_span(constant.span));
+        }
+        self.evaluating[index] = false;
+        self.values[index] = Some(value.clone());
+        Ok(value)
+    }
+
+    fn expression(&mut self, expression: &AbstractSyntaxTree) -> Result<Value, Diagnostic> {
+        let value = match expression {
+            AbstractSyntaxTree::Int { value, .. } => Value::Int(*value),
+            AbstractSyntaxTree::Float { value, .. } => Value::Float(*value),
+            AbstractSyntaxTree::String { value } => Value::from(value.as_str()),
+            AbstractSyntaxTree::UpName { name } if name == "True" => Value::Bool(true),
+            AbstractSyntaxTree::UpName { name } if name == "False" => Value::Bool(false),
+            AbstractSyntaxTree::Name { name } => {
+                if let Some(index) = self.constants.iter().position(|c| c.name == *name) {
+                    return self.constant(index);
+                }
+                match self.symbol_table.constants.iter().find(|c| c.name == *name) {
+                    Some(constant) => constant.value.clone(),
+                    None => return Err(Diagnostic::new(format!("Constant not found: {}", name))),
+                }
+            }
+            AbstractSyntaxTree::Call { name, args, .. } => {
+                let Some((_, params, _)) = BUILTINS.iter().find(|(b, _, _)| b == name) else {
+                    return Err(Diagnostic::new(format!(
+                        "{} can't be called in a constant",
+                        name
+                    )));
+                };
+                if args.len() != params.len() {
+                    return Err(Diagnostic::new(format!(
+                        "{} takes {} arguments but was given {}",
+                        name,
+                        params.len(),
+                        args.len()
+                    )));
+                }
+                let mut values = Vec::new();
+                for (arg, param) in args.iter().zip(*params) {
+                    let value = self.expression(arg)?;
+                    if value.type_name() != *param {
+                        return Err(Diagnostic::new("Type mismatch"));
+                    }
+                    values.push(value);
+                }
+                let result = match (name.as_str(), values.as_slice()) {
+                    ("add" | "add_float", [a, b]) => a.add(b),
+                    ("sub" | "sub_float", [a, b]) => a.sub(b),
+                    ("and", [a, b]) => a.and(b),
+                    ("or", [a, b]) => a.or(b),
+                    ("not", [a]) => a.not(),
+                    ("concat", [a, b]) => a.concat(b),
+                    _ => unreachable!("Unknown builtin {}", name),
+                };
+                result.map_err(|kind| Diagnostic::new(format!("Invalid constant: {}", kind)))?
+            }
+            _ => return Err(Diagnostic::new("Invalid value")),
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::optimize::OptLevel;
+    use crate::{asm, compile_source, testing, Engine, Value};
+
+    const MAIN: &str = "\nfn main() {\n}";
+
+    fn error(source: &str) -> String {
+        compile_source(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn constants_are_evaluated_at_compile_time() {
+        let (engine, output) = testing::engine(OptLevel::O0);
+        let program = engine
+            .compile(
+                r#"
+                const hour: Integer = add(minute, sub(minute, 1))
+                const minute: Integer = add(30, 30)
+                const rate: Float = sub_float(2.5, half)
+                const half: Float = 0.5
+                const greeting: String = concat(concat("hi", " "), name)
+                const name: String = "bee"
+                const ready: Bool = and(not(False), or(False, True))
+                fn main() {
+                    let h: Integer = hour
+                    let r: Float = rate
+                    print_integer(h)
+                    print_float(r)
+                    print_string(greeting)
+                    print_bool(ready)
+                }"#,
+            )
+            .unwrap();
+        engine.run(&program).unwrap();
+        assert_eq!(output.take(), "119\n2.0\nhi bee\nTrue\n");
+        // Constants are folded into the code that uses them
+        let text = asm::disassemble(program.op_codes());
+        assert!(text.starts_with("store_int s0, 119\n"));
+        assert!(!text.contains("add"));
+    }
+
+    #[test]
+    fn invalid_constants_are_errors() {
+        assert_eq!(
+            error(&format!(
+                "const a: Integer = add(b, 1)\nconst b: Integer = a{}",
+                MAIN
+            )),
+            "1:7: Constant a depends on itself"
+        );
+        assert_eq!(
+            error(&format!("const a: Integer = a{}", MAIN)),
+            "1:7: Constant a depends on itself"
+        );
+        assert_eq!(
+            error(&format!("const a: Integer = sub(0, 1){}", MAIN)),
+            "1:7: Invalid constant: integer overflow"
+        );
+        assert_eq!(
+            error(&format!("const a: Float = add(1, 2){}", MAIN)),
+            "1:7: Type mismatch"
+        );
+        assert_eq!(
+            error(&format!("const a: Integer = add(1, \"2\"){}", MAIN)),
+            "1:7: Type mismatch"
+        );
+        assert_eq!(
+            error(&format!("const a: Integer = b{}", MAIN)),
+            "1:7: Constant not found: b"
+        );
+        assert_eq!(
+            error(&format!(
+                "const a: Integer = 1\nconst a: Integer = 2{}",
+                MAIN
+            )),
+            "2:7: Duplicate constant: a"
+        );
+        let mut engine = Engine::new();
+        engine.register_fn("now", &[], "Integer", |_| Ok(Value::Int(0)));
+        assert_eq!(
+            engine
+                .compile(&format!("const a: Integer = now(){}", MAIN))
+                .unwrap_err()
+                .to_string(),
+            "1:7: now can't be called in a constant"
+        );
+    }
+
+    #[test]
+    fn constants_of_unsupported_types_are_errors() {
+        // There are no lists, tuples or records yet
+        assert_eq!(
+            error(&format!("const a: List = 1{}", MAIN)),
+            "1:7: Constants of type List are not supported, only Integer, Float, String and Bool"
+        );
+        assert_eq!(
+            error(&format!("const a: Point = Point(1, 2){}", MAIN)),
+            "1:18: Records are not supported yet"
+        );
+        // The engine reports every error, not just the first
+        let errors = |source: &str| Engine::new().compile(source).unwrap_err().to_string();
+        assert_eq!(
+            errors(&format!("const a: List = [1, 2]{}", MAIN)),
+            "1:17: Lists are not supported yet\n1:22: Lists are not supported yet"
+        );
+        assert_eq!(
+            error(&format!("const a: Tuple = #(1, 2){}", MAIN)),
+            "1:18: Tuples are not supported yet"
+        );
+    }
+}
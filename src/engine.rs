@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::bytecode::Program;
+use crate::code_gen::HostSignature;
+use crate::compile_source_with_host_functions;
+use crate::diagnostic::{Diagnostic, Diagnostics};
+use crate::host::{self, HostFunction};
+use crate::lex::lex_with_spans;
+use crate::optimize::OptLevel;
+use crate::verify;
+use crate::vm::{self, ErrorKind, Output, RunOptions, RuntimeError, Value, Vm};
+
+// Entry point for Rust programs embedding bee:
+//
+//   let mut engine = Engine::new();
+//   engine.register_fn("double", &["Integer"], "Integer", |args| match args {
+//       [Value::Int(value)] => Ok(Value::Int(value * 2)),
+//       _ => unreachable!(),
+//   });
+//   let program = engine.compile(source)?;
+//   let value = engine.run(&program)?;
+//
+// Compiled programs don't depend on the engine so they can be reused.
+#[derive(Clone)]
+pub struct Engine {
+    options: RunOptions,
+    host_functions: HashMap<String, HostFunction>,
+    output: Output,
+    opt_level: OptLevel,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine {
+            options: RunOptions::default(),
+            host_functions: HashMap::new(),
+            output: vm::stdout(),
+            opt_level: OptLevel::default(),
+        }
+    }
+}
+
+impl fmt::Debug for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Engine")
+            .field("options", &self.options)
+            .field("host_functions", &self.host_functions)
+            .field("opt_level", &self.opt_level)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Engine::default()
+    }
+
+    // Limits every run of the engine, see RunOptions
+    pub fn with_options(options: RunOptions) -> Self {
+        Engine {
+            options,
+            ..Engine::default()
+        }
+    }
+
+    // Sends what programs print somewhere other than stdout:
+    //
+    //   let output = Rc::new(RefCell::new(Vec::new()));
+    //   engine.set_output(output.clone());
+    pub fn set_output(&mut self, output: Output) {
+        self.output = output;
+    }
+
+    // Programs are optimized at -O1 unless told otherwise
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.opt_level = opt_level;
+    }
+
+    // Makes a Rust function callable from bee. Calls are type checked
+    // against the parameter and return types, which are bee type names.
+    // Registering a name again replaces the earlier function. Functions
+    // declared with @external(rust, "math", "sqrt") bind to "math.sqrt".
+    pub fn register_fn(
+        &mut self,
+        name: &str,
+        params: &[&str],
+        returns: &str,
+        function: impl Fn(&[Value]) -> Result<Value, String> + 'static,
+    ) {
+        let signature = HostSignature {
+            name: name.to_owned(),
+            binding: name.to_owned(),
+            params: params.iter().map(|param| param.to_string()).collect(),
+            returns: returns.to_owned(),
+        };
+        self.host_functions.insert(
+            name.to_owned(),
+            HostFunction {
+                signature,
+                function: Rc::new(function),
+            },
+        );
+    }
+
+    pub fn compile(&self, source: &str) -> Result<Program, Diagnostics> {
+        // The parser stops at the first invalid token, so they are all
+        // collected from the lexer first
+        let (tokens, spans) = lex_with_spans(source);
+        let lex_errors: Vec<Diagnostic> = tokens
+            .iter()
+            .zip(&spans)
+            .filter_map(|(token, span)| Some(Diagnostic::new(token.error()?).with_span(*span)))
+            .collect();
+        if !lex_errors.is_empty() {
+            return Err(Diagnostics(lex_errors));
+        }
+        let signatures = self
+            .host_functions
+            .values()
+            .map(|host_function| host_function.signature.clone())
+            .collect();
+        compile_source_with_host_functions(source, signatures, self.opt_level)
+            .map_err(Diagnostics::from)
+    }
+
+    // The program is verified, and every host function it calls has to be
+    // registered, before anything runs
+    pub fn run(&self, program: &Program) -> Result<Value, RuntimeError> {
+        verify::verify(program).map_err(|error| {
+            let kind = ErrorKind::InvalidBytecode(error.to_string());
+            vm::runtime_error(program, kind, error.pc().unwrap_or(0))
+        })?;
+        host::link(program, &self.host_functions)?;
+        let mut vm = Vm::new();
+        vm.host_functions = self.host_functions.clone();
+        vm.output = self.output.clone();
+        vm.run(program, &self.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::optimize::OptLevel;
+    use crate::vm::RunOptions;
+    use crate::{asm, testing, verify, vm, Value};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn engine_compiles_and_runs_programs() {
+        use crate::{Engine, Value};
+        let mut engine = Engine::new();
+        engine.set_opt_level(OptLevel::O0);
+        let program = engine
+            .compile("fn main() {\n  let one: Integer = 1\n  let two: Integer = add(one, one)\n}")
+            .unwrap();
+        assert_eq!(engine.run(&program), Ok(Value::Int(0)));
+        assert_eq!(engine.run(&program), Ok(Value::Int(0)));
+
+        let limited = Engine::with_options(RunOptions {
+            fuel: Some(1),
+            deadline: None,
+        });
+        assert_eq!(
+            limited.run(&program).unwrap_err().kind,
+            crate::vm::ErrorKind::OutOfFuel
+        );
+    }
+
+    #[test]
+    fn engine_reports_every_compile_error() {
+        use crate::Engine;
+        let engine = Engine::new();
+        let errors = engine
+            .compile("fn main() {\n  let a: String = \"\\q\"\n  let b: Integer = 1x\n}")
+            .unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            "2:19: Invalid escape sequence in string: \\q\n3:20: Invalid number literal: 1x"
+        );
+        let errors = engine.compile("fn main() {\n  missing(1)\n}").unwrap_err();
+        assert_eq!(errors.to_string(), "2:3: Function not found: missing");
+    }
+
+    #[test]
+    fn engine_compiles_only_main() {
+        use crate::Engine;
+        let engine = Engine::new();
+        // main is found by name, and other functions can't be compiled yet
+        for source in [
+            "fn main() {\n}\nfn other() {\n}",
+            "fn other() {\n}\nfn main() {\n}",
+        ] {
+            let errors = engine.compile(source).unwrap_err();
+            assert!(errors
+                .to_string()
+                .ends_with(": Only a main function is supported for now, found: other"));
+        }
+        let errors = engine.compile("fn other() {\n}").unwrap_err();
+        assert_eq!(errors.to_string(), "Expected a main function");
+    }
+
+    // An engine with host functions registered, and what log was called with
+    fn host_engine() -> (crate::Engine, Rc<RefCell<Vec<Vec<Value>>>>) {
+        let logged = Rc::new(RefCell::new(Vec::new()));
+        let mut engine = crate::Engine::new();
+        engine.register_fn("http_status", &["Integer"], "String", |args| match args {
+            [Value::Int(404)] => Ok(Value::from("Not Found")),
+            [Value::Int(code)] => Err(format!("unknown status {}", code)),
+            _ => unreachable!(),
+        });
+        let log = logged.clone();
+        engine.register_fn("log", &["String", "Bool"], "Bool", move |args| {
+            log.borrow_mut().push(args.to_vec());
+            Ok(Value::from(true))
+        });
+        engine.register_fn("broken", &[], "Integer", |_| Ok(Value::from(1.5)));
+        (engine, logged)
+    }
+
+    const HOST_CALLS: &str = r#"
+        const code: Integer = 404
+        fn main() {
+            let status: String = http_status(code)
+            log(status, True)
+            let done: Bool = log("again", False)
+        }"#;
+
+    #[test]
+    fn host_functions_are_called() {
+        let (engine, logged) = host_engine();
+        let program = engine.compile(HOST_CALLS).unwrap();
+        assert!(
+            asm::disassemble(program.op_codes()).contains("call_host r1, \"http_status\", r1, 1\n")
+        );
+        assert_eq!(verify::verify(&program), Ok(()));
+        engine.run(&program).unwrap();
+        assert_eq!(
+            *logged.borrow(),
+            vec![
+                vec![Value::from("Not Found"), Value::Bool(true)],
+                vec![Value::from("again"), Value::Bool(false)],
+            ]
+        );
+    }
+
+    #[test]
+    fn host_calls_are_type_checked() {
+        let (engine, _) = host_engine();
+        let error = |source: &str| engine.compile(source).unwrap_err().to_string();
+        assert_eq!(
+            error("fn main() {\n  let s: Integer = http_status(404)\n}"),
+            "2:3: Type mismatch"
+        );
+        assert_eq!(
+            error("fn main() {\n  log(\"x\")\n}"),
+            "2:3: log takes 2 arguments but was given 1"
+        );
+        assert_eq!(
+            error("fn main() {\n  log(1, True)\n}"),
+            "2:3: Type mismatch"
+        );
+        assert_eq!(
+            crate::Engine::new()
+                .compile("fn main() {\n  log(\"x\", True)\n}")
+                .unwrap_err()
+                .to_string(),
+            "2:3: Function not found: log"
+        );
+    }
+
+    #[test]
+    fn host_errors_are_runtime_errors() {
+        let (engine, _) = host_engine();
+        let failing = engine
+            .compile("fn main() {\n  let s: String = http_status(500)\n}")
+            .unwrap();
+        assert_eq!(
+            engine.run(&failing).unwrap_err().to_string(),
+            "2:3: host error: unknown status 500 (in main at pc 1)"
+        );
+        let broken = engine
+            .compile("fn main() {\n  let n: Integer = broken()\n}")
+            .unwrap();
+        assert_eq!(
+            engine.run(&broken).unwrap_err().kind,
+            vm::ErrorKind::HostError(
+                "broken returned Float but is declared to return Integer".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn unregistered_host_functions_stop_programs_before_they_run() {
+        let (engine, logged) = host_engine();
+        let program = engine.compile(HOST_CALLS).unwrap();
+        let unlinked = crate::Engine::new().run(&program).unwrap_err();
+        assert_eq!(
+            unlinked.kind,
+            vm::ErrorKind::HostFunctionNotFound("http_status".to_owned())
+        );
+        assert_eq!(unlinked.pc, 1);
+        assert!(logged.borrow().is_empty());
+    }
+
+    #[test]
+    fn external_functions_link_to_host_functions() {
+        use crate::Engine;
+        use std::cell::Cell;
+
+        let source = "@external(rust, \"math\", \"sqrt\")\nfn sqrt(x: Float) -> Float\n\nfn main() {\n  let root: Float = sqrt(2.25)\n}\n";
+        let mut engine = Engine::new();
+        let program = engine.compile(source).unwrap();
+        assert_eq!(program.host_functions.len(), 1);
+        assert_eq!(program.host_functions[0].to_string(), "fn(Float) -> Float");
+        // Calls go to the binding rather than the name used in bee
+        assert!(
+            asm::disassemble(program.op_codes()).contains("call_host r1, \"math.sqrt\", r1, 1\n")
+        );
+
+        // Nothing runs until every binding is registered
+        let unlinked = engine.run(&program).unwrap_err();
+        assert_eq!(
+            unlinked.to_string(),
+            "5:3: host function math.sqrt is not registered (in main at pc 1)"
+        );
+        engine.register_fn("math.sqrt", &["Integer"], "Float", |_| unreachable!());
+        assert_eq!(
+            engine.run(&program).unwrap_err().kind,
+            vm::ErrorKind::HostSignatureMismatch(
+                "math.sqrt is declared as fn(Float) -> Float but registered as fn(Integer) -> Float"
+                    .to_owned()
+            )
+        );
+        let root = Rc::new(Cell::new(0.0));
+        let result = root.clone();
+        engine.register_fn("math.sqrt", &["Float"], "Float", move |args| match args {
+            [Value::Float(x)] => {
+                result.set(x.sqrt());
+                Ok(Value::Float(x.sqrt()))
+            }
+            _ => unreachable!(),
+        });
+        assert!(engine.run(&program).is_ok());
+        assert_eq!(root.get(), 1.5);
+    }
+
+    #[test]
+    fn engine_refuses_programs_that_fail_verification() {
+        // s0 is loaded before anything is stored in it
+        let program = asm::assemble_program("load_int r1, 1\nprint r1\nload r0, s0\nhalt").unwrap();
+        let (engine, output) = testing::engine(OptLevel::O1);
+        let error = engine.run(&program).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid bytecode: variable slot s0 is loaded at pc 2 before it is stored (in main at pc 2)"
+        );
+        // Nothing ran
+        assert_eq!(output.take(), "");
+    }
+
+    #[test]
+    fn print_writes_to_the_output() {
+        let (engine, output) = testing::engine(OptLevel::O1);
+        let program = engine
+            .compile(
+                r#"
+                fn main() {
+                    let n: Integer = 5
+                    let f: Float = 2.0
+                    let s: String = concat("h", "i")
+                    print_integer(n)
+                    print_float(f)
+                    print_float(0.25)
+                    print_string(s)
+                    print_string("a\tb")
+                }"#,
+            )
+            .unwrap();
+        engine.run(&program).unwrap();
+        assert_eq!(output.take(), "5\n2.0\n0.25\nhi\na\tb\n");
+    }
+
+    #[test]
+    fn output_errors_are_runtime_errors() {
+        use std::io::{self, Write};
+
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut engine = crate::Engine::new();
+        engine.set_output(Rc::new(RefCell::new(Closed)));
+        let program = engine
+            .compile("fn main() {\n  let n: Integer = 5\n  print_integer(n)\n}")
+            .unwrap();
+        let error = engine.run(&program).unwrap_err();
+        assert_eq!(error.kind, vm::ErrorKind::OutputError("closed".to_owned()));
+        assert_eq!(error.span.map(|span| span.start.line), Some(2));
+    }
+}
@@ -0,0 +1,323 @@
+use crate::ast::{AbstractSyntaxTree, Constant, Document, External, Function};
+use crate::diagnostic::Diagnostic;
+use crate::lex::{lex, lex_with_spans};
+use crate::parse::parse_with_spans;
+use crate::span::Position;
+use crate::token::Token;
+
+const INDENT: usize = 2;
+const LINE_WIDTH: usize = 80;
+
+pub fn format(source: &str) -> Result<String, Diagnostic> {
+    let (tokens, spans) = lex_with_spans(source);
+    let comment_count = count_comments(&tokens);
+    let document = parse_with_spans(tokens, &spans)?;
+    let formatted = format_document(&document);
+
+    // Comments are only kept where a definition or statement can start, so
+    // refuse to format rather than silently drop one from inside an expression
+    if count_comments(&lex(formatted.clone())) != comment_count {
+        return Err(Diagnostic::new(
+            "Comments inside expressions are not supported by the formatter",
+        ));
+    }
+    Ok(formatted)
+}
+
+fn count_comments(tokens: &[Token]) -> usize {
+    tokens
+        .iter()
+        .filter(|token| matches!(token, Token::Comment { .. }))
+        .count()
+}
+
+// Definitions are written in the order of their spans, so they stay where
+// they were in the source. Constants next to each other aren't separated by
+// a blank line.
+pub fn format_document(document: &Document) -> String {
+    let mut definitions: Vec<(Position, bool, String)> = Vec::new();
+    for constant in &document.constants {
+        definitions.push((constant.span.start, true, format_constant(constant)));
+    }
+    for external in &document.externals {
+        definitions.push((external.span.start, false, format_external(external)));
+    }
+    for function in &document.functions {
+        definitions.push((function.span.start, false, format_function(function)));
+    }
+    definitions.sort_by_key(|(start, ..)| *start);
+
+    let mut output = String::new();
+    let mut after_constant = false;
+    for (_, is_constant, definition) in definitions {
+        if !output.is_empty() && (!is_constant || !after_constant) {
+            output.push('\n');
+        }
+        output.push_str(&definition);
+        after_constant = is_constant;
+    }
+    if !document.comments.is_empty() {
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        output.push_str(&format_comments(&document.comments, 0));
+    }
+    output
+}
+
+fn format_comments(comments: &[String], indent: usize) -> String {
+    comments
+        .iter()
+        .map(|comment| format!("{}//{}\n", " ".repeat(indent), comment))
+        .collect()
+}
+
+// Trailing comments go at the end of the last line written
+fn push_trailing_comment(output: &mut String, comment: Option<&str>) {
+    if let Some(comment) = comment {
+        if output.ends_with('\n') {
+            output.pop();
+        }
+        output.push_str(&format!(" //{}\n", comment));
+    }
+}
+
+fn format_constant(constant: &Constant) -> String {
+    let head = format!("const {}: {} = ", constant.name, constant.type_annot);
+    let mut output = format!(
+        "{}{}{}\n",
+        format_comments(&constant.comments, 0),
+        head,
+        format_expression(&constant.value, 0, head.len())
+    );
+    push_trailing_comment(&mut output, constant.trailing_comment.as_deref());
+    output
+}
+
+fn format_external(external: &External) -> String {
+    let params: Vec<String> = external
+        .params
+        .iter()
+        .map(|(name, type_annot)| format!("{}: {}", name, type_annot))
+        .collect();
+    let mut output = format!(
+        "{}@external({}, {}, {})\nfn {}({}) -> {}\n",
+        format_comments(&external.comments, 0),
+        external.target,
+        format_string(&external.module),
+        format_string(&external.function),
+        external.name,
+        params.join(", "),
+        external.returns
+    );
+    push_trailing_comment(&mut output, external.trailing_comment.as_deref());
+    output
+}
+
+fn format_function(function: &Function) -> String {
+    let mut output = format_comments(&function.comments, 0);
+    output.push_str(&format!(
+        "fn {}({}) {{\n",
+        function.name,
+        function.args.join(", ")
+    ));
+    for statement in &function.body {
+        push_statement(&mut output, statement, INDENT);
+    }
+    output.push_str("}\n");
+    push_trailing_comment(&mut output, function.trailing_comment.as_deref());
+    output
+}
+
+fn push_statement(output: &mut String, statement: &AbstractSyntaxTree, indent: usize) {
+    match statement {
+        AbstractSyntaxTree::Comment {
+            value,
+            trailing: true,
+        } => push_trailing_comment(output, Some(value)),
+        _ => output.push_str(&format_statement(statement, indent)),
+    }
+}
+
+fn format_statement(statement: &AbstractSyntaxTree, indent: usize) -> String {
+    let padding = " ".repeat(indent);
+    match statement {
+        AbstractSyntaxTree::Comment { value, .. } => format!("{}//{}\n", padding, value),
+        AbstractSyntaxTree::Let {
+            name,
+            type_annot,
+            value,
+            ..
+        } => {
+            let head = format!("let {}: {} = ", name, type_annot);
+            format!(
+                "{}{}{}\n",
+                padding,
+                head,
+                format_expression(value, indent, indent + head.len())
+            )
+        }
+        expression => format!(
+            "{}{}\n",
+            padding,
+            format_expression(expression, indent, indent)
+        ),
+    }
+}
+
+// Formats an expression starting at `column`. Calls that don't fit on the
+// line put each argument on its own line, one level deeper than `indent`.
+fn format_expression(expression: &AbstractSyntaxTree, indent: usize, column: usize) -> String {
+    let flat = format_flat(expression);
+    match expression {
+        AbstractSyntaxTree::Call { name, args, .. }
+            if !args.is_empty() && column + flat.len() > LINE_WIDTH =>
+        {
+            let arg_indent = indent + INDENT;
+            let args: Vec<String> = args
+                .iter()
+                .map(|arg| {
+                    format!(
+                        "{}{}",
+                        " ".repeat(arg_indent),
+                        format_expression(arg, arg_indent, arg_indent)
+                    )
+                })
+                .collect();
+            format!("{}(\n{}\n{})", name, args.join(",\n"), " ".repeat(indent))
+        }
+        AbstractSyntaxTree::Block { statements } => {
+            let mut output = String::from("{\n");
+            for statement in statements {
+                push_statement(&mut output, statement, indent + INDENT);
+            }
+            output.push_str(&" ".repeat(indent));
+            output.push('}');
+            output
+        }
+        _ => flat,
+    }
+}
+
+fn format_flat(expression: &AbstractSyntaxTree) -> String {
+    match expression {
+        AbstractSyntaxTree::Int { literal, .. } => literal.clone(),
+        AbstractSyntaxTree::Float { literal, .. } => literal.clone(),
+        AbstractSyntaxTree::String { value } => format_string(value),
+        AbstractSyntaxTree::Name { name } => name.clone(),
+        AbstractSyntaxTree::UpName { name } => name.clone(),
+        AbstractSyntaxTree::Call { name, args, .. } => {
+            let args: Vec<String> = args.iter().map(format_flat).collect();
+            format!("{}({})", name, args.join(", "))
+        }
+        AbstractSyntaxTree::Let { .. }
+        | AbstractSyntaxTree::Block { .. }
+        | AbstractSyntaxTree::Comment { .. } => format_statement(expression, 0),
+    }
+}
+
+// Newlines are written as-is since strings may span multiple lines
+fn format_string(value: &str) -> String {
+    let mut output = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\t' => output.push_str("\\t"),
+            '\r' => output.push_str("\\r"),
+            '\n' => output.push('\n'),
+            c if c.is_control() => output.push_str(&format!("\\u{{{:X}}}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format;
+
+    #[test]
+    fn format_document_canonically() {
+        let source = r#"
+// The answer
+const answer:Integer=0x2A   const greeting: String = "say \"hi\""
+fn main(  ) {
+        // Printing
+    let sum: Integer = add(answer,1_000)
+  print_integer(sum)
+    let long_name_for_a_sum: Integer = add(a_rather_long_variable_name, another_long_variable_name)
+}
+// The end"#;
+        let expected = r#"// The answer
+const answer: Integer = 0x2A
+const greeting: String = "say \"hi\""
+
+fn main() {
+  // Printing
+  let sum: Integer = add(answer, 1_000)
+  print_integer(sum)
+  let long_name_for_a_sum: Integer = add(
+    a_rather_long_variable_name,
+    another_long_variable_name
+  )
+}
+
+// The end
+"#;
+        let formatted = format::format(source).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format::format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn format_keeps_definitions_in_source_order() {
+        let source = "fn main() {\n}\nconst later: Integer = 1\n@external(rust, \"math\", \"double\")\nfn double(x: Integer) -> Integer\nconst last: Integer = 2\n";
+        assert_eq!(
+            format::format(source).unwrap(),
+            "fn main() {\n}\n\nconst later: Integer = 1\n\n@external(rust, \"math\", \"double\")\nfn double(x: Integer) -> Integer\n\nconst last: Integer = 2\n"
+        );
+    }
+
+    #[test]
+    fn format_keeps_trailing_comments() {
+        let source = r#"const answer: Integer = 42   // the answer
+fn main() {
+  print_integer(answer)   // trailing
+} // main
+@external(rust, "math", "double")
+fn double(x: Integer) -> Integer // host
+"#;
+        let expected = r#"const answer: Integer = 42 // the answer
+
+fn main() {
+  print_integer(answer) // trailing
+} // main
+
+@external(rust, "math", "double")
+fn double(x: Integer) -> Integer // host
+"#;
+        let formatted = format::format(source).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format::format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn format_refuses_to_drop_comments() {
+        let source = "fn main() {\n  print_integer(\n    // one\n    1\n  )\n}\n";
+        assert!(format::format(source).is_err());
+    }
+
+    #[test]
+    fn format_external_declarations() {
+        let source = r#"// Provided by the host
+@external(rust,"math",  "sqrt")
+fn sqrt(x:Float,  y: Float)->Float
+"#;
+        assert_eq!(
+            format::format(source).unwrap(),
+            "// Provided by the host\n@external(rust, \"math\", \"sqrt\")\nfn sqrt(x: Float, y: Float) -> Float\n"
+        );
+    }
+}
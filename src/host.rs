@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::bytecode::Program;
+use crate::code_gen::HostSignature;
+use crate::opcode::OpCode;
+use crate::vm::{ErrorKind, RuntimeError, Value};
+
+// Host functions get their arguments already checked against the signature.
+// An Err becomes a runtime error in the calling program.
+pub type HostFn = dyn Fn(&[Value]) -> Result<Value, String>;
+
+#[derive(Clone)]
+pub struct HostFunction {
+    pub signature: HostSignature,
+    pub function: Rc<HostFn>,
+}
+
+impl HostFunction {
+    // Whether a value returned by the function matches its signature
+    pub fn returns(&self, value: &Value) -> bool {
+        self.signature.returns == value.type_name()
+    }
+}
+
+impl fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HostFunction({:?})", self.signature)
+    }
+}
+
+// Checks that every host function the program calls is registered under
+// the signature it was compiled against, so nothing runs when one is missing
+pub fn link(
+    program: &Program,
+    host_functions: &HashMap<String, HostFunction>,
+) -> Result<(), RuntimeError> {
+    for (pc, op_code) in program.op_codes().iter().enumerate() {
+        let OpCode::CallHost { name, .. } = op_code else {
+            continue;
+        };
+        let declared = program
+            .host_functions
+            .iter()
+            .find(|signature| *signature.binding == **name);
+        let kind = match (host_functions.get(&**name), declared) {
+            (None, _) => ErrorKind::HostFunctionNotFound(name.to_string()),
+            (Some(registered), Some(declared))
+                if registered.signature.params != declared.params
+                    || registered.signature.returns != declared.returns =>
+            {
+                ErrorKind::HostSignatureMismatch(format!(
+                    "{} is declared as {} but registered as {}",
+                    name, declared, registered.signature
+                ))
+            }
+            _ => continue,
+        };
+        return Err(RuntimeError {
+            kind,
+            pc,
+            function: program
+                .function_at(pc)
+                .map_or_else(String::new, |function| function.name.clone()),
+            span: program.span_at(pc),
+        });
+    }
+    Ok(())
+}
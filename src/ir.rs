@@ -0,0 +1,265 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::span::Span;
+use crate::vm::Value;
+
+// Mid-level representation between the syntax tree and op codes. A
+// function is a list of basic blocks, each a run of instructions ending in
+// a terminator that says where control goes next. Values are held in
+// temporaries, which have a type and may be assigned on more than one path
+// into a block. Variables are the VM's slots.
+//
+// The textual form is for reading, not parsing:
+//
+//   fn main {
+//   b0:
+//     t0: Integer = load s0
+//     t1: Integer = const 4
+//     t2: Integer = add t0, t1
+//     store s1, t2
+//     halt
+//   }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Temp(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockId(pub usize);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    // The bee type of each temporary, indexed by its number
+    pub temps: Vec<String>,
+    // In the order they are laid out, starting with the entry block
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub instructions: Vec<Instruction>,
+    // The index of the first instruction of each statement that starts in
+    // the block, and the statement's span
+    pub lines: Vec<(usize, Span)>,
+    pub terminator: Terminator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Concat,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Const {
+        dest: Temp,
+        value: Value,
+    },
+    Load {
+        dest: Temp,
+        variable: usize,
+    },
+    Store {
+        variable: usize,
+        value: Temp,
+    },
+    Binary {
+        op: BinaryOp,
+        dest: Temp,
+        lhs: Temp,
+        rhs: Temp,
+    },
+    Not {
+        dest: Temp,
+        value: Temp,
+    },
+    // Calls the host function registered under binding
+    CallHost {
+        dest: Temp,
+        binding: Box<str>,
+        args: Vec<Temp>,
+    },
+    Print {
+        value: Temp,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    // Goes to if_true or if_false depending on the Bool in condition
+    Branch {
+        condition: Temp,
+        if_true: BlockId,
+        if_false: BlockId,
+    },
+    Halt,
+}
+
+impl Function {
+    // The temporaries whose values are needed on entry to each block
+    pub fn live_in(&self) -> Vec<HashSet<Temp>> {
+        let mut live_in = vec![HashSet::new(); self.blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for index in (0..self.blocks.len()).rev() {
+                let mut live = self.live_out(index, &live_in);
+                for instruction in self.blocks[index].instructions.iter().rev() {
+                    live = before(instruction, live);
+                }
+                if live != live_in[index] {
+                    live_in[index] = live;
+                    changed = true;
+                }
+            }
+        }
+        live_in
+    }
+
+    // The temporaries still needed after each instruction of a block
+    pub fn live_after(&self, index: usize, live_in: &[HashSet<Temp>]) -> Vec<HashSet<Temp>> {
+        let instructions = &self.blocks[index].instructions;
+        let mut live = self.live_out(index, live_in);
+        let mut live_after = vec![HashSet::new(); instructions.len()];
+        for (position, instruction) in instructions.iter().enumerate().rev() {
+            live_after[position] = live.clone();
+            live = before(instruction, live);
+        }
+        live_after
+    }
+
+    fn live_out(&self, index: usize, live_in: &[HashSet<Temp>]) -> HashSet<Temp> {
+        let terminator = &self.blocks[index].terminator;
+        let mut live: HashSet<Temp> = terminator
+            .successors()
+            .iter()
+            .flat_map(|successor| live_in[successor.0].iter().copied())
+            .collect();
+        live.extend(terminator.uses());
+        live
+    }
+}
+
+fn before(instruction: &Instruction, mut live: HashSet<Temp>) -> HashSet<Temp> {
+    if let Some(dest) = instruction.dest() {
+        live.remove(&dest);
+    }
+    live.extend(instruction.uses());
+    live
+}
+
+impl Instruction {
+    pub fn dest(&self) -> Option<Temp> {
+        match self {
+            Instruction::Const { dest, .. }
+            | Instruction::Load { dest, .. }
+            | Instruction::Binary { dest, .. }
+            | Instruction::Not { dest, .. }
+            | Instruction::CallHost { dest, .. } => Some(*dest),
+            Instruction::Store { .. } | Instruction::Print { .. } => None,
+        }
+    }
+
+    // The temporaries read, in the order they are read
+    pub fn uses(&self) -> Vec<Temp> {
+        match self {
+            Instruction::Const { .. } | Instruction::Load { .. } => Vec::new(),
+            Instruction::Store { value, .. }
+            | Instruction::Not { value, .. }
+            | Instruction::Print { value } => vec![*value],
+            Instruction::Binary { lhs, rhs, .. } => vec![*lhs, *rhs],
+            Instruction::CallHost { args, .. } => args.clone(),
+        }
+    }
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch {
+                if_true, if_false, ..
+            } => vec![*if_true, *if_false],
+            Terminator::Halt => Vec::new(),
+        }
+    }
+
+    pub fn uses(&self) -> Vec<Temp> {
+        match self {
+            Terminator::Branch { condition, .. } => vec![*condition],
+            Terminator::Jump(_) | Terminator::Halt => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Temp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "t{}", self.0)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "b{}", self.0)
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "fn {} {{", self.name)?;
+        for (index, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "{}:", BlockId(index))?;
+            for instruction in &block.instructions {
+                match instruction.dest() {
+                    Some(dest) => write!(f, "  {}: {} = ", dest, self.temps[dest.0])?,
+                    None => write!(f, "  ")?,
+                }
+                writeln!(f, "{}", Operation(instruction))?;
+            }
+            match &block.terminator {
+                Terminator::Jump(target) => writeln!(f, "  jump {}", target)?,
+                Terminator::Branch {
+                    condition,
+                    if_true,
+                    if_false,
+                } => writeln!(f, "  branch {}, {}, {}", condition, if_true, if_false)?,
+                Terminator::Halt => writeln!(f, "  halt")?,
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+// An instruction without its destination
+struct Operation<'a>(&'a Instruction);
+
+impl fmt::Display for Operation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Instruction::Const {
+                value: Value::String(value),
+                ..
+            } => write!(f, "const {:?}", value),
+            Instruction::Const { value, .. } => write!(f, "const {}", value),
+            Instruction::Load { variable, .. } => write!(f, "load s{}", variable),
+            Instruction::Store { variable, value } => write!(f, "store s{}, {}", variable, value),
+            Instruction::Binary { op, lhs, rhs, .. } => {
+                let name = match op {
+                    BinaryOp::Add => "add",
+                    BinaryOp::Sub => "sub",
+                    BinaryOp::Concat => "concat",
+                };
+                write!(f, "{} {}, {}", name, lhs, rhs)
+            }
+            Instruction::Not { value, .. } => write!(f, "not {}", value),
+            Instruction::CallHost { binding, args, .. } => {
+                let args: Vec<String> = args.iter().map(Temp::to_string).collect();
+                write!(f, "call {:?}({})", binding, args.join(", "))
+            }
+            Instruction::Print { value } => write!(f, "print {}", value),
+        }
+    }
+}
@@ -1,10 +1,52 @@
+use crate::span::{Position, Span};
 use crate::token::Token;
 
+// The characters of the source along with the position of the next one
+struct Source<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    position: Position,
+}
+
+impl Source<'_> {
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+}
+
+impl Iterator for Source<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.position.line += 1;
+            self.position.column = 0;
+        } else {
+            self.position.column += c.len_utf16();
+        }
+        Some(c)
+    }
+}
+
 pub fn lex(source: String) -> Vec<Token> {
+    lex_with_spans(&source).0
+}
+
+// Returns the span of each token alongside the tokens
+pub fn lex_with_spans(source: &str) -> (Vec<Token>, Vec<Span>) {
     let mut tokens = Vec::new();
-    let mut source = source.chars().peekable();
+    let mut spans = Vec::new();
+    let mut source = Source {
+        chars: source.chars().peekable(),
+        position: Position::default(),
+    };
 
-    while let Some(c) = source.next() {
+    loop {
+        // Each iteration lexes at most one token
+        let start = source.position;
+        let Some(c) = source.next() else {
+            break;
+        };
         match c {
             // Skip whitespace
             ' ' | '\t' | '\r' | '\n' => continue,
@@ -16,7 +58,31 @@ pub fn lex(source: String) -> Vec<Token> {
             ',' => tokens.push(Token::Comma),
             // Other Punctuation
             ':' => tokens.push(Token::Colon),
+            // Comments run to the end of the line and are kept for the formatter
+            '/' if source.peek() == Some(&'/') => {
+                source.next();
+                let mut value = String::new();
+                while let Some(&c) = source.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    value.push(c);
+                    source.next();
+                }
+                let trailing = spans
+                    .last()
+                    .is_some_and(|span: &Span| span.end.line == start.line);
+                tokens.push(Token::Comment {
+                    value: value.trim_end().to_owned(),
+                    trailing,
+                });
+            }
             '=' => tokens.push(Token::Equal),
+            '@' => tokens.push(Token::At),
+            '-' if source.peek() == Some(&'>') => {
+                source.next();
+                tokens.push(Token::RightArrow);
+            }
             // Keywords
             'a'..='z' | 'A'..='Z' => {
                 let mut name = String::new();
@@ -50,58 +116,262 @@ pub fn lex(source: String) -> Vec<Token> {
                 }
             }
             // Int or Float
-            '0'..='9' => {
-                let mut value = String::new();
-                value.push(c);
-                while let Some(&c) = source.peek() {
-                    match c {
-                        '0'..='9' => {
-                            value.push(c);
-                            source.next();
-                        }
-                        '.' => {
-                            value.push(c);
-                            source.next();
-                            while let Some(&c) = source.peek() {
-                                match c {
-                                    '0'..='9' => {
-                                        value.push(c);
-                                        source.next();
-                                    }
-                                    _ => break,
-                                }
-                            }
-                            tokens.push(Token::Float { value });
-                            break;
-                        }
-                        _ => {
-                            tokens.push(Token::Int { value });
-                            break;
-                        }
-                    }
-                }
-            }
+            '0'..='9' => tokens.push(lex_number(c, &mut source)),
             // String
             '"' => {
                 let mut value = String::new();
+                let mut terminated = false;
+                let mut invalid_escape = None;
                 while let Some(c) = source.next() {
                     match c {
                         '"' => {
-                            tokens.push(Token::String { value });
-                            break;
-                        }
-                        '\n' => {
-                            tokens.push(Token::UnterminatedString(value));
+                            terminated = true;
                             break;
                         }
+                        '\\' => match lex_escape(&mut source) {
+                            Ok(escaped) => value.push(escaped),
+                            // Keep consuming up to the closing quote so the rest of
+                            // the string isn't lexed as code
+                            Err(escape) => {
+                                if invalid_escape.is_none() {
+                                    invalid_escape = Some(escape);
+                                }
+                            }
+                        },
+                        // Strings may span multiple lines, newlines are kept as-is
                         _ => value.push(c),
                     }
                 }
+                if !terminated {
+                    tokens.push(Token::UnterminatedString(value));
+                } else if let Some(escape) = invalid_escape {
+                    tokens.push(Token::InvalidEscape(escape));
+                } else {
+                    tokens.push(Token::String { value });
+                }
             }
 
             // Invalid
             _ => tokens.push(Token::UnexpectedGrapheme(c.to_string())),
         }
+        if spans.len() < tokens.len() {
+            spans.push(Span {
+                start,
+                end: source.position,
+            });
+        }
+    }
+    (tokens, spans)
+}
+
+// Called after a backslash inside a string literal. On failure the error holds
+// the offending escape sequence as written in the source.
+fn lex_escape(source: &mut Source) -> Result<char, String> {
+    match source.next() {
+        Some('n') => Ok('\n'),
+        Some('t') => Ok('\t'),
+        Some('r') => Ok('\r'),
+        Some('\\') => Ok('\\'),
+        Some('"') => Ok('"'),
+        Some('u') => {
+            let mut escape = String::from("\\u");
+            if source.peek() != Some(&'{') {
+                return Err(escape);
+            }
+            source.next();
+            escape.push('{');
+            let mut digits = String::new();
+            while let Some(&c) = source.peek() {
+                match c {
+                    '0'..='9' | 'a'..='f' | 'A'..='F' => {
+                        digits.push(c);
+                        escape.push(c);
+                        source.next();
+                    }
+                    _ => break,
+                }
+            }
+            if source.peek() != Some(&'}') {
+                return Err(escape);
+            }
+            source.next();
+            escape.push('}');
+            if digits.is_empty() || digits.len() > 6 {
+                return Err(escape);
+            }
+            u32::from_str_radix(&digits, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or(escape)
+        }
+        Some(c) => Err(format!("\\{}", c)),
+        None => Err("\\".to_owned()),
+    }
+}
+
+// Numbers are 0x, 0o and 0b prefixed integers or decimals with an optional
+// fraction and exponent. Underscores may separate digits, the token keeps the
+// literal as written. Anything alphanumeric directly following the literal
+// makes the whole literal invalid rather than starting a new token.
+fn lex_number(first: char, source: &mut Source) -> Token {
+    let mut value = String::from(first);
+    let mut valid = true;
+
+    let radix = match (first, source.peek()) {
+        ('0', Some('x' | 'X')) => Some(16),
+        ('0', Some('o' | 'O')) => Some(8),
+        ('0', Some('b' | 'B')) => Some(2),
+        _ => None,
+    };
+    if let Some(radix) = radix {
+        value.push(source.next().unwrap());
+        let mut any_digits = false;
+        while let Some(&c @ ('a'..='z' | 'A'..='Z' | '0'..='9' | '_')) = source.peek() {
+            if c != '_' {
+                valid &= c.is_digit(radix);
+                any_digits = true;
+            }
+            value.push(c);
+            source.next();
+        }
+        return if valid && any_digits {
+            Token::Int { value }
+        } else {
+            Token::InvalidNumber(value)
+        };
+    }
+
+    let mut is_float = false;
+    lex_digits(source, &mut value);
+    if source.peek() == Some(&'.') {
+        is_float = true;
+        value.push('.');
+        source.next();
+        lex_digits(source, &mut value);
+    }
+    if let Some(&e @ ('e' | 'E')) = source.peek() {
+        is_float = true;
+        value.push(e);
+        source.next();
+        if let Some(&sign @ ('+' | '-')) = source.peek() {
+            value.push(sign);
+            source.next();
+        }
+        valid &= lex_digits(source, &mut value);
+    }
+    while let Some(&c @ ('a'..='z' | 'A'..='Z' | '0'..='9' | '_')) = source.peek() {
+        valid = false;
+        value.push(c);
+        source.next();
+    }
+
+    if !valid {
+        Token::InvalidNumber(value)
+    } else if is_float {
+        Token::Float { value }
+    } else {
+        Token::Int { value }
+    }
+}
+
+// Consumes decimal digits and underscores, returning whether any digit was seen
+fn lex_digits(source: &mut Source, value: &mut String) -> bool {
+    let mut any_digits = false;
+    while let Some(&c @ ('0'..='9' | '_')) = source.peek() {
+        any_digits |= c != '_';
+        value.push(c);
+        source.next();
+    }
+    any_digits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token;
+
+    #[test]
+    fn string_escape_sequences() {
+        let tokens = lex(r#""a\n\t\\\"b\u{1F600}""#.to_string());
+        assert_eq!(
+            tokens,
+            vec![token::Token::String {
+                value: "a\n\t\\\"b\u{1F600}".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    fn string_invalid_escapes() {
+        for (source, escape) in [
+            (r#""\q""#, r"\q"),
+            (r#""\u1F600""#, r"\u"),
+            (r#""\u{}""#, r"\u{}"),
+            (r#""\u{D800}""#, r"\u{D800}"),
+            (r#""\u{1F600""#, r"\u{1F600"),
+        ] {
+            assert_eq!(
+                lex(source.to_string()),
+                vec![token::Token::InvalidEscape(escape.to_owned())]
+            );
+        }
+    }
+
+    #[test]
+    fn string_multi_line_and_unterminated() {
+        let tokens = lex("\"one\ntwo\" \"three".to_string());
+        assert_eq!(
+            tokens,
+            vec![
+                token::Token::String {
+                    value: "one\ntwo".to_owned()
+                },
+                token::Token::UnterminatedString("three".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn number_literal_forms() {
+        let tokens = lex("0xFF 0o17 0b1010 1_000_000 1.5e-3 2E10 5. 7".to_string());
+        assert_eq!(
+            tokens,
+            vec![
+                token::Token::Int {
+                    value: "0xFF".to_owned()
+                },
+                token::Token::Int {
+                    value: "0o17".to_owned()
+                },
+                token::Token::Int {
+                    value: "0b1010".to_owned()
+                },
+                token::Token::Int {
+                    value: "1_000_000".to_owned()
+                },
+                token::Token::Float {
+                    value: "1.5e-3".to_owned()
+                },
+                token::Token::Float {
+                    value: "2E10".to_owned()
+                },
+                token::Token::Float {
+                    value: "5.".to_owned()
+                },
+                token::Token::Int {
+                    value: "7".to_owned()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_number_literals() {
+        for source in ["0x", "0b102", "0o8", "12abc", "1e", "1.5e+"] {
+            assert_eq!(
+                lex(source.to_string()),
+                vec![token::Token::InvalidNumber(source.to_owned())]
+            );
+        }
     }
-    tokens
 }
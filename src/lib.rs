@@ -0,0 +1,357 @@
+pub mod asm;
+pub mod ast;
+pub mod bench;
+pub mod bytecode;
+pub mod code;
+pub mod code_gen;
+pub mod constant;
+pub mod diagnostic;
+pub mod engine;
+pub mod format;
+pub mod host;
+pub mod ir;
+pub mod json;
+pub mod lex;
+pub mod lower;
+pub mod lsp;
+pub mod opcode;
+pub mod optimize;
+pub mod parse;
+pub mod peephole;
+pub mod repl;
+pub mod span;
+#[cfg(test)]
+mod testing;
+pub mod token;
+pub mod verify;
+pub mod vm;
+
+use ast::{AbstractSyntaxTree, Document};
+use bytecode::FunctionEntry;
+use code_gen::{
+    code_gen_function, main_function, Function, HostSignature, SymbolTable, Variable, MAX_HOST_ARGS,
+};
+use lex::lex_with_spans;
+use lower::lower_function;
+use opcode::OpCode;
+use optimize::optimize_function;
+use parse::parse_with_spans;
+
+pub use bytecode::Program;
+pub use diagnostic::{Diagnostic, Diagnostics};
+pub use engine::Engine;
+pub use optimize::OptLevel;
+pub use vm::{ConversionError, RunOptions, RuntimeError, Value};
+
+// Compiles a whole source file down to a program for the VM
+pub fn compile_source(source: &str) -> Result<Program, Diagnostic> {
+    compile_source_with_host_functions(source, Vec::new(), OptLevel::default())
+}
+
+// Calls to the given host functions are type checked against their
+// signatures and left for the VM to dispatch
+pub fn compile_source_with_host_functions(
+    source: &str,
+    host_functions: Vec<HostSignature>,
+    opt_level: OptLevel,
+) -> Result<Program, Diagnostic> {
+    let (tokens, spans) = lex_with_spans(source);
+    let document = parse_with_spans(tokens, &spans)?;
+    let mut symbol_table = SymbolTable {
+        functions: Vec::new(),
+        constants: Vec::new(),
+        host_functions,
+    };
+    analyze_document(document.clone(), &mut symbol_table)?;
+    let main_function = main_function(&document)?;
+    let mut function = lower_function(&main_function.name, &main_function.body, &symbol_table)?;
+    if opt_level == OptLevel::O1 {
+        optimize_function(&mut function);
+    }
+    let mut op_codes = Vec::new();
+    let mut line_table = Vec::new();
+    code_gen_function(&function, &mut symbol_table, &mut op_codes, &mut line_table)?;
+    // Counted after code generation, which adds slots for spilled registers
+    let variable_count = symbol_table
+        .functions
+        .iter()
+        .find(|function| function.name == main_function.name)
+        .map_or(0, |function| function.variables.len());
+    let host_functions = symbol_table
+        .host_functions
+        .into_iter()
+        .filter(|signature| {
+            op_codes.iter().any(|op_code| {
+                matches!(op_code, OpCode::CallHost { name, .. } if **name == signature.binding)
+            })
+        })
+        .collect();
+    let mut program = Program::new(
+        vec![FunctionEntry {
+            name: main_function.name.clone(),
+            start: 0,
+            variable_count,
+        }],
+        host_functions,
+        op_codes,
+        line_table,
+    );
+    if opt_level == OptLevel::O1 {
+        peephole::optimize(&mut program);
+    }
+    // Encoded now so that every run reuses it
+    program
+        .code()
+        .map_err(|error| Diagnostic::new(error.to_string()))?;
+    Ok(program)
+}
+
+pub fn analyze_document(
+    document: Document,
+    symbol_table: &mut SymbolTable,
+) -> Result<(), Diagnostic> {
+    let mut externals: Vec<String> = Vec::new();
+    for external in document.externals {
+        let error = |message: String| Err(Diagnostic::new(message).with_span(external.span));
+        if external.target != "rust" {
+            return error(format!("Unsupported external target: {}", external.target));
+        }
+        if externals.contains(&external.name) {
+            return error(format!("Duplicate external function: {}", external.name));
+        }
+        let types = external.params.iter().map(|(_, type_annot)| type_annot);
+        if let Some(type_annot) = types.chain([&external.returns]).find(|type_annot| {
+            !["Integer", "Float", "String", "Bool"].contains(&type_annot.as_str())
+        }) {
+            return error(format!("Unknown type: {}", type_annot));
+        }
+        // The declaration is trusted, and takes the place of a registered
+        // function of the same name
+        symbol_table
+            .host_functions
+            .retain(|signature| signature.name != external.name);
+        symbol_table.host_functions.push(HostSignature {
+            name: external.name.clone(),
+            binding: format!("{}.{}", external.module, external.function),
+            params: external
+                .params
+                .into_iter()
+                .map(|(_, type_annot)| type_annot)
+                .collect(),
+            returns: external.returns,
+        });
+        externals.push(external.name);
+    }
+    constant::evaluate_constants(&document.constants, symbol_table)?;
+    for function in document.functions {
+        symbol_table.functions.push(Function {
+            name: function.name.clone(),
+            variables: Vec::new(),
+            span: function.span,
+        });
+        for statement in function.body {
+            analyze(statement, symbol_table)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn analyze(ast: AbstractSyntaxTree, symbol_table: &mut SymbolTable) -> Result<(), Diagnostic> {
+    match ast.span() {
+        Some(span) => {
+            analyze_statement(ast, symbol_table).map_err(|diagnostic| diagnostic.with_span(span))
+        }
+        None => analyze_statement(ast, symbol_table),
+    }
+}
+
+fn analyze_statement(
+    ast: AbstractSyntaxTree,
+    symbol_table: &mut SymbolTable,
+) -> Result<(), Diagnostic> {
+    match ast {
+        AbstractSyntaxTree::Let {
+            name,
+            type_annot,
+            value,
+            span,
+        } => {
+            if expression_type(&value, symbol_table)? != type_annot {
+                return Err(Diagnostic::new("Type mismatch"));
+            }
+
+            let mut new_function = symbol_table.functions.pop().expect("Function not found");
+            new_function.variables.push(Variable {
+                name: name.clone(),
+                type_annot: type_annot.clone(),
+                span,
+            });
+
+            symbol_table.functions.push(new_function);
+        }
+        AbstractSyntaxTree::Block { statements } => {
+            for statement in statements {
+                analyze(statement, symbol_table)?;
+            }
+        }
+        // Only the print builtins and host functions can be called for their
+        // effect
+        AbstractSyntaxTree::Call { name, args, .. } => {
+            let params = match name.as_str() {
+                "print_integer" => vec!["Integer".to_owned()],
+                "print_float" => vec!["Float".to_owned()],
+                "print_bool" => vec!["Bool".to_owned()],
+                "print_string" => vec!["String".to_owned()],
+                name => match symbol_table.host_functions.iter().find(|f| f.name == name) {
+                    Some(signature) => {
+                        check_host_arity(name, &args)?;
+                        signature.params.clone()
+                    }
+                    None => return Err(Diagnostic::new(format!("Function not found: {}", name))),
+                },
+            };
+            analyze_args(&name, &params, &args, symbol_table)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Parameter and return types of the builtin functions that produce a value
+const BUILTINS: &[(&str, &[&str], &str)] = &[
+    ("add", &["Integer", "Integer"], "Integer"),
+    ("sub", &["Integer", "Integer"], "Integer"),
+    ("add_float", &["Float", "Float"], "Float"),
+    ("sub_float", &["Float", "Float"], "Float"),
+    ("and", &["Bool", "Bool"], "Bool"),
+    ("or", &["Bool", "Bool"], "Bool"),
+    ("not", &["Bool"], "Bool"),
+    ("concat", &["String", "String"], "String"),
+];
+
+// Checks an expression, including any calls nested in it, and returns the
+// name of its type
+pub fn expression_type(
+    expression: &AbstractSyntaxTree,
+    symbol_table: &SymbolTable,
+) -> Result<String, Diagnostic> {
+    let type_annot = match expression {
+        AbstractSyntaxTree::Int { .. } => "Integer",
+        AbstractSyntaxTree::Float { .. } => "Float",
+        AbstractSyntaxTree::String { .. } => "String",
+        AbstractSyntaxTree::UpName { name } if name == "True" || name == "False" => "Bool",
+        AbstractSyntaxTree::Name { name } => {
+            let constant = symbol_table.constants.iter().find(|c| c.name == *name);
+            let variable = symbol_table
+                .functions
+                .last()
+                .and_then(|function| function.variables.iter().find(|v| v.name == *name));
+            return match (constant, variable) {
+                (Some(constant), _) => Ok(constant.type_annot.clone()),
+                (None, Some(variable)) => Ok(variable.type_annot.clone()),
+                (None, None) => Err(Diagnostic::new(format!("Variable not found: {}", name))),
+            };
+        }
+        AbstractSyntaxTree::Call { name, args, span } => {
+            if let Some((_, params, returns)) = BUILTINS.iter().find(|(b, _, _)| b == name) {
+                let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                analyze_args(name, &params, args, symbol_table)?;
+                returns
+            } else if let Some(signature) =
+                symbol_table.host_functions.iter().find(|f| f.name == *name)
+            {
+                check_host_arity(name, args).map_err(|diagnostic| diagnostic.with_span(*span))?;
+                analyze_args(name, &signature.params, args, symbol_table)?;
+                return Ok(signature.returns.clone());
+            } else {
+                return Err(Diagnostic::new(format!("Function not found: {}", name)));
+            }
+        }
+        _ => return Err(Diagnostic::new("Invalid value")),
+    };
+    Ok(type_annot.to_owned())
+}
+
+// The VM passes host function arguments in registers
+fn check_host_arity(name: &str, args: &[AbstractSyntaxTree]) -> Result<(), Diagnostic> {
+    if args.len() > MAX_HOST_ARGS {
+        return Err(Diagnostic::new(format!(
+            "{} is called with {} arguments but host functions can take at most {}",
+            name,
+            args.len(),
+            MAX_HOST_ARGS
+        )));
+    }
+    Ok(())
+}
+
+fn analyze_args(
+    name: &str,
+    params: &[String],
+    args: &[AbstractSyntaxTree],
+    symbol_table: &SymbolTable,
+) -> Result<(), Diagnostic> {
+    if args.len() != params.len() {
+        return Err(Diagnostic::new(format!(
+            "{} takes {} arguments but was given {}",
+            name,
+            params.len(),
+            args.len()
+        )));
+    }
+    for (arg, param) in args.iter().zip(params) {
+        if expression_type(arg, symbol_table)? != *param {
+            return Err(Diagnostic::new("Type mismatch"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile_source;
+
+    fn error(source: &str) -> String {
+        compile_source(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn external_functions_are_checked() {
+        assert_eq!(
+            error("fn main() {\n  let root: Float = sqrt(2)\n}"),
+            "2:3: Function not found: sqrt"
+        );
+        assert_eq!(
+            error("@external(rust, \"m\", \"f\")\nfn f(x: Float) -> Float\nfn main() {\n  let n: Integer = f(2.0)\n}"),
+            "4:3: Type mismatch"
+        );
+        assert_eq!(
+            error("@external(erlang, \"m\", \"f\")\nfn f() -> Float\nfn main() {\n}"),
+            "2:4: Unsupported external target: erlang"
+        );
+        assert_eq!(
+            error("@external(rust, \"m\", \"f\")\nfn f() -> Float\n@external(rust, \"m\", \"g\")\nfn f() -> Float\nfn main() {\n}"),
+            "4:4: Duplicate external function: f"
+        );
+    }
+
+    #[test]
+    fn nested_arguments_are_checked() {
+        assert_eq!(
+            error("fn main() {\n  let a: Integer = add(1, add(2, \"x\"))\n}"),
+            "2:3: Type mismatch"
+        );
+        assert_eq!(
+            error("fn main() {\n  print_integer(add(1, concat(\"a\", \"b\")))\n}"),
+            "2:3: Type mismatch"
+        );
+        assert_eq!(
+            error("fn main() {\n  print_bool(not(True, False))\n}"),
+            "2:3: not takes 1 arguments but was given 2"
+        );
+        assert_eq!(
+            error("fn main() {\n  print_integer(add(1, nope))\n}"),
+            "2:3: Variable not found: nope"
+        );
+    }
+}
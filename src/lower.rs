@@ -0,0 +1,297 @@
+use crate::ast::AbstractSyntaxTree;
+use crate::code_gen::{SymbolTable, Variable};
+use crate::diagnostic::Diagnostic;
+use crate::ir::{BinaryOp, Block, BlockId, Function, Instruction, Temp, Terminator};
+use crate::vm::Value;
+use crate::BUILTINS;
+
+// Turns the statements of an analyzed function into IR. Variables are
+// looked up in the last function of the symbol table, and constants are
+// replaced by their values. Analysis rejects anything that can't be
+// lowered, so an error here means the two disagree.
+pub fn lower_function(
+    name: &str,
+    statements: &[AbstractSyntaxTree],
+    symbol_table: &SymbolTable,
+) -> Result<Function, Diagnostic> {
+    let mut lowering = Lowering {
+        symbol_table,
+        function: Function {
+            name: name.to_owned(),
+            temps: Vec::new(),
+            blocks: Vec::new(),
+        },
+        current: BlockId(0),
+    };
+    lowering.new_block();
+    for statement in statements {
+        lowering.statement(statement)?;
+    }
+    Ok(lowering.function)
+}
+
+struct Lowering<'a> {
+    symbol_table: &'a SymbolTable,
+    function: Function,
+    // The block instructions are added to
+    current: BlockId,
+}
+
+impl Lowering<'_> {
+    // New blocks halt until they're given another terminator
+    fn new_block(&mut self) -> BlockId {
+        self.function.blocks.push(Block {
+            instructions: Vec::new(),
+            lines: Vec::new(),
+            terminator: Terminator::Halt,
+        });
+        self.current = BlockId(self.function.blocks.len() - 1);
+        self.current
+    }
+
+    fn block(&mut self, id: BlockId) -> &mut Block {
+        &mut self.function.blocks[id.0]
+    }
+
+    fn new_temp(&mut self, type_annot: &str) -> Temp {
+        self.function.temps.push(type_annot.to_owned());
+        Temp(self.function.temps.len() - 1)
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        let current = self.current;
+        self.block(current).instructions.push(instruction);
+    }
+
+    fn statement(&mut self, statement: &AbstractSyntaxTree) -> Result<(), Diagnostic> {
+        let Some(span) = statement.span() else {
+            return self.lower_statement(statement);
+        };
+        let current = self.current;
+        let block = self.block(current);
+        block.lines.push((block.instructions.len(), span));
+        self.lower_statement(statement)
+            .map_err(|diagnostic| diagnostic.with_span(span))
+    }
+
+    fn lower_statement(&mut self, statement: &AbstractSyntaxTree) -> Result<(), Diagnostic> {
+        match statement {
+            AbstractSyntaxTree::Let { name, value, .. } => {
+                let value = self.expression(value, None)?;
+                let variable = variable_index(self.symbol_table, name)?;
+                self.emit(Instruction::Store { variable, value });
+            }
+            AbstractSyntaxTree::Block { statements } => {
+                for statement in statements {
+                    self.statement(statement)?;
+                }
+            }
+            AbstractSyntaxTree::Call { name, args, .. } => match (name.as_str(), &args[..]) {
+                ("print_integer" | "print_float" | "print_bool" | "print_string", [arg]) => {
+                    let value = self.expression(arg, None)?;
+                    self.emit(Instruction::Print { value });
+                }
+                // Called for its effect, so the result is dropped
+                _ => {
+                    self.expression(statement, None)?;
+                }
+            },
+            AbstractSyntaxTree::Comment { .. } => {}
+            _ => return Err(Diagnostic::new("Invalid code")),
+        }
+        Ok(())
+    }
+
+    // Puts the expression's value in dest, or a new temporary
+    fn expression(
+        &mut self,
+        expression: &AbstractSyntaxTree,
+        dest: Option<Temp>,
+    ) -> Result<Temp, Diagnostic> {
+        let symbol_table = self.symbol_table;
+        let (dest, instruction) = match expression {
+            AbstractSyntaxTree::Int { value, .. } => self.constant(dest, Value::Int(*value)),
+            AbstractSyntaxTree::Float { value, .. } => self.constant(dest, Value::Float(*value)),
+            AbstractSyntaxTree::String { value } => self.constant(dest, value.as_str().into()),
+            AbstractSyntaxTree::UpName { name } => match name.as_str() {
+                "True" => self.constant(dest, Value::Bool(true)),
+                "False" => self.constant(dest, Value::Bool(false)),
+                _ => return Err(Diagnostic::new("Invalid value")),
+            },
+            AbstractSyntaxTree::Name { name } => {
+                match symbol_table.constants.iter().find(|c| c.name == *name) {
+                    Some(constant) => self.constant(dest, constant.value.clone()),
+                    None => {
+                        let variable = variable_index(symbol_table, name)?;
+                        let type_annot = &variables(symbol_table)?[variable].type_annot;
+                        let dest = self.dest(dest, type_annot);
+                        (dest, Instruction::Load { dest, variable })
+                    }
+                }
+            }
+            AbstractSyntaxTree::Call { name, args, .. } => match (name.as_str(), &args[..]) {
+                ("add" | "sub" | "add_float" | "sub_float" | "concat", [lhs, rhs]) => {
+                    let lhs = self.expression(lhs, None)?;
+                    let rhs = self.expression(rhs, None)?;
+                    let op = match name.as_str() {
+                        "add" | "add_float" => BinaryOp::Add,
+                        "sub" | "sub_float" => BinaryOp::Sub,
+                        _ => BinaryOp::Concat,
+                    };
+                    let dest = self.dest(dest, builtin_returns(name)?);
+                    (dest, Instruction::Binary { op, dest, lhs, rhs })
+                }
+                ("not", [value]) => {
+                    let value = self.expression(value, None)?;
+                    let dest = self.dest(dest, "Bool");
+                    (dest, Instruction::Not { dest, value })
+                }
+                ("and" | "or", [first, second]) => {
+                    return self.short_circuit(name, first, second, dest)
+                }
+                (host_name, _) => {
+                    let signature = symbol_table
+                        .host_functions
+                        .iter()
+                        .find(|f| f.name == host_name)
+                        .ok_or_else(|| {
+                            Diagnostic::new(format!("Function not found: {}", host_name))
+                        })?;
+                    let binding = signature.binding.as_str().into();
+                    let args = args
+                        .iter()
+                        .map(|arg| self.expression(arg, None))
+                        .collect::<Result<_, _>>()?;
+                    let dest = self.dest(dest, &signature.returns);
+                    let instruction = Instruction::CallHost {
+                        dest,
+                        binding,
+                        args,
+                    };
+                    (dest, instruction)
+                }
+            },
+            _ => return Err(Diagnostic::new("Invalid value")),
+        };
+        self.emit(instruction);
+        Ok(dest)
+    }
+
+    fn dest(&mut self, dest: Option<Temp>, type_annot: &str) -> Temp {
+        dest.unwrap_or_else(|| self.new_temp(type_annot))
+    }
+
+    fn constant(&mut self, dest: Option<Temp>, value: Value) -> (Temp, Instruction) {
+        let dest = self.dest(dest, value.type_name());
+        (dest, Instruction::Const { dest, value })
+    }
+
+    // The first argument goes in dest, and the second only overwrites it
+    // when the first doesn't decide the result
+    fn short_circuit(
+        &mut self,
+        name: &str,
+        first: &AbstractSyntaxTree,
+        second: &AbstractSyntaxTree,
+        dest: Option<Temp>,
+    ) -> Result<Temp, Diagnostic> {
+        let dest = self.expression(first, dest)?;
+        let decided = self.current;
+        let second_block = self.new_block();
+        self.expression(second, Some(dest))?;
+        let last = self.current;
+        let end = self.new_block();
+        self.block(last).terminator = Terminator::Jump(end);
+        self.block(decided).terminator = match name {
+            "and" => Terminator::Branch {
+                condition: dest,
+                if_true: second_block,
+                if_false: end,
+            },
+            _ => Terminator::Branch {
+                condition: dest,
+                if_true: end,
+                if_false: second_block,
+            },
+        };
+        Ok(dest)
+    }
+}
+
+fn builtin_returns(name: &str) -> Result<&'static str, Diagnostic> {
+    BUILTINS
+        .iter()
+        .find(|(builtin, _, _)| *builtin == name)
+        .map(|(_, _, returns)| *returns)
+        .ok_or_else(|| Diagnostic::new(format!("Function not found: {}", name)))
+}
+
+fn variables(symbol_table: &SymbolTable) -> Result<&[Variable], Diagnostic> {
+    symbol_table
+        .functions
+        .last()
+        .map(|function| function.variables.as_slice())
+        .ok_or_else(|| Diagnostic::new("Function not found"))
+}
+
+fn variable_index(symbol_table: &SymbolTable, name: &str) -> Result<usize, Diagnostic> {
+    variables(symbol_table)?
+        .iter()
+        .position(|v| v.name == name)
+        .ok_or_else(|| Diagnostic::new(format!("Variable not found: {}", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze_document;
+    use crate::lex::lex;
+    use crate::parse::parse;
+
+    #[test]
+    fn lowers_to_ir_with_blocks_and_temporaries() {
+        let source = r#"
+            const two: Integer = 2
+            fn main() {
+                let a: Integer = 1
+                let b: Bool = and(True, not(False))
+                print_integer(add(a, two))
+                print_bool(b)
+            }"#;
+        let document = parse(lex(source.to_string())).unwrap();
+        let mut symbol_table = SymbolTable {
+            functions: Vec::new(),
+            constants: Vec::new(),
+            host_functions: Vec::new(),
+        };
+        analyze_document(document.clone(), &mut symbol_table).unwrap();
+        let main = &document.functions[0];
+        let function = lower_function(&main.name, &main.body, &symbol_table).unwrap();
+        assert_eq!(
+            function.to_string(),
+            r#"fn main {
+b0:
+  t0: Integer = const 1
+  store s0, t0
+  t1: Bool = const True
+  branch t1, b1, b2
+b1:
+  t2: Bool = const False
+  t1: Bool = not t2
+  jump b2
+b2:
+  store s1, t1
+  t3: Integer = load s0
+  t4: Integer = const 2
+  t5: Integer = add t3, t4
+  print t5
+  t6: Bool = load s1
+  print t6
+  halt
+}
+"#
+        );
+        assert_eq!(function.blocks[0].lines.len(), 2);
+        assert_eq!(function.blocks[2].lines.len(), 2);
+    }
+}
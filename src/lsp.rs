@@ -0,0 +1,673 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use crate::analyze_document;
+use crate::code_gen::{Function, SymbolTable};
+use crate::diagnostic::Diagnostic;
+use crate::json::Json;
+use crate::lex::lex_with_spans;
+use crate::parse::parse_with_spans;
+use crate::span::{Position, Span};
+use crate::token::Token;
+
+// Signatures of the builtin functions, shown on hover and in completions
+const BUILTINS: &[(&str, &str)] = &[
+    ("add", "fn(Integer, Integer) -> Integer"),
+    ("sub", "fn(Integer, Integer) -> Integer"),
+    ("add_float", "fn(Float, Float) -> Float"),
+    ("sub_float", "fn(Float, Float) -> Float"),
+    ("and", "fn(Bool, Bool) -> Bool"),
+    ("or", "fn(Bool, Bool) -> Bool"),
+    ("not", "fn(Bool) -> Bool"),
+    ("concat", "fn(String, String) -> String"),
+    ("print_integer", "fn(Integer) -> Nil"),
+    ("print_float", "fn(Float) -> Nil"),
+    ("print_bool", "fn(Bool) -> Nil"),
+    ("print_string", "fn(String) -> Nil"),
+];
+
+// JSON-RPC error codes
+const PARSE_ERROR: f64 = -32700.0;
+const INVALID_PARAMS: f64 = -32602.0;
+const METHOD_NOT_FOUND: f64 = -32601.0;
+
+// CompletionItemKind values from the protocol
+const FUNCTION_KIND: usize = 3;
+const VARIABLE_KIND: usize = 6;
+const CONSTANT_KIND: usize = 21;
+
+// What the server knows about one open document. The symbol table holds
+// whatever analysis got through before the first error.
+struct Analysis {
+    tokens: Vec<Token>,
+    spans: Vec<Span>,
+    diagnostics: Vec<Diagnostic>,
+    symbol_table: SymbolTable,
+    // Where each @external function is declared, as host signatures don't
+    // keep a span
+    externals: HashMap<String, Span>,
+}
+
+// A name resolved at some point in a document
+enum Definition {
+    Constant { type_annot: String, span: Span },
+    Variable { type_annot: String, span: Span },
+    Function { span: Span },
+    External { signature: String, span: Span },
+    Builtin { signature: &'static str },
+}
+
+fn analyze_source(source: &str) -> Analysis {
+    let (tokens, spans) = lex_with_spans(source);
+    let mut diagnostics: Vec<Diagnostic> = tokens
+        .iter()
+        .zip(&spans)
+        .filter_map(|(token, span)| Some(Diagnostic::new(token.error()?).with_span(*span)))
+        .collect();
+    let mut symbol_table = SymbolTable {
+        functions: Vec::new(),
+        constants: Vec::new(),
+        host_functions: Vec::new(),
+    };
+    let mut externals = HashMap::new();
+    let result = parse_with_spans(tokens.clone(), &spans).and_then(|document| {
+        for external in &document.externals {
+            externals.insert(external.name.clone(), external.span);
+        }
+        analyze_document(document, &mut symbol_table)
+    });
+    if let Err(diagnostic) = result {
+        // The parser stops at the first invalid token, which is already reported
+        if !diagnostics.contains(&diagnostic) {
+            diagnostics.push(diagnostic);
+        }
+    }
+    Analysis {
+        tokens,
+        spans,
+        diagnostics,
+        symbol_table,
+        externals,
+    }
+}
+
+impl Analysis {
+    fn name_at(&self, position: Position) -> Option<&str> {
+        self.tokens
+            .iter()
+            .zip(&self.spans)
+            .find_map(|(token, span)| match token {
+                Token::Name { name } | Token::UpName { name } if span.contains(position) => {
+                    Some(name.as_str())
+                }
+                _ => None,
+            })
+    }
+
+    // The function the position is in, found by the span of the name after
+    // the last top level `fn` before it. Externals have no entry in the
+    // symbol table's functions, so nothing is found inside their signatures.
+    fn function_at(&self, position: Position) -> Option<&Function> {
+        let mut depth = 0;
+        let mut name_span = None;
+        let mut tokens = self.tokens.iter().zip(&self.spans).peekable();
+        while let Some((token, span)) = tokens.next() {
+            if span.start > position {
+                break;
+            }
+            match token {
+                Token::LeftBrace => depth += 1,
+                Token::RightBrace => depth -= 1,
+                Token::Fn if depth == 0 => name_span = tokens.peek().map(|(_, span)| **span),
+                Token::Const if depth == 0 => name_span = None,
+                _ => {}
+            }
+        }
+        let name_span = name_span?;
+        self.symbol_table
+            .functions
+            .iter()
+            .find(|function| function.span == name_span)
+    }
+
+    fn resolve(&self, name: &str, position: Position) -> Option<Definition> {
+        let function = self.function_at(position);
+        // The latest binding before the position wins
+        if let Some(variable) = function.and_then(|function| {
+            function
+                .variables
+                .iter()
+                .rev()
+                .find(|v| v.name == name && v.span.start <= position)
+        }) {
+            return Some(Definition::Variable {
+                type_annot: variable.type_annot.clone(),
+                span: variable.span,
+            });
+        }
+        if let Some(constant) = self.symbol_table.constants.iter().find(|c| c.name == name) {
+            return Some(Definition::Constant {
+                type_annot: constant.type_annot.clone(),
+                span: constant.span,
+            });
+        }
+        if let Some(function) = self.symbol_table.functions.iter().find(|f| f.name == name) {
+            return Some(Definition::Function {
+                span: function.span,
+            });
+        }
+        if let Some(external) = self
+            .symbol_table
+            .host_functions
+            .iter()
+            .find(|h| h.name == name)
+        {
+            return Some(Definition::External {
+                signature: external.to_string(),
+                span: self.externals.get(name).copied()?,
+            });
+        }
+        BUILTINS
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|(_, signature)| Definition::Builtin { signature })
+    }
+
+    fn hover(&self, position: Position) -> Json {
+        let Some(name) = self.name_at(position) else {
+            return Json::Null;
+        };
+        let signature = match self.resolve(name, position) {
+            Some(Definition::Variable { type_annot, .. }) => format!("{}: {}", name, type_annot),
+            Some(Definition::Constant { type_annot, .. }) => {
+                format!("const {}: {}", name, type_annot)
+            }
+            Some(Definition::Function { .. }) => format!("fn {}", name),
+            Some(Definition::External { signature, .. }) => format!("{}: {}", name, signature),
+            Some(Definition::Builtin { signature }) => format!("{}: {}", name, signature),
+            None => return Json::Null,
+        };
+        Json::object([(
+            "contents",
+            Json::object([
+                ("kind", "markdown".into()),
+                ("value", format!("```bee\n{}\n```", signature).into()),
+            ]),
+        )])
+    }
+
+    fn definition(&self, uri: &str, position: Position) -> Json {
+        let span = match self
+            .name_at(position)
+            .and_then(|name| self.resolve(name, position))
+        {
+            Some(Definition::Variable { span, .. })
+            | Some(Definition::Constant { span, .. })
+            | Some(Definition::Function { span })
+            | Some(Definition::External { span, .. }) => span,
+            Some(Definition::Builtin { .. }) | None => return Json::Null,
+        };
+        Json::object([("uri", uri.into()), ("range", range(span))])
+    }
+
+    fn completion(&self, position: Position) -> Json {
+        let mut items = Vec::new();
+        let mut item = |label: &str, kind: usize, detail: String| {
+            items.push(Json::object([
+                ("label", label.into()),
+                ("kind", kind.into()),
+                ("detail", detail.into()),
+            ]))
+        };
+        if let Some(function) = self.function_at(position) {
+            let mut seen = Vec::new();
+            for variable in function.variables.iter().rev() {
+                if variable.span.start <= position && !seen.contains(&&variable.name) {
+                    seen.push(&variable.name);
+                    item(&variable.name, VARIABLE_KIND, variable.type_annot.clone());
+                }
+            }
+        }
+        for constant in &self.symbol_table.constants {
+            item(&constant.name, CONSTANT_KIND, constant.type_annot.clone());
+        }
+        for function in &self.symbol_table.functions {
+            item(&function.name, FUNCTION_KIND, "fn".to_owned());
+        }
+        for external in &self.symbol_table.host_functions {
+            item(&external.name, FUNCTION_KIND, external.to_string());
+        }
+        for (name, signature) in BUILTINS {
+            item(name, FUNCTION_KIND, signature.to_string());
+        }
+        Json::Array(items)
+    }
+}
+
+fn range(span: Span) -> Json {
+    let position = |position: Position| {
+        Json::object([
+            ("line", position.line.into()),
+            ("character", position.column.into()),
+        ])
+    };
+    Json::object([("start", position(span.start)), ("end", position(span.end))])
+}
+
+struct Server {
+    documents: HashMap<String, Analysis>,
+}
+
+impl Server {
+    fn request(&self, method: &str, params: &Json) -> Result<Json, (f64, String)> {
+        match method {
+            "initialize" => Ok(Json::object([
+                (
+                    "capabilities",
+                    Json::object([
+                        // Full document sync
+                        ("textDocumentSync", 1.into()),
+                        ("hoverProvider", Json::Bool(true)),
+                        ("definitionProvider", Json::Bool(true)),
+                        ("completionProvider", Json::object([])),
+                    ]),
+                ),
+                ("serverInfo", Json::object([("name", "bee".into())])),
+            ])),
+            "shutdown" => Ok(Json::Null),
+            "textDocument/hover" | "textDocument/definition" | "textDocument/completion" => {
+                let Some((uri, position)) = document_position(params) else {
+                    return Err((
+                        INVALID_PARAMS,
+                        "Expected a document and position".to_owned(),
+                    ));
+                };
+                let Some(analysis) = self.documents.get(uri) else {
+                    return Ok(Json::Null);
+                };
+                Ok(match method {
+                    "textDocument/hover" => analysis.hover(position),
+                    "textDocument/definition" => analysis.definition(uri, position),
+                    _ => analysis.completion(position),
+                })
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method {}", method))),
+        }
+    }
+
+    // Returns the notifications to send back
+    fn notify(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let document = params.get("textDocument");
+        let Some(uri) = document.and_then(|d| d.get("uri")).and_then(Json::as_str) else {
+            return Vec::new();
+        };
+        let text = match method {
+            "textDocument/didOpen" => document.and_then(|d| d.get("text")),
+            // Only full document changes are requested in initialize
+            "textDocument/didChange" => match params.get("contentChanges") {
+                Some(Json::Array(changes)) => changes.last().and_then(|c| c.get("text")),
+                _ => None,
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return vec![publish_diagnostics(uri, &[])];
+            }
+            _ => None,
+        };
+        let Some(text) = text.and_then(Json::as_str) else {
+            return Vec::new();
+        };
+        let analysis = analyze_source(text);
+        let notification = publish_diagnostics(uri, &analysis.diagnostics);
+        self.documents.insert(uri.to_owned(), analysis);
+        vec![notification]
+    }
+}
+
+fn document_position(params: &Json) -> Option<(&str, Position)> {
+    let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+    let position = params.get("position")?;
+    let position = Position {
+        line: position.get("line")?.as_usize()?,
+        column: position.get("character")?.as_usize()?,
+    };
+    Some((uri, position))
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: &[Diagnostic]) -> Json {
+    let diagnostics = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            Json::object([
+                ("range", range(diagnostic.span.unwrap_or_default())),
+                // Error
+                ("severity", 1.into()),
+                ("source", "bee".into()),
+                ("message", diagnostic.message.clone().into()),
+            ])
+        })
+        .collect();
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("method", "textDocument/publishDiagnostics".into()),
+        (
+            "params",
+            Json::object([
+                ("uri", uri.into()),
+                ("diagnostics", Json::Array(diagnostics)),
+            ]),
+        ),
+    ])
+}
+
+// Messages are framed by a Content-Length header. None at end of input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let Some(content_length) = content_length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Missing Content-Length header",
+        ));
+    };
+    let mut body = vec![0; content_length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+fn response(id: Json, result: Result<Json, (f64, String)>) -> Json {
+    let outcome = match result {
+        Ok(result) => ("result", result),
+        Err((code, message)) => (
+            "error",
+            Json::object([("code", Json::Number(code)), ("message", message.into())]),
+        ),
+    };
+    Json::object([("jsonrpc", "2.0".into()), ("id", id), outcome])
+}
+
+// Serves until the client sends exit or closes the input
+pub fn serve(mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut server = Server {
+        documents: HashMap::new(),
+    };
+    while let Some(body) = read_message(&mut input)? {
+        let message = match Json::parse(&body) {
+            Ok(message) => message,
+            Err(error) => {
+                write_message(
+                    &mut output,
+                    &response(Json::Null, Err((PARSE_ERROR, error))),
+                )?;
+                continue;
+            }
+        };
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        match (
+            message.get("method").and_then(Json::as_str),
+            message.get("id"),
+        ) {
+            (Some("exit"), _) => break,
+            (Some(method), Some(id)) => {
+                let result = server.request(method, &params);
+                write_message(&mut output, &response(id.clone(), result))?;
+            }
+            (Some(method), None) => {
+                for notification in server.notify(method, &params) {
+                    write_message(&mut output, &notification)?;
+                }
+            }
+            // Responses from the client, the server never sends requests
+            (None, _) => {}
+        }
+    }
+    Ok(())
+}
+
+pub fn run() -> io::Result<()> {
+    serve(io::stdin().lock(), io::stdout().lock())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lsp::{analyze_source, Definition};
+    use crate::span::Position;
+    use crate::{json, lsp};
+
+    #[test]
+    fn lsp_finds_functions_by_span() {
+        // The external's `fn` comes first but isn't one of the functions
+        let source = "@external(rust, \"math\", \"double\")\nfn double(x: Integer) -> Integer\n\nfn main() {\n  let one: Integer = double(1)\n  print_integer(one)\n}\n";
+        let analysis = analyze_source(source);
+        assert!(analysis.diagnostics.is_empty());
+        let position = Position {
+            line: 5,
+            column: 16,
+        };
+        assert!(matches!(
+            analysis.resolve("one", position),
+            Some(Definition::Variable { .. })
+        ));
+    }
+
+    #[test]
+    fn lsp_resolves_external_functions() {
+        let source = "@external(rust, \"math\", \"sqrt\")\nfn sqrt(x: Float) -> Float\n\nfn main() {\n  let root: Float = sqrt(2.25)\n}\n";
+        let analysis = analyze_source(source);
+        assert!(analysis.diagnostics.is_empty());
+        let call = Position {
+            line: 4,
+            column: 20,
+        };
+        assert_eq!(
+            analysis.hover(call).to_string(),
+            r#"{"contents":{"kind":"markdown","value":"```bee\nsqrt: fn(Float) -> Float\n```"}}"#
+        );
+        assert_eq!(
+            analysis.definition("file:///main.bee", call).to_string(),
+            r#"{"uri":"file:///main.bee","range":{"start":{"line":1,"character":3},"end":{"line":1,"character":7}}}"#
+        );
+    }
+
+    #[test]
+    fn lsp_analyzes_every_function() {
+        let source = "fn helper() {\n  let name: String = \"bee\"\n}\n\nfn main() {\n  let one: Integer = 1\n  let two: Integer = add(one, one)\n}\n";
+        let analysis = analyze_source(source);
+        assert!(analysis.diagnostics.is_empty());
+        let name = Position { line: 1, column: 6 };
+        assert_eq!(
+            analysis.hover(name).to_string(),
+            r#"{"contents":{"kind":"markdown","value":"```bee\nname: String\n```"}}"#
+        );
+        let one = Position {
+            line: 6,
+            column: 25,
+        };
+        assert_eq!(
+            analysis.hover(one).to_string(),
+            r#"{"contents":{"kind":"markdown","value":"```bee\none: Integer\n```"}}"#
+        );
+        assert_eq!(
+            analysis.definition("file:///main.bee", one).to_string(),
+            r#"{"uri":"file:///main.bee","range":{"start":{"line":5,"character":2},"end":{"line":5,"character":22}}}"#
+        );
+    }
+
+    #[test]
+    fn lsp_scripted_session() {
+        let source = "const limit: Integer = 5\n\nfn main() {\n  let one: Integer = 1\n  let two: Integer = add(one, limit)\n  let bad: String = one\n}\n";
+        let document =
+            json::Json::object([("uri", "file:///main.bee".into()), ("text", source.into())]);
+        let at = |id: usize, method: &str, line: usize, character: usize| {
+            json::Json::object([
+                ("jsonrpc", "2.0".into()),
+                ("id", id.into()),
+                ("method", method.into()),
+                (
+                    "params",
+                    json::Json::object([
+                        (
+                            "textDocument",
+                            json::Json::object([("uri", "file:///main.bee".into())]),
+                        ),
+                        (
+                            "position",
+                            json::Json::object([
+                                ("line", line.into()),
+                                ("character", character.into()),
+                            ]),
+                        ),
+                    ]),
+                ),
+            ])
+        };
+        let messages = [
+            r#"{"jsonrpc":"2.0","id":0,"method":"initialize","params":{}}"#.to_owned(),
+            r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#.to_owned(),
+            json::Json::object([
+                ("jsonrpc", "2.0".into()),
+                ("method", "textDocument/didOpen".into()),
+                ("params", json::Json::object([("textDocument", document)])),
+            ])
+            .to_string(),
+            at(1, "textDocument/hover", 4, 26).to_string(),
+            at(2, "textDocument/definition", 4, 26).to_string(),
+            at(3, "textDocument/completion", 5, 2).to_string(),
+            at(4, "textDocument/rename", 4, 26).to_string(),
+            r#"{"jsonrpc":"2.0","id":5,"method":"shutdown"}"#.to_owned(),
+            r#"{"jsonrpc":"2.0","method":"exit"}"#.to_owned(),
+        ];
+        let input: String = messages
+            .iter()
+            .map(|message| format!("Content-Length: {}\r\n\r\n{}", message.len(), message))
+            .collect();
+
+        let mut output = Vec::new();
+        lsp::serve(input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let responses: Vec<json::Json> = output
+            .split("Content-Length: ")
+            .skip(1)
+            .map(|frame| json::Json::parse(frame.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect();
+        let result = |id: usize| {
+            responses
+                .iter()
+                .find(|response| response.get("id") == Some(&id.into()))
+                .unwrap()
+        };
+
+        let capabilities = result(0)
+            .get("result")
+            .unwrap()
+            .get("capabilities")
+            .unwrap();
+        assert_eq!(
+            capabilities.get("hoverProvider"),
+            Some(&json::Json::Bool(true))
+        );
+
+        let diagnostics = responses
+            .iter()
+            .find(|message| {
+                message.get("method").and_then(json::Json::as_str)
+                    == Some("textDocument/publishDiagnostics")
+            })
+            .unwrap()
+            .get("params")
+            .unwrap()
+            .get("diagnostics")
+            .unwrap();
+        assert_eq!(
+            diagnostics.to_string(),
+            r#"[{"range":{"start":{"line":5,"character":2},"end":{"line":5,"character":23}},"severity":1,"source":"bee","message":"Type mismatch"}]"#
+        );
+
+        // Requests are answered from the analysis of the opened document
+        assert_eq!(
+            result(1)
+                .get("result")
+                .unwrap()
+                .get("contents")
+                .unwrap()
+                .get("value"),
+            Some(&json::Json::from("```bee\none: Integer\n```"))
+        );
+        assert!(result(2).get("result").unwrap().get("range").is_some());
+        assert!(matches!(
+            result(3).get("result"),
+            Some(json::Json::Array(items)) if !items.is_empty()
+        ));
+
+        let error = result(4).get("error").unwrap();
+        assert_eq!(error.get("code"), Some(&json::Json::Number(-32601.0)));
+        assert_eq!(result(5).get("result"), Some(&json::Json::Null));
+    }
+
+    #[test]
+    fn lsp_hover_and_definition() {
+        let source = "const limit: Integer = 5\n\nfn main() {\n  let one: Integer = 1\n  let two: Integer = add(one, limit)\n}\n";
+        let analysis = analyze_source(source);
+        assert!(analysis.diagnostics.is_empty());
+        let one = Position {
+            line: 4,
+            column: 26,
+        };
+        let limit = Position {
+            line: 4,
+            column: 31,
+        };
+        assert_eq!(
+            analysis.hover(one).to_string(),
+            r#"{"contents":{"kind":"markdown","value":"```bee\none: Integer\n```"}}"#
+        );
+        assert_eq!(
+            analysis.hover(limit).to_string(),
+            r#"{"contents":{"kind":"markdown","value":"```bee\nconst limit: Integer\n```"}}"#
+        );
+        assert_eq!(
+            analysis.definition("file:///main.bee", one).to_string(),
+            r#"{"uri":"file:///main.bee","range":{"start":{"line":3,"character":2},"end":{"line":3,"character":22}}}"#
+        );
+        let nothing = Position { line: 1, column: 0 };
+        assert_eq!(analysis.hover(nothing), json::Json::Null);
+    }
+
+    #[test]
+    fn lsp_completion() {
+        let source = "const limit: Integer = 5\n\nfn main() {\n  let one: Integer = 1\n  let two: Integer = add(one, limit)\n  \n}\n";
+        let analysis = analyze_source(source);
+        let json::Json::Array(items) = analysis.completion(Position { line: 5, column: 2 }) else {
+            panic!("Expected completion items");
+        };
+        let labels: Vec<&str> = items
+            .iter()
+            .map(|item| item.get("label").unwrap().as_str().unwrap())
+            .collect();
+        // Variables in scope come first, the latest first
+        assert_eq!(&labels[..4], &["two", "one", "limit", "main"]);
+        assert!(labels.contains(&"print_integer"));
+    }
+}
@@ -1,579 +1,406 @@
-use core::panic;
 use std::collections::HashMap;
-
-mod ast;
-mod code_gen;
-mod lex;
-mod opcode;
-mod parse;
-mod token;
-
-use ast::{AbstractSyntaxTree, Document};
-use code_gen::{code_gen_document, Constant, Function, SymbolTable, Variable};
-use lex::lex;
-use opcode::OpCode;
-use parse::parse;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+use bee::code_gen::{code_gen_document, SymbolTable};
+use bee::lex::lex;
+use bee::parse::parse;
+use bee::vm::interpret;
+use bee::{
+    analyze_document, asm, bench, bytecode, compile_source_with_host_functions, format, host, lsp,
+    repl, verify,
+};
+use bee::{OptLevel, Program, RunOptions};
+
+// Lets `bee bench` count allocations
+#[global_allocator]
+static ALLOCATOR: bench::CountingAllocator = bench::CountingAllocator;
 
 fn main() {
-    let contents = r#"
-    const ctest: Integer = 5
-    
-    fn main() {
-      let one: Integer = 1
-      let one_again: Integer = one
-      let five: Integer = add(one, 4)
-      let itest: Integer = sub(five, 4)
-      let ctest_add: Integer = add(1, ctest)
-      print_integer(itest)
-      print_integer(ctest_add)
-    }"#;
-    let tokens = lex(contents.to_string());
-    let document = parse(tokens);
-    let mut symbol_table = SymbolTable {
-        functions: Vec::new(),
-        constants: Vec::new(),
-    };
-    analyze_document(document.clone(), &mut symbol_table);
-    println!("Symbol Table:");
-    println!("{:?}", symbol_table);
-
-    let mut op_codes = Vec::new();
-    code_gen_document(document, &mut symbol_table, &mut op_codes);
-
-    println!("Codegen Output:");
-    for op_code in op_codes.clone() {
-        println!("{:?}", op_code);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("fmt") => std::process::exit(fmt_command(&args[1..])),
+        Some("compile") => std::process::exit(compile_command(&args[1..])),
+        Some("run") => std::process::exit(run_command(&args[1..])),
+        Some("disasm") => std::process::exit(disasm_command(&args[1..])),
+        Some("bench") => std::process::exit(bench_command(&args[1..])),
+        Some("repl") => repl::run(),
+        Some("lsp") => {
+            if let Err(error) = lsp::run() {
+                eprintln!("error: {}", error);
+                std::process::exit(1);
+            }
+        }
+        _ => run_example(),
     }
-
-    println!("Interpretation:");
-    interpret(op_codes);
-}
-
-#[derive(Debug, Clone)]
-enum Value {
-    Int(usize),
-    Float(f64),
-    String(Box<str>),
 }
 
-impl Operation for Value {
-    fn add(&self, other: &Self) -> Self {
-        match self {
-            Value::Int(value) => match other {
-                Value::Int(other_value) => Value::Int(value + other_value),
-                _ => panic!("Invalid operation"),
-            },
-            Value::Float(value) => match other {
-                Value::Float(other_value) => Value::Float(value + other_value),
-                _ => panic!("Invalid operation"),
-            },
-            Value::String(_) => panic!("Invalid operation"),
+// bee fmt [--check] [files...]
+// Formats the files in place, or stdin to stdout when no files are given.
+// With --check nothing is written and the exit code is 1 if any input is
+// not formatted.
+fn fmt_command(args: &[String]) -> i32 {
+    let check = args.iter().any(|arg| arg == "--check");
+    let paths: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+
+    if paths.is_empty() {
+        let mut source = String::new();
+        if let Err(error) = std::io::stdin().read_to_string(&mut source) {
+            eprintln!("error: {}", error);
+            return 1;
         }
+        return match format::format(&source) {
+            Ok(formatted) if check && formatted != source => {
+                eprintln!("stdin is not formatted");
+                1
+            }
+            Ok(_) if check => 0,
+            Ok(formatted) => {
+                print!("{}", formatted);
+                0
+            }
+            Err(diagnostic) => {
+                eprintln!("error: {}", diagnostic);
+                1
+            }
+        };
     }
 
-    fn sub(&self, other: &Self) -> Self {
-        match self {
-            Value::Int(value) => match other {
-                Value::Int(other_value) => Value::Int(value - other_value),
-                _ => panic!("Invalid operation"),
-            },
-            Value::Float(value) => match other {
-                Value::Float(other_value) => Value::Float(value - other_value),
-                _ => panic!("Invalid operation"),
-            },
-            Value::String(_) => panic!("Invalid operation"),
+    let mut status = 0;
+    for path in paths {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("error: {}: {}", path, error);
+                status = 1;
+                continue;
+            }
+        };
+        match format::format(&source) {
+            Ok(formatted) if formatted == source => {}
+            Ok(_) if check => {
+                eprintln!("{} is not formatted", path);
+                status = 1;
+            }
+            Ok(formatted) => {
+                if let Err(error) = std::fs::write(path, formatted) {
+                    eprintln!("error: {}: {}", path, error);
+                    status = 1;
+                }
+            }
+            Err(diagnostic) => {
+                eprintln!("error: {}: {}", path, diagnostic);
+                status = 1;
+            }
         }
     }
+    status
+}
 
-    fn and(&self, other: &Self) -> Self {
-        match self {
-            Value::Int(value) => match other {
-                Value::Int(other_value) => Value::Int(value & other_value),
-                _ => panic!("Invalid operation"),
-            },
-            Value::Float(_) => panic!("Invalid operation"),
-            Value::String(_) => panic!("Invalid operation"),
+// Takes -O0 or -O1 out of the arguments, defaulting to -O1
+fn opt_level_flag(args: &[String]) -> (OptLevel, Vec<String>) {
+    let mut opt_level = OptLevel::default();
+    let mut rest = Vec::new();
+    for arg in args {
+        match OptLevel::from_flag(arg) {
+            Some(level) => opt_level = level,
+            None => rest.push(arg.clone()),
         }
     }
+    (opt_level, rest)
+}
 
-    fn or(&self, other: &Self) -> Self {
-        match self {
-            Value::Int(value) => match other {
-                Value::Int(other_value) => Value::Int(value | other_value),
-                _ => panic!("Invalid operation"),
-            },
-            Value::Float(_) => panic!("Invalid operation"),
-            Value::String(_) => panic!("Invalid operation"),
+// bee compile [-O0 | -O1] <file.bee> [-o <file.beec>]
+// The output defaults to the input path with a .beec extension
+fn compile_command(args: &[String]) -> i32 {
+    let (opt_level, args) = opt_level_flag(args);
+    let (input, output) = match args.as_slice() {
+        [input] => (input, std::path::Path::new(input).with_extension("beec")),
+        [input, flag, output] if flag == "-o" => (input, output.into()),
+        _ => {
+            eprintln!("usage: bee compile [-O0 | -O1] <file.bee> [-o <file.beec>]");
+            return 2;
+        }
+    };
+    let source = match std::fs::read_to_string(input) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("error: {}: {}", input, error);
+            return 1;
+        }
+    };
+    let program = match compile_source_with_host_functions(&source, Vec::new(), opt_level) {
+        Ok(program) => program,
+        Err(diagnostic) => {
+            eprintln!("error: {}: {}", input, diagnostic);
+            return 1;
+        }
+    };
+    let bytes = match bytecode::encode(&program) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            eprintln!("error: {}: {}", input, error);
+            return 1;
         }
+    };
+    if let Err(error) = std::fs::write(&output, bytes) {
+        eprintln!("error: {}: {}", output.display(), error);
+        return 1;
     }
+    0
+}
 
-    fn not(&self) -> Self {
-        match self {
-            Value::Int(value) => Value::Int(value ^ 1),
-            Value::Float(_) => panic!("Invalid operation"),
-            Value::String(_) => panic!("Invalid operation"),
+// bee run [-O0 | -O1] [--fuel <op codes>] [--timeout <milliseconds>] <file>
+// Runs a compiled .beec file, or compiles and runs an assembly or source file
+fn run_command(args: &[String]) -> i32 {
+    let usage = "usage: bee run [-O0 | -O1] [--fuel <op codes>] [--timeout <milliseconds>] <file.beec | file.beeasm | file.bee>";
+    let (opt_level, args) = opt_level_flag(args);
+    let mut options = RunOptions::default();
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let limit = match arg.as_str() {
+            "--fuel" | "--timeout" => args.next().and_then(|value| value.parse().ok()),
+            _ if path.is_none() => {
+                path = Some(arg);
+                continue;
+            }
+            _ => None,
+        };
+        match (arg.as_str(), limit) {
+            ("--fuel", Some(fuel)) => options.fuel = Some(fuel),
+            ("--timeout", Some(milliseconds)) => {
+                options.deadline = Some(Instant::now() + Duration::from_millis(milliseconds))
+            }
+            _ => {
+                eprintln!("{}", usage);
+                return 2;
+            }
         }
     }
-
-    fn concat(&self, other: &Self) -> Self {
-        match self {
-            Value::String(value) => match other {
-                Value::String(other_value) => {
-                    Value::String(format!("{}{}", value, other_value).into())
-                }
-                _ => panic!("Invalid operation"),
-            },
-            Value::Int(_) => panic!("Invalid operation"),
-            Value::Float(_) => panic!("Invalid operation"),
+    let Some(path) = path else {
+        eprintln!("{}", usage);
+        return 2;
+    };
+    let result = load_program(path, opt_level).and_then(|program| {
+        verify::verify(&program).map_err(|error| error.to_string())?;
+        // The command line has no host functions to call
+        host::link(&program, &HashMap::new()).map_err(|error| error.to_string())?;
+        interpret(&program, &options).map_err(|error| error.to_string())
+    });
+    match result {
+        Ok(_) => 0,
+        Err(error) => {
+            eprintln!("error: {}: {}", path, error);
+            1
         }
     }
 }
 
-pub trait Operation {
-    fn add(&self, other: &Self) -> Self;
-    fn sub(&self, other: &Self) -> Self;
-    fn and(&self, other: &Self) -> Self;
-    fn or(&self, other: &Self) -> Self;
-    fn not(&self) -> Self;
-    fn concat(&self, other: &Self) -> Self;
+// bee disasm [-O0 | -O1] <file>
+// Prints the op codes of a compiled .beec file or a source file as assembly
+fn disasm_command(args: &[String]) -> i32 {
+    let (opt_level, args) = opt_level_flag(args);
+    let [path] = args.as_slice() else {
+        eprintln!("usage: bee disasm [-O0 | -O1] <file.beec | file.bee>");
+        return 2;
+    };
+    match load_program(path, opt_level) {
+        Ok(program) => {
+            print!("{}", asm::disassemble(program.op_codes()));
+            0
+        }
+        Err(error) => {
+            eprintln!("error: {}: {}", path, error);
+            1
+        }
+    }
 }
 
-const INIT: Value = Value::Int(0);
-pub fn interpret(op_codes: Vec<OpCode>) {
-    let mut registers: [Value; 32] = [INIT; 32];
-
-    // Does this need to be a hash map? since everything is index by usize
-    // we should be able to use a vector
-    let mut variables: HashMap<usize, Value> = HashMap::new();
-    let mut pc = 0;
-    loop {
-        let op_code = &op_codes[pc];
-        match op_code {
-            OpCode::Add { arg1, arg2, arg3 } => {
-                registers[*arg1] = registers[*arg2].add(&registers[*arg3]);
-            }
-            OpCode::Sub { arg1, arg2, arg3 } => {
-                registers[*arg1] = registers[*arg2].sub(&registers[*arg3]);
-            }
-            OpCode::And { arg1, arg2, arg3 } => {
-                registers[*arg1] = registers[*arg2].and(&registers[*arg3]);
-            }
-            OpCode::Or { arg1, arg2, arg3 } => {
-                registers[*arg1] = registers[*arg2].or(&registers[*arg3]);
-            }
-            OpCode::Not { value } => {
-                registers[*value] = registers[*value].not();
-            }
-            OpCode::Concat { arg1, arg2, arg3 } => {
-                registers[*arg1] = registers[*arg2].concat(&registers[*arg3]);
-            }
-            OpCode::Load { arg1, arg2 } => {
-                registers[*arg1] = variables.get(&arg2).unwrap().clone();
-            }
-            OpCode::LoadIntConst { arg1, arg2 } => {
-                registers[*arg1] = Value::Int(*arg2);
-            }
-            OpCode::LoadFloatConst { arg1, arg2 } => {
-                registers[*arg1] = Value::Float(*arg2);
-            }
-            OpCode::LoadStringConst { arg1, arg2 } => {
-                registers[*arg1] = Value::String(arg2.clone());
-            }
-            OpCode::Move { arg1, arg2 } => {
-                registers[*arg1] = registers[*arg2].to_owned();
-            }
-            OpCode::Store { arg1, arg2 } => {
-                variables.insert(*arg1, registers[*arg2].to_owned());
-            }
-            OpCode::StoreIntConst { arg1, arg2 } => {
-                variables.insert(*arg1, Value::Int(*arg2));
-            }
-            OpCode::StoreFloatConst { arg1, arg2 } => {
-                variables.insert(*arg1, Value::Float(*arg2));
+// The benches/ directory of this repository, so `bee bench` finds it from
+// anywhere when run from a build of it
+const BENCH_DIRECTORY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/benches");
+
+// bee bench [-O0 | -O1] [--save] [--dir <directory>] [files...]
+// Runs each benchmark, every .bee file in the bench directory by default,
+// and reports the op codes it executes, the allocations it makes and its
+// speed. The exit code is 1 if the op codes or allocations of any benchmark
+// went up from its baseline in baselines.json in the bench directory. With
+// --save the baselines are updated to this run instead.
+//
+// The benchmarks cover arithmetic, logic, spills and strings. Recursion,
+// list building and closures wait until the language has calls, lists and
+// closures, see todo.
+fn bench_command(args: &[String]) -> i32 {
+    let (opt_level, args) = opt_level_flag(args);
+    let mut save = false;
+    let mut directory = std::path::PathBuf::from(BENCH_DIRECTORY);
+    let mut paths = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--save" => save = true,
+            "--dir" => match args.next() {
+                Some(dir) => directory = dir.into(),
+                None => {
+                    eprintln!(
+                        "usage: bee bench [-O0 | -O1] [--save] [--dir <directory>] [files...]"
+                    );
+                    return 1;
+                }
+            },
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        let entries = match std::fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(error) => {
+                eprintln!("error: {}: {}", directory.display(), error);
+                return 1;
             }
-            OpCode::StoreStringConst { arg1, arg2 } => {
-                variables.insert(*arg1, Value::String(arg2.clone()));
+        };
+        paths = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "bee"))
+            .map(|path| path.display().to_string())
+            .collect();
+        paths.sort();
+    }
+
+    let baselines_path = directory.join("baselines.json");
+    let mut baselines = match std::fs::read_to_string(&baselines_path) {
+        Ok(text) => match bench::parse_baselines(&text) {
+            Ok(baselines) => baselines,
+            Err(error) => {
+                eprintln!("error: {}: {}", baselines_path.display(), error);
+                return 1;
             }
-            OpCode::Print { arg1 } => {
-                println!("{:?}", registers[*arg1]);
+        },
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Default::default(),
+        Err(error) => {
+            eprintln!("error: {}: {}", baselines_path.display(), error);
+            return 1;
+        }
+    };
+
+    let mut status = 0;
+    println!(
+        "{:<24} {:>10} {:>12} {:>14}",
+        "benchmark", "op codes", "allocations", "op codes/s"
+    );
+    for path in &paths {
+        let stem = std::path::Path::new(path)
+            .file_stem()
+            .map_or(path.clone(), |stem| stem.to_string_lossy().into_owned());
+        // Optimization changes the counts, so each level has its own baseline
+        let name = format!("{} {}", stem, opt_level.flag());
+        let result = load_program(path, opt_level).and_then(|program| {
+            verify::verify(&program).map_err(|error| error.to_string())?;
+            bench::measure(&program, Duration::from_millis(200)).map_err(|error| error.to_string())
+        });
+        let measurement = match result {
+            Ok(measurement) => measurement,
+            Err(error) => {
+                eprintln!("error: {}: {}", path, error);
+                status = 1;
+                continue;
             }
-            OpCode::Halt => {
-                break;
+        };
+        println!("{:<24} {}", name, measurement);
+        if save {
+            baselines.insert(name, (&measurement).into());
+        } else if let Some(baseline) = baselines.get(&name) {
+            let regressions = baseline.regressions(&measurement);
+            if !regressions.is_empty() {
+                eprintln!("regression: {}: {}", name, regressions.join(", "));
+                status = 1;
             }
         }
-        pc += 1;
     }
+    if save {
+        if let Err(error) = std::fs::write(&baselines_path, bench::format_baselines(&baselines)) {
+            eprintln!("error: {}: {}", baselines_path.display(), error);
+            return 1;
+        }
+    }
+    status
 }
 
-pub fn analyze_document(document: Document, symbol_table: &mut SymbolTable) {
-    for constant in document.constants {
-        symbol_table.constants.push(Constant {
-            name: constant.name.clone(),
-            type_annot: constant.type_annot.clone(),
-            value: match constant.value {
-                AbstractSyntaxTree::Int { value } => value.to_string(),
-                AbstractSyntaxTree::Float { value } => value.to_string(),
-                AbstractSyntaxTree::String { value } => value.clone(),
-                AbstractSyntaxTree::UpName { name } => {
-                    if name == "True" {
-                        "1".to_owned()
-                    } else if name == "False" {
-                        "0".to_owned()
-                    } else {
-                        panic!("Invalid value")
-                    }
-                }
-                _ => panic!("Invalid value"),
-            },
-        });
+// The optimization level only applies to source files
+fn load_program(path: &str, opt_level: OptLevel) -> Result<Program, String> {
+    let bytes = std::fs::read(path).map_err(|error| error.to_string())?;
+    if path.ends_with(".beec") {
+        bytecode::decode(&bytes).map_err(|error| error.to_string())
+    } else if path.ends_with(".beeasm") {
+        let source =
+            String::from_utf8(bytes).map_err(|_| "assembly file is not valid UTF-8".to_owned())?;
+        asm::assemble_program(&source).map_err(|diagnostic| diagnostic.to_string())
+    } else {
+        let source =
+            String::from_utf8(bytes).map_err(|_| "source file is not valid UTF-8".to_owned())?;
+        compile_source_with_host_functions(&source, Vec::new(), opt_level)
+            .map_err(|diagnostic| diagnostic.to_string())
     }
-    for function in document.functions {
-        symbol_table.functions.push(Function {
-            name: function.name.clone(),
-            variables: Vec::new(),
-        });
-        for statement in function.body {
-            analyze(statement, symbol_table);
+}
+
+fn run_example() {
+    let contents = r#"
+    const ctest: Integer = 5
+    
+    fn main() {
+      let one: Integer = 1
+      let one_again: Integer = one
+      let five: Integer = add(one, 4)
+      let itest: Integer = sub(five, 4)
+      let ctest_add: Integer = add(1, ctest)
+      print_integer(itest)
+      print_integer(ctest_add)
+    }"#;
+    let tokens = lex(contents.to_string());
+    let document = match parse(tokens) {
+        Ok(document) => document,
+        Err(diagnostic) => {
+            eprintln!("error: {}", diagnostic);
+            std::process::exit(1);
         }
+    };
+    let mut symbol_table = SymbolTable {
+        functions: Vec::new(),
+        constants: Vec::new(),
+        host_functions: Vec::new(),
+    };
+    if let Err(diagnostic) = analyze_document(document.clone(), &mut symbol_table) {
+        eprintln!("error: {}", diagnostic);
+        std::process::exit(1);
     }
-}
+    println!("Symbol Table:");
+    println!("{:?}", symbol_table);
 
-pub fn analyze(ast: AbstractSyntaxTree, symbol_table: &mut SymbolTable) {
-    match ast {
-        AbstractSyntaxTree::Let {
-            name,
-            type_annot,
-            value,
-        } => {
-            match *value {
-                AbstractSyntaxTree::Int { .. } => {
-                    if type_annot != "Integer" {
-                        panic!("Type mismatch");
-                    }
-                }
-                AbstractSyntaxTree::Float { .. } => {
-                    if type_annot != "Float" {
-                        panic!("Type mismatch");
-                    }
-                }
-                AbstractSyntaxTree::String { .. } => {
-                    if type_annot != "String" {
-                        panic!("Type mismatch");
-                    }
-                }
-                AbstractSyntaxTree::UpName { name } => {
-                    if type_annot == "Bool" && (name == "True" || name == "False") {
-                    } else {
-                        panic!("Type mismatch");
-                    }
-                }
-                AbstractSyntaxTree::Name { name } => {
-                    match symbol_table.constants.iter().find(|v| v.name == name) {
-                        Some(v) => {
-                            if v.type_annot != type_annot {
-                                panic!("Type mismatch");
-                            }
-                        }
-                        None => {
-                            let variable = symbol_table
-                                .functions
-                                .last()
-                                .unwrap()
-                                .variables
-                                .iter()
-                                .find(|v| v.name == name);
-                            match variable {
-                                Some(v) => {
-                                    if v.type_annot != type_annot {
-                                        panic!("Type mismatch");
-                                    }
-                                }
-                                None => panic!("Variable not found"),
-                            }
-                        }
-                    }
-                }
-                AbstractSyntaxTree::Call { name, args } => match name.as_str() {
-                    "add" | "sub" => {
-                        for arg in args {
-                            match arg {
-                                AbstractSyntaxTree::Int { .. } => {
-                                    if type_annot != "Integer" {
-                                        panic!("Type mismatch");
-                                    }
-                                }
-                                AbstractSyntaxTree::Name { name } => {
-                                    match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                        Some(v) => {
-                                            if v.type_annot != "Integer" {
-                                                panic!("Type mismatch");
-                                            }
-                                        }
-                                        None => {
-                                            let variable = symbol_table
-                                                .functions
-                                                .last()
-                                                .unwrap()
-                                                .variables
-                                                .iter()
-                                                .find(|v| v.name == *name);
-                                            match variable {
-                                                Some(v) => {
-                                                    if v.type_annot != "Integer" {
-                                                        panic!("Type mismatch");
-                                                    }
-                                                }
-                                                None => panic!("Variable not found"),
-                                            }
-                                        }
-                                    }
-                                }
-                                _ => panic!("Invalid value"),
-                            }
-                        }
-                    }
-                    "add_float" | "sub_float" => {
-                        for arg in args {
-                            match arg {
-                                AbstractSyntaxTree::Float { .. } => {
-                                    if type_annot != "Float" {
-                                        panic!("Type mismatch");
-                                    }
-                                }
-                                AbstractSyntaxTree::Name { name } => {
-                                    match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                        Some(v) => {
-                                            if v.type_annot != "Float" {
-                                                panic!("Type mismatch");
-                                            }
-                                        }
-                                        None => {
-                                            let variable = symbol_table
-                                                .functions
-                                                .last()
-                                                .unwrap()
-                                                .variables
-                                                .iter()
-                                                .find(|v| v.name == *name);
-                                            match variable {
-                                                Some(v) => {
-                                                    if v.type_annot != "Float" {
-                                                        panic!("Type mismatch");
-                                                    }
-                                                }
-                                                None => panic!("Variable not found"),
-                                            }
-                                        }
-                                    }
-                                }
-                                _ => panic!("Invalid value"),
-                            }
-                        }
-                    }
-                    "and" | "or" => {
-                        for arg in args {
-                            match arg {
-                                AbstractSyntaxTree::UpName { .. } => {
-                                    if type_annot != "Bool" {
-                                        panic!("Type mismatch");
-                                    }
-                                }
-                                AbstractSyntaxTree::Name { name } => {
-                                    match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                        Some(v) => {
-                                            if v.type_annot != "Bool" {
-                                                panic!("Type mismatch");
-                                            }
-                                        }
-                                        None => {
-                                            let variable = symbol_table
-                                                .functions
-                                                .last()
-                                                .unwrap()
-                                                .variables
-                                                .iter()
-                                                .find(|v| v.name == *name);
-                                            match variable {
-                                                Some(v) => {
-                                                    if v.type_annot != "Bool" {
-                                                        panic!("Type mismatch");
-                                                    }
-                                                }
-                                                None => panic!("Variable not found"),
-                                            }
-                                        }
-                                    }
-                                }
-                                _ => panic!("Invalid value"),
-                            }
-                        }
-                    }
-                    "not" => {
-                        for arg in args {
-                            match arg {
-                                AbstractSyntaxTree::UpName { .. } => {
-                                    if type_annot != "Bool" {
-                                        panic!("Type mismatch");
-                                    }
-                                }
-                                AbstractSyntaxTree::Name { name } => {
-                                    match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                        Some(v) => {
-                                            if v.type_annot != "Bool" {
-                                                panic!("Type mismatch");
-                                            }
-                                        }
-                                        None => {
-                                            let variable = symbol_table
-                                                .functions
-                                                .last()
-                                                .unwrap()
-                                                .variables
-                                                .iter()
-                                                .find(|v| v.name == *name);
-                                            match variable {
-                                                Some(v) => {
-                                                    if v.type_annot != "Bool" {
-                                                        panic!("Type mismatch");
-                                                    }
-                                                }
-                                                None => panic!("Variable not found"),
-                                            }
-                                        }
-                                    }
-                                }
-                                _ => panic!("Invalid value"),
-                            }
-                        }
-                    }
-                    "concat" => {
-                        for arg in args {
-                            match arg {
-                                AbstractSyntaxTree::String { .. } => {
-                                    if type_annot != "String" {
-                                        panic!("Type mismatch");
-                                    }
-                                }
-                                AbstractSyntaxTree::Name { name } => {
-                                    match symbol_table.constants.iter().find(|v| v.name == *name) {
-                                        Some(v) => {
-                                            if v.type_annot != "String" {
-                                                panic!("Type mismatch");
-                                            }
-                                        }
-                                        None => {
-                                            let variable = symbol_table
-                                                .functions
-                                                .last()
-                                                .unwrap()
-                                                .variables
-                                                .iter()
-                                                .find(|v| v.name == *name);
-                                            match variable {
-                                                Some(v) => {
-                                                    if v.type_annot != "String" {
-                                                        panic!("Type mismatch");
-                                                    }
-                                                }
-                                                None => panic!("Variable not found"),
-                                            }
-                                        }
-                                    }
-                                }
-                                _ => panic!("Invalid value"),
-                            }
-                        }
-                    }
-                    _ => {
-                        let function = symbol_table.functions.iter().find(|f| f.name == name);
-                        match function {
-                            Some(f) => {
-                                for (i, arg) in args.iter().enumerate() {
-                                    match arg {
-                                        AbstractSyntaxTree::Int { .. } => {
-                                            if f.variables[i].type_annot != "Integer" {
-                                                panic!("Type mismatch");
-                                            }
-                                        }
-                                        AbstractSyntaxTree::String { .. } => {
-                                            if f.variables[i].type_annot != "String" {
-                                                panic!("Type mismatch");
-                                            }
-                                        }
-                                        AbstractSyntaxTree::UpName { .. } => {
-                                            if f.variables[i].type_annot != "Bool" {
-                                                panic!("Type mismatch");
-                                            }
-                                        }
-                                        AbstractSyntaxTree::Name { name } => match symbol_table
-                                            .constants
-                                            .iter()
-                                            .find(|v| v.name == *name)
-                                        {
-                                            Some(v) => {
-                                                if v.type_annot != f.variables[i].type_annot {
-                                                    panic!("Type mismatch");
-                                                }
-                                            }
-                                            None => {
-                                                let variable = symbol_table
-                                                    .functions
-                                                    .last()
-                                                    .unwrap()
-                                                    .variables
-                                                    .iter()
-                                                    .find(|v| v.name == *name);
-                                                match variable {
-                                                    Some(v) => {
-                                                        if v.type_annot != f.variables[i].type_annot
-                                                        {
-                                                            panic!("Type mismatch");
-                                                        }
-                                                    }
-                                                    None => panic!("Variable not found"),
-                                                }
-                                            }
-                                        },
-                                        AbstractSyntaxTree::Call { .. } => {
-                                            panic!("Cant handle nested function calls")
-                                        }
-                                        _ => panic!("Invalid value"),
-                                    }
-                                }
-                            }
-                            None => panic!("Function not found"),
-                        }
-                    }
-                },
-                some_value => {
-                    println!("{:?}", some_value);
-                    panic!("Invalid value")
-                }
-            }
+    let mut op_codes = Vec::new();
+    if let Err(diagnostic) = code_gen_document(document, &mut symbol_table, &mut op_codes) {
+        eprintln!("error: {}", diagnostic);
+        std::process::exit(1);
+    }
 
-            let mut new_function = symbol_table.functions.pop().expect("Function not found");
-            new_function.variables.push(Variable {
-                name: name.clone(),
-                type_annot: type_annot.clone(),
-            });
+    println!("Codegen Output:");
+    print!("{}", asm::disassemble(&op_codes));
 
-            symbol_table.functions.push(new_function);
-        }
-        AbstractSyntaxTree::Block { statements } => {
-            for statement in statements {
-                analyze(statement, symbol_table);
-            }
-        }
-        _ => {}
+    println!("Interpretation:");
+    if let Err(error) = interpret(&Program::from_op_codes(op_codes), &RunOptions::default()) {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use code_gen::code_gen_document;
-    use lex::lex;
-    use parse::parse;
+    use bee::lex::lex;
+    use bee::parse::parse;
+    use bee::vm::interpret;
+
     #[test]
     fn int_const_sub_add_print() {
         let contents = r#"
@@ -589,19 +416,20 @@ mod tests {
                 print_integer(ctest_add)
             }"#;
         let tokens = lex(contents.to_string());
-        let document = parse(tokens);
+        let document = parse(tokens).unwrap();
         println!("Document:");
         println!("{:?}", document);
         let mut symbol_table = SymbolTable {
             functions: Vec::new(),
             constants: Vec::new(),
+            host_functions: Vec::new(),
         };
-        analyze_document(document.clone(), &mut symbol_table);
+        analyze_document(document.clone(), &mut symbol_table).unwrap();
         println!("Symbol Table:");
         println!("{:?}", symbol_table);
 
         let mut op_codes = Vec::new();
-        code_gen_document(document, &mut symbol_table, &mut op_codes);
+        code_gen_document(document, &mut symbol_table, &mut op_codes).unwrap();
 
         println!("Codegen Output:");
         for op_code in op_codes.clone() {
@@ -609,7 +437,7 @@ mod tests {
         }
 
         println!("Interpretation:");
-        interpret(op_codes);
+        interpret(&Program::from_op_codes(op_codes), &RunOptions::default()).unwrap();
     }
 
     #[test]
@@ -625,19 +453,20 @@ mod tests {
                 print_string(itest)
             }"#;
         let tokens = lex(contents.to_string());
-        let document = parse(tokens);
+        let document = parse(tokens).unwrap();
         println!("Document:");
         println!("{:?}", document);
         let mut symbol_table = SymbolTable {
             functions: Vec::new(),
             constants: Vec::new(),
+            host_functions: Vec::new(),
         };
-        analyze_document(document.clone(), &mut symbol_table);
+        analyze_document(document.clone(), &mut symbol_table).unwrap();
         println!("Symbol Table:");
         println!("{:?}", symbol_table);
 
         let mut op_codes = Vec::new();
-        code_gen_document(document, &mut symbol_table, &mut op_codes);
+        code_gen_document(document, &mut symbol_table, &mut op_codes).unwrap();
 
         println!("Codegen Output:");
         for op_code in op_codes.clone() {
@@ -645,7 +474,7 @@ mod tests {
         }
 
         println!("Interpretation:");
-        interpret(op_codes);
+        interpret(&Program::from_op_codes(op_codes), &RunOptions::default()).unwrap();
     }
 
     #[test]
@@ -662,19 +491,20 @@ mod tests {
                 print_bool(not_itest)
             }"#;
         let tokens = lex(contents.to_string());
-        let document = parse(tokens);
+        let document = parse(tokens).unwrap();
         println!("Document:");
         println!("{:?}", document);
         let mut symbol_table = SymbolTable {
             functions: Vec::new(),
             constants: Vec::new(),
+            host_functions: Vec::new(),
         };
-        analyze_document(document.clone(), &mut symbol_table);
+        analyze_document(document.clone(), &mut symbol_table).unwrap();
         println!("Symbol Table:");
         println!("{:?}", symbol_table);
 
         let mut op_codes = Vec::new();
-        code_gen_document(document, &mut symbol_table, &mut op_codes);
+        code_gen_document(document, &mut symbol_table, &mut op_codes).unwrap();
 
         println!("Codegen Output:");
         for op_code in op_codes.clone() {
@@ -682,7 +512,7 @@ mod tests {
         }
 
         println!("Interpretation:");
-        interpret(op_codes);
+        interpret(&Program::from_op_codes(op_codes), &RunOptions::default()).unwrap();
     }
 
     #[test]
@@ -700,19 +530,20 @@ mod tests {
                 print_float(ctest_add)
             }"#;
         let tokens = lex(contents.to_string());
-        let document = parse(tokens);
+        let document = parse(tokens).unwrap();
         println!("Document:");
         println!("{:?}", document);
         let mut symbol_table = SymbolTable {
             functions: Vec::new(),
             constants: Vec::new(),
+            host_functions: Vec::new(),
         };
-        analyze_document(document.clone(), &mut symbol_table);
+        analyze_document(document.clone(), &mut symbol_table).unwrap();
         println!("Symbol Table:");
         println!("{:?}", symbol_table);
 
         let mut op_codes = Vec::new();
-        code_gen_document(document, &mut symbol_table, &mut op_codes);
+        code_gen_document(document, &mut symbol_table, &mut op_codes).unwrap();
 
         println!("Codegen Output:");
         for op_code in op_codes.clone() {
@@ -720,6 +551,6 @@ mod tests {
         }
 
         println!("Interpretation:");
-        interpret(op_codes);
+        interpret(&Program::from_op_codes(op_codes), &RunOptions::default()).unwrap();
     }
 }
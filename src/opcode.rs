@@ -1,5 +1,6 @@
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum OpCode {
+    // Not emitted by code_gen, which short-circuits and/or with jumps
     And {
         arg1: usize,
         arg2: usize,
@@ -44,6 +45,11 @@ pub enum OpCode {
         arg1: usize,
         arg2: Box<str>,
     },
+    LoadBoolConst {
+        arg1: usize,
+        arg2: bool,
+    },
+    // Emitted by the peephole pass in place of reloading a stored register
     Move {
         arg1: usize,
         arg2: usize,
@@ -64,8 +70,34 @@ pub enum OpCode {
         arg1: usize,
         arg2: Box<str>,
     },
+    StoreBoolConst {
+        arg1: usize,
+        arg2: bool,
+    },
     Print {
         arg1: usize,
     },
+    // Continues at the op code with index target
+    Jump {
+        target: usize,
+    },
+    // Jump when the Bool in arg1 is false or true, otherwise continue with
+    // the next op code
+    JumpIfFalse {
+        arg1: usize,
+        target: usize,
+    },
+    JumpIfTrue {
+        arg1: usize,
+        target: usize,
+    },
+    // Calls the host function with the arg3 registers starting at arg2 and
+    // puts its result in arg1
+    CallHost {
+        name: Box<str>,
+        arg1: usize,
+        arg2: usize,
+        arg3: usize,
+    },
     Halt,
 }
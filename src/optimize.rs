@@ -0,0 +1,335 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::{BinaryOp, BlockId, Function, Instruction, Temp, Terminator};
+use crate::vm::{Operation, Value};
+
+// How much work to do between lowering and code generation, and on the op
+// codes afterwards (see peephole::optimize). At -O1, on the IR:
+//
+// - instructions whose operands are known are folded into their result,
+//   and loads of variables whose value is known into the value
+// - branches on a known Bool become jumps, which drops the second argument
+//   of and/or when the first decides them, and blocks that can no longer
+//   be reached are removed
+// - instructions whose result is never read are dropped when they have no
+//   effect and can't fail, as are stores to variables that are never loaded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OptLevel {
+    O0,
+    #[default]
+    O1,
+}
+
+impl OptLevel {
+    // Parses a command line flag such as -O1
+    pub fn from_flag(flag: &str) -> Option<Self> {
+        match flag {
+            "-O0" => Some(OptLevel::O0),
+            "-O1" => Some(OptLevel::O1),
+            _ => None,
+        }
+    }
+
+    pub fn flag(self) -> &'static str {
+        match self {
+            OptLevel::O0 => "-O0",
+            OptLevel::O1 => "-O1",
+        }
+    }
+}
+
+pub fn optimize_function(function: &mut Function) {
+    propagate(function);
+    remove_unreachable(function);
+    while remove_dead_code(function) {}
+}
+
+// The values temporaries and variables are known to hold at a point
+#[derive(Debug, Clone, Default)]
+struct Known {
+    temps: HashMap<Temp, Value>,
+    variables: HashMap<usize, Value>,
+}
+
+impl Known {
+    // What is known on every path into a block
+    fn meet(mut self, other: &Known) -> Known {
+        self.temps
+            .retain(|temp, value| other.temps.get(temp) == Some(value));
+        self.variables
+            .retain(|variable, value| other.variables.get(variable) == Some(value));
+        self
+    }
+}
+
+// Walks the blocks in layout order, so that a block's predecessors have been
+// seen before it unless control goes backwards, in which case nothing is
+// assumed on entry
+fn propagate(function: &mut Function) {
+    let mut exits: Vec<Option<Known>> = vec![None; function.blocks.len()];
+    for index in 0..function.blocks.len() {
+        let predecessors: Vec<usize> = function
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| block.terminator.successors().contains(&BlockId(index)))
+            .map(|(predecessor, _)| predecessor)
+            .collect();
+        let mut known = if index == 0 || predecessors.iter().any(|p| *p >= index) {
+            Known::default()
+        } else {
+            // Predecessors whose branches were folded away don't count
+            let mut entries = predecessors.iter().filter_map(|p| exits[*p].as_ref());
+            let Some(first) = entries.next() else {
+                continue;
+            };
+            entries.fold(first.clone(), Known::meet)
+        };
+
+        let block = &mut function.blocks[index];
+        for instruction in &mut block.instructions {
+            propagate_instruction(instruction, &mut known);
+        }
+        if let Terminator::Branch {
+            condition,
+            if_true,
+            if_false,
+        } = block.terminator
+        {
+            if let Some(Value::Bool(value)) = known.temps.get(&condition) {
+                block.terminator = Terminator::Jump(if *value { if_true } else { if_false });
+            }
+        }
+        exits[index] = Some(known);
+    }
+}
+
+// Errors such as overflow are left for the program to raise when it runs
+fn propagate_instruction(instruction: &mut Instruction, known: &mut Known) {
+    let value = match instruction {
+        Instruction::Const { value, .. } => Some(value.clone()),
+        Instruction::Load { variable, .. } => known.variables.get(variable).cloned(),
+        Instruction::Store { variable, value } => {
+            match known.temps.get(value) {
+                Some(value) => known.variables.insert(*variable, value.clone()),
+                None => known.variables.remove(variable),
+            };
+            return;
+        }
+        Instruction::Binary { op, lhs, rhs, .. } => {
+            match (known.temps.get(lhs), known.temps.get(rhs)) {
+                (Some(lhs), Some(rhs)) => match op {
+                    BinaryOp::Add => lhs.add(rhs),
+                    BinaryOp::Sub => lhs.sub(rhs),
+                    BinaryOp::Concat => lhs.concat(rhs),
+                }
+                .ok(),
+                _ => None,
+            }
+        }
+        Instruction::Not { value, .. } => known.temps.get(value).and_then(|v| v.not().ok()),
+        Instruction::CallHost { .. } => None,
+        Instruction::Print { .. } => return,
+    };
+    let Some(dest) = instruction.dest() else {
+        return;
+    };
+    match value {
+        Some(value) => {
+            known.temps.insert(dest, value.clone());
+            *instruction = Instruction::Const { dest, value };
+        }
+        None => {
+            known.temps.remove(&dest);
+        }
+    }
+}
+
+fn remove_unreachable(function: &mut Function) {
+    let mut reachable = vec![false; function.blocks.len()];
+    let mut pending = vec![BlockId(0)];
+    while let Some(id) = pending.pop() {
+        if !reachable[id.0] {
+            reachable[id.0] = true;
+            pending.extend(function.blocks[id.0].terminator.successors());
+        }
+    }
+    // The id each block that is kept ends up with
+    let mut ids = Vec::new();
+    let mut next = 0;
+    for kept in &reachable {
+        ids.push(BlockId(next));
+        if *kept {
+            next += 1;
+        }
+    }
+    let blocks = std::mem::take(&mut function.blocks);
+    for (mut block, kept) in blocks.into_iter().zip(reachable) {
+        if !kept {
+            continue;
+        }
+        match &mut block.terminator {
+            Terminator::Jump(target) => *target = ids[target.0],
+            Terminator::Branch {
+                if_true, if_false, ..
+            } => {
+                *if_true = ids[if_true.0];
+                *if_false = ids[if_false.0];
+            }
+            Terminator::Halt => {}
+        }
+        function.blocks.push(block);
+    }
+}
+
+// Returns whether anything was removed, as that can leave more to remove
+fn remove_dead_code(function: &mut Function) -> bool {
+    let loaded: HashSet<usize> = function
+        .blocks
+        .iter()
+        .flat_map(|block| &block.instructions)
+        .filter_map(|instruction| match instruction {
+            Instruction::Load { variable, .. } => Some(*variable),
+            _ => None,
+        })
+        .collect();
+    let live_in = function.live_in();
+    let mut changed = false;
+    for index in 0..function.blocks.len() {
+        let live_after = function.live_after(index, &live_in);
+        let block = &mut function.blocks[index];
+        // The position each instruction ends up at
+        let mut positions = Vec::new();
+        let mut kept = Vec::new();
+        for (instruction, live) in std::mem::take(&mut block.instructions)
+            .into_iter()
+            .zip(live_after)
+        {
+            positions.push(kept.len());
+            let dead = match &instruction {
+                Instruction::Store { variable, .. } => !loaded.contains(variable),
+                _ => instruction.dest().is_some_and(|dest| {
+                    !live.contains(&dest) && is_pure(&instruction, &function.temps)
+                }),
+            };
+            if dead {
+                changed = true;
+            } else {
+                kept.push(instruction);
+            }
+        }
+        positions.push(kept.len());
+
+        // Statements left without instructions are dropped from the lines
+        let mut lines: Vec<(usize, _)> = Vec::new();
+        for (start, span) in std::mem::take(&mut block.lines) {
+            let start = positions[start];
+            if lines.last().is_some_and(|(last, _)| *last == start) {
+                lines.pop();
+            }
+            if start < kept.len() {
+                lines.push((start, span));
+            }
+        }
+        block.lines = lines;
+        block.instructions = kept;
+    }
+    changed
+}
+
+// Integer arithmetic can overflow and host functions can do anything
+fn is_pure(instruction: &Instruction, temps: &[String]) -> bool {
+    match instruction {
+        Instruction::Const { .. } | Instruction::Load { .. } | Instruction::Not { .. } => true,
+        Instruction::Binary {
+            op: BinaryOp::Concat,
+            ..
+        } => true,
+        Instruction::Binary { dest, .. } => temps[dest.0] == "Float",
+        Instruction::Store { .. } | Instruction::CallHost { .. } | Instruction::Print { .. } => {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::optimize::OptLevel;
+    use crate::{asm, testing, verify, vm, Value};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const SOURCE: &str = r#"
+            const name: String = "bee"
+            fn main() {
+                let five: Integer = add(1, 4)
+                let six: Integer = add(five, 1)
+                let unused: Float = add_float(1.0, 2.0)
+                let greeting: String = concat(concat("hi", " "), name)
+                let skipped: Bool = and(False, check("and"))
+                let kept: Bool = or(False, check("or"))
+                print_integer(six)
+                print_string(greeting)
+                print_bool(and(not(False), kept))
+                // Left for the program to raise when it runs
+                let big: Integer = sub(0, 1)
+            }"#;
+
+    // SOURCE behaves the same at every level
+    fn run(opt_level: OptLevel) -> String {
+        let (mut engine, output) = testing::engine(opt_level);
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let log = calls.clone();
+        engine.register_fn("check", &["String"], "Bool", move |args| {
+            log.borrow_mut().push(args[0].clone());
+            Ok(Value::Bool(true))
+        });
+        let program = engine.compile(SOURCE).unwrap();
+        assert_eq!(verify::verify(&program), Ok(()));
+        let error = engine.run(&program).unwrap_err();
+        assert_eq!(error.kind, vm::ErrorKind::IntegerOverflow);
+        assert_eq!(error.span.map(|span| span.start.line), Some(13));
+        assert_eq!(*calls.borrow(), vec![Value::from("or")]);
+        assert_eq!(output.take(), "6\nhi bee\nTrue\n");
+        asm::disassemble(program.op_codes())
+    }
+
+    #[test]
+    fn optimizer_folds_constants_and_drops_dead_code() {
+        assert_eq!(
+            run(OptLevel::O1),
+            [
+                "load_string r1, \"or\"",
+                "call_host r1, \"check\", r1, 1",
+                "store s5, r1",
+                "load_int r1, 6",
+                "print r1",
+                "load_string r1, \"hi bee\"",
+                "print r1",
+                "load r1, s5",
+                "print r1",
+                "load_int r1, 0",
+                "load_int r2, 1",
+                "sub r1, r1, r2",
+                "halt",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn unoptimized_code_is_left_as_written() {
+        let unoptimized = run(OptLevel::O0);
+        assert!(unoptimized.contains("load_int r1, 1\nload_int r2, 4\nadd r1, r1, r2\n"));
+        assert!(unoptimized.contains("concat r1, r1, r2\n"));
+        assert!(unoptimized.contains("jump_if_false r1, "));
+    }
+
+    #[test]
+    fn opt_levels_come_from_flags() {
+        assert_eq!(OptLevel::from_flag("-O0"), Some(OptLevel::O0));
+        assert_eq!(OptLevel::from_flag("-O1"), Some(OptLevel::O1));
+        assert_eq!(OptLevel::from_flag("-O2"), None);
+    }
+}
@@ -1,82 +1,288 @@
-use crate::ast::{AbstractSyntaxTree, Constant, Document, Function};
+use crate::ast::{AbstractSyntaxTree, Constant, Document, External, Function};
+use crate::diagnostic::Diagnostic;
+use crate::span::Span;
 use crate::token::Token;
 
-pub fn parse(tokens: Vec<Token>) -> Document {
-    let mut tokens = tokens.iter().peekable();
+// Steps through the tokens while keeping track of where they came from
+struct Parser<'a> {
+    tokens: &'a [Token],
+    // Empty when the tokens weren't lexed with spans
+    spans: &'a [Span],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(tokens: &'a [Token], spans: &'a [Span]) -> Self {
+        Parser {
+            tokens,
+            spans,
+            position: 0,
+        }
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.position)?;
+        self.position += 1;
+        Some(token)
+    }
+
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<&'a Token> {
+        self.tokens.get(self.position + n)
+    }
+
+    // Index of the token most recently returned by next
+    fn last_index(&self) -> usize {
+        self.position.saturating_sub(1)
+    }
+
+    // Span from the token at `start` up to the most recently consumed token
+    fn span_from(&self, start: usize) -> Span {
+        let start = self.spans.get(start).copied().unwrap_or_default();
+        let end = self
+            .spans
+            .get(self.last_index())
+            .copied()
+            .unwrap_or_default();
+        Span {
+            start: start.start,
+            end: end.end,
+        }
+    }
+
+    // Errors are located at the token the parser stopped on
+    fn locate(&self, diagnostic: Diagnostic) -> Diagnostic {
+        let span = self.span_from(self.last_index());
+        diagnostic.with_span(span)
+    }
+}
+
+pub fn parse(tokens: Vec<Token>) -> Result<Document, Diagnostic> {
+    parse_with_spans(tokens, &[])
+}
+
+pub fn parse_with_spans(tokens: Vec<Token>, spans: &[Span]) -> Result<Document, Diagnostic> {
+    let mut parser = Parser::new(&tokens, spans);
+    parse_document(&mut parser).map_err(|diagnostic| parser.locate(diagnostic))
+}
+
+fn parse_document(tokens: &mut Parser) -> Result<Document, Diagnostic> {
     let mut functions = Vec::new();
     let mut constants = Vec::new();
+    let mut externals = Vec::new();
+    // Comments are attached to the definition that follows them
+    let mut comments = Vec::new();
     while let Some(token) = tokens.next() {
         match token {
+            Token::Comment { value, .. } => comments.push(value.clone()),
             Token::Const => {
                 let name = match tokens.next() {
                     Some(Token::Name { name }) => name,
-                    _ => panic!("Expected a name after const"),
+                    _ => return Err(Diagnostic::new("Expected a name after const")),
                 };
+                let span = tokens.span_from(tokens.last_index());
                 let type_annotation: String = match tokens.next() {
                     Some(Token::Colon) => match tokens.next() {
                         Some(Token::UpName { name }) => name.clone(),
-                        _ => panic!("Expected a type annotation after colon"),
+                        _ => return Err(Diagnostic::new("Expected a type annotation after colon")),
                     },
-                    _ => panic!("Expected a colon after value name"),
+                    _ => return Err(Diagnostic::new("Expected a colon after value name")),
                 };
                 match tokens.next() {
                     Some(Token::Equal) => {}
-                    _ => panic!("Expected an equal sign after type annotation"),
+                    _ => {
+                        return Err(Diagnostic::new(
+                            "Expected an equal sign after type annotation",
+                        ))
+                    }
                 }
-                let value = parse_expression(&mut tokens);
+                let value = parse_expression(tokens)?;
                 constants.push(Constant {
                     name: name.clone(),
                     type_annot: type_annotation.clone(),
                     value,
+                    comments: std::mem::take(&mut comments),
+                    trailing_comment: parse_trailing_comment(tokens),
+                    span,
                 });
             }
             Token::Fn => {
                 let name = match tokens.next() {
                     Some(Token::Name { name }) => name,
-                    _ => panic!("Expected a name after fn"),
+                    _ => return Err(Diagnostic::new("Expected a name after fn")),
                 };
+                let span = tokens.span_from(tokens.last_index());
                 let mut args = Vec::new();
                 match tokens.next() {
                     Some(Token::LeftParen) => {}
-                    _ => panic!("Expected a left paren after fn"),
+                    _ => return Err(Diagnostic::new("Expected a left paren after fn")),
                 }
                 while let Some(token) = tokens.next() {
                     match token {
                         Token::RightParen => break,
-                        Token::Name { name } => args.push(name),
-                        _ => panic!("Expected a name or right paren after left paren"),
+                        Token::Name { name } => args.push(name.clone()),
+                        Token::Comma => {}
+                        _ => {
+                            return Err(Diagnostic::new(
+                                "Expected a name or right paren after left paren",
+                            ))
+                        }
                     }
                 }
                 match tokens.next() {
                     Some(Token::LeftBrace) => {}
-                    _ => panic!("Expected a left brace after fn args"),
+                    _ => return Err(Diagnostic::new("Expected a left brace after fn args")),
                 }
-                let body = parse_fn_body(&mut tokens);
+                let body = parse_fn_body(tokens)?;
                 functions.push(Function {
                     name: name.clone(),
+                    args,
                     body,
+                    comments: std::mem::take(&mut comments),
+                    trailing_comment: parse_trailing_comment(tokens),
+                    span,
                 });
             }
-            _ => {
-                panic!("Unexpected token")
+            Token::At => {
+                let mut external = parse_external(tokens)?;
+                external.comments = std::mem::take(&mut comments);
+                external.trailing_comment = parse_trailing_comment(tokens);
+                externals.push(external);
             }
+            _ => return Err(Diagnostic::new("Unexpected token")),
         }
     }
-    Document {
+    Ok(Document {
         constants,
         functions,
+        externals,
+        comments,
+    })
+}
+
+// Parses the rest of `@external(rust, "module", "function")` and the
+// declaration that follows it
+fn parse_external(tokens: &mut Parser) -> Result<External, Diagnostic> {
+    match tokens.next() {
+        Some(Token::Name { name }) if name == "external" => {}
+        Some(Token::Name { name }) => {
+            return Err(Diagnostic::new(format!("Unknown attribute: {}", name)))
+        }
+        _ => return Err(Diagnostic::new("Expected an attribute name after @")),
+    }
+    match tokens.next() {
+        Some(Token::LeftParen) => {}
+        _ => return Err(Diagnostic::new("Expected a left paren after external")),
+    }
+    let target = match tokens.next() {
+        Some(Token::Name { name }) => name.clone(),
+        _ => return Err(Diagnostic::new("Expected a target such as rust")),
+    };
+    let mut strings = Vec::new();
+    for _ in 0..2 {
+        match (tokens.next(), tokens.next()) {
+            (Some(Token::Comma), Some(Token::String { value })) => strings.push(value.clone()),
+            _ => {
+                return Err(Diagnostic::new(
+                    "Expected a module and function name after the target",
+                ))
+            }
+        }
+    }
+    match tokens.next() {
+        Some(Token::RightParen) => {}
+        _ => {
+            return Err(Diagnostic::new(
+                "Expected a right paren after the function name",
+            ))
+        }
+    }
+    match tokens.next() {
+        Some(Token::Fn) => {}
+        _ => return Err(Diagnostic::new("Expected a fn after an external attribute")),
+    }
+    let name = match tokens.next() {
+        Some(Token::Name { name }) => name.clone(),
+        _ => return Err(Diagnostic::new("Expected a name after fn")),
+    };
+    let span = tokens.span_from(tokens.last_index());
+    match tokens.next() {
+        Some(Token::LeftParen) => {}
+        _ => return Err(Diagnostic::new("Expected a left paren after fn")),
+    }
+    let mut params = Vec::new();
+    loop {
+        match tokens.next() {
+            Some(Token::RightParen) => break,
+            Some(Token::Comma) => {}
+            Some(Token::Name { name }) => match (tokens.next(), tokens.next()) {
+                (Some(Token::Colon), Some(Token::UpName { name: type_annot })) => {
+                    params.push((name.clone(), type_annot.clone()))
+                }
+                _ => {
+                    return Err(Diagnostic::new(
+                        "External function parameters need a type annotation",
+                    ))
+                }
+            },
+            _ => {
+                return Err(Diagnostic::new(
+                    "Expected a name or right paren after left paren",
+                ))
+            }
+        }
+    }
+    let returns = match (tokens.next(), tokens.next()) {
+        (Some(Token::RightArrow), Some(Token::UpName { name })) => name.clone(),
+        _ => {
+            return Err(Diagnostic::new(
+                "External functions need a return type after ->",
+            ))
+        }
+    };
+    if let Some(Token::LeftBrace) = tokens.peek() {
+        tokens.next();
+        return Err(Diagnostic::new("External functions can't have a body"));
     }
+    let [module, function] = <[String; 2]>::try_from(strings).unwrap();
+    Ok(External {
+        name,
+        params,
+        returns,
+        target,
+        module,
+        function,
+        comments: Vec::new(),
+        trailing_comment: None,
+        span,
+    })
 }
 
-fn parse_expression(
-    tokens: &mut std::iter::Peekable<std::slice::Iter<Token>>,
-) -> AbstractSyntaxTree {
+fn parse_trailing_comment(tokens: &mut Parser) -> Option<String> {
+    match tokens.peek() {
+        Some(Token::Comment {
+            value,
+            trailing: true,
+        }) => {
+            tokens.next();
+            Some(value.clone())
+        }
+        _ => None,
+    }
+}
+
+fn parse_expression(tokens: &mut Parser) -> Result<AbstractSyntaxTree, Diagnostic> {
     let ast = match tokens.next() {
         Some(Token::Int { value }) => AbstractSyntaxTree::Int {
-            value: value.parse().unwrap(),
+            value: parse_int(value)?,
+            literal: value.clone(),
         },
         Some(Token::Float { value }) => AbstractSyntaxTree::Float {
-            value: value.parse().unwrap(),
+            value: parse_float(value)?,
+            literal: value.clone(),
         },
         Some(Token::String { value }) => AbstractSyntaxTree::String {
             value: value.clone(),
@@ -85,30 +291,23 @@ fn parse_expression(
             //could be a function call or a variable
             match tokens.peek() {
                 Some(Token::LeftParen) => {
+                    let start = tokens.last_index();
                     tokens.next();
-                    let mut args = Vec::new();
-                    while let Some(token) = tokens.peek() {
-                        match token {
-                            Token::RightParen => {
-                                tokens.next();
-                                break;
-                            }
-                            _ => {
-                                let arg = parse_expression(tokens);
-                                args.push(arg);
-                            }
-                        }
-                    }
+                    let args = parse_call_args(tokens)?;
                     AbstractSyntaxTree::Call {
                         name: name.clone(),
                         args,
+                        span: tokens.span_from(start),
                     }
                 }
                 _ => AbstractSyntaxTree::Name { name: name.clone() },
             }
         }
+        Some(Token::UpName { .. }) if tokens.peek() == Some(&Token::LeftParen) => {
+            return Err(Diagnostic::new("Records are not supported yet"))
+        }
         Some(Token::UpName { name }) => AbstractSyntaxTree::UpName { name: name.clone() },
-        Some(Token::LeftParen) => parse_expression(tokens),
+        Some(Token::LeftParen) => parse_expression(tokens)?,
         Some(Token::LeftBrace) => {
             let mut statements = Vec::new();
             while let Some(token) = tokens.next() {
@@ -116,80 +315,210 @@ fn parse_expression(
                     Token::RightBrace => break,
                     _ => {
                         tokens.next();
-                        let statement = parse_expression(tokens);
+                        let statement = parse_expression(tokens)?;
                         statements.push(statement);
                     }
                 }
             }
             AbstractSyntaxTree::Block { statements }
         }
-        Some(Token::Comma) => parse_expression(tokens),
+        Some(Token::Comma) => parse_expression(tokens)?,
+        Some(Token::Comment { .. }) => parse_expression(tokens)?,
         Some(token) => {
-            println!("{:?}", tokens);
-            panic!("Unexpected token: {:?}", token)
+            let message = token
+                .error()
+                .unwrap_or_else(|| format!("Unexpected token: {:?}", token));
+            return Err(Diagnostic::new(message));
         }
-        None => panic!("Unexpected end of input"),
+        None => return Err(Diagnostic::new("Unexpected end of input")),
     };
-    ast
+    Ok(ast)
 }
 
-fn parse_fn_body(
-    tokens: &mut std::iter::Peekable<std::slice::Iter<Token>>,
-) -> Vec<AbstractSyntaxTree> {
+// Parses the arguments of a call up to and including the closing paren
+fn parse_call_args(tokens: &mut Parser) -> Result<Vec<AbstractSyntaxTree>, Diagnostic> {
+    let mut args = Vec::new();
+    while let Some(token) = tokens.peek() {
+        match token {
+            Token::RightParen => {
+                tokens.next();
+                return Ok(args);
+            }
+            Token::Comma | Token::Comment { .. } => {
+                tokens.next();
+            }
+            _ => {
+                let arg = parse_expression(tokens)?;
+                args.push(arg);
+            }
+        }
+    }
+    Err(Diagnostic::new(
+        "Expected a right paren after function args",
+    ))
+}
+
+// Parses statements on their own, or a single expression, as typed into
+// the repl
+pub fn parse_statements(
+    tokens: Vec<Token>,
+    spans: &[Span],
+) -> Result<Vec<AbstractSyntaxTree>, Diagnostic> {
+    let mut parser = Parser::new(&tokens, spans);
+    parse_repl_input(&mut parser).map_err(|diagnostic| parser.locate(diagnostic))
+}
+
+fn parse_repl_input(tokens: &mut Parser) -> Result<Vec<AbstractSyntaxTree>, Diagnostic> {
+    let is_statement = match tokens.peek() {
+        Some(Token::Let | Token::Comment { .. }) => true,
+        Some(Token::Name { .. }) => matches!(tokens.peek_nth(1), Some(Token::LeftParen)),
+        _ => false,
+    };
+    let statements = if is_statement {
+        parse_fn_body(tokens)?
+    } else {
+        vec![parse_expression(tokens)?]
+    };
+    match tokens.next() {
+        None => Ok(statements),
+        Some(token) => Err(Diagnostic::new(format!("Unexpected token: {:?}", token))),
+    }
+}
+
+fn parse_fn_body(tokens: &mut Parser) -> Result<Vec<AbstractSyntaxTree>, Diagnostic> {
     let mut statements = Vec::new();
     while let Some(token) = tokens.next() {
         match token {
             Token::RightBrace => break,
+            Token::Comment { value, trailing } => statements.push(AbstractSyntaxTree::Comment {
+                value: value.clone(),
+                trailing: *trailing,
+            }),
             Token::Name { name } => match tokens.next() {
                 Some(Token::LeftParen) => {
-                    let arg = parse_expression(tokens);
-                    match tokens.next() {
-                        Some(Token::RightParen) => {}
-                        _ => panic!("Expected a right paren after function args"),
-                    }
+                    let start = tokens.last_index() - 1;
+                    let args = parse_call_args(tokens)?;
                     statements.push(AbstractSyntaxTree::Call {
                         name: name.clone(),
-                        args: vec![arg],
+                        args,
+                        span: tokens.span_from(start),
                     });
                 }
                 Some(token) => {
-                    println!("{:?}", statements);
-                    panic!("Unexpected token: {:?}", token)
-                }
-                _ => {
-                    println!("{:?}", statements);
-                    panic!("Expected a left paren after name")
+                    return Err(Diagnostic::new(format!("Unexpected token: {:?}", token)))
                 }
+                _ => return Err(Diagnostic::new("Expected a left paren after name")),
             },
             Token::Let => {
+                let start = tokens.last_index();
                 let name = match tokens.next() {
                     Some(Token::Name { name }) => name,
-                    _ => panic!("Expected a name after let"),
+                    _ => return Err(Diagnostic::new("Expected a name after let")),
                 };
                 let type_annotation: String = match tokens.next() {
                     Some(Token::Colon) => match tokens.next() {
                         Some(Token::UpName { name }) => name.clone(),
-                        _ => panic!("Expected a type annotation after colon"),
+                        _ => return Err(Diagnostic::new("Expected a type annotation after colon")),
                     },
-                    _ => panic!("Expected a colon after value name"),
+                    _ => return Err(Diagnostic::new("Expected a colon after value name")),
                 };
                 match tokens.next() {
                     Some(Token::Equal) => {}
-                    _ => panic!("Expected an equal sign after type annotation"),
+                    _ => {
+                        return Err(Diagnostic::new(
+                            "Expected an equal sign after type annotation",
+                        ))
+                    }
                 }
-                let value = parse_expression(tokens);
+                let value = parse_expression(tokens)?;
                 statements.push(AbstractSyntaxTree::Let {
                     name: name.clone(),
                     value: Box::new(value),
                     type_annot: type_annotation.clone(),
+                    span: tokens.span_from(start),
                 });
             }
-            _ => {
-                println!("{:?}", statements);
-                println!("{:?}", token);
-                panic!("Unexpected token")
-            }
+            _ => return Err(Diagnostic::new("Unexpected token")),
         }
     }
-    statements
+    Ok(statements)
+}
+
+// Number tokens hold the literal as written, including any radix prefix
+// and underscores
+pub fn parse_int(value: &str) -> Result<usize, Diagnostic> {
+    let digits = value.replace('_', "");
+    let (digits, radix) = match digits.get(..2) {
+        Some("0x" | "0X") => (&digits[2..], 16),
+        Some("0o" | "0O") => (&digits[2..], 8),
+        Some("0b" | "0B") => (&digits[2..], 2),
+        _ => (digits.as_str(), 10),
+    };
+    usize::from_str_radix(digits, radix).map_err(|_| {
+        Diagnostic::new(format!(
+            "Integer literal {} is out of range, the maximum is {}",
+            value,
+            usize::MAX
+        ))
+    })
+}
+
+pub fn parse_float(value: &str) -> Result<f64, Diagnostic> {
+    match value.replace('_', "").parse::<f64>() {
+        Ok(float) if float.is_finite() => Ok(float),
+        _ => Err(Diagnostic::new(format!(
+            "Float literal {} is out of range",
+            value
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::AbstractSyntaxTree;
+    use crate::lex::lex;
+    use crate::parse::parse;
+
+    #[test]
+    fn number_literal_values() {
+        let document = parse(lex(r#"
+            const a: Integer = 0xFF
+            const b: Integer = 0o17
+            const c: Integer = 0b1010
+            const d: Integer = 1_000_000
+            const e: Float = 1.5e-3"#
+            .to_string()))
+        .unwrap();
+        let values: Vec<String> = document
+            .constants
+            .iter()
+            .map(|constant| match &constant.value {
+                AbstractSyntaxTree::Int { value, .. } => value.to_string(),
+                AbstractSyntaxTree::Float { value, .. } => value.to_string(),
+                value => unreachable!("Unexpected constant value: {:?}", value),
+            })
+            .collect();
+        assert_eq!(values, vec!["255", "15", "10", "1000000", "0.0015"]);
+    }
+
+    #[test]
+    fn number_literals_out_of_range() {
+        let diagnostic = parse(lex(
+            "const big: Integer = 0xFFFF_FFFF_FFFF_FFFF_F".to_string()
+        ))
+        .unwrap_err();
+        assert!(diagnostic.message.contains("out of range"));
+        let diagnostic = parse(lex("const big: Float = 1e400".to_string())).unwrap_err();
+        assert!(diagnostic.message.contains("out of range"));
+    }
+
+    #[test]
+    fn external_declarations_need_attributes_and_a_return_type() {
+        let error = |source: &str| parse(lex(source.to_string())).unwrap_err().message;
+        assert_eq!(
+            error("@external(rust, \"m\", \"f\")\nfn f()\nfn main() {\n}"),
+            "External functions need a return type after ->"
+        );
+        assert_eq!(error("@inline\nfn f() {\n}"), "Unknown attribute: inline");
+    }
 }
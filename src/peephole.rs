@@ -0,0 +1,443 @@
+use std::collections::HashSet;
+
+use crate::bytecode::Program;
+use crate::opcode::OpCode;
+
+// Rewrites short sequences of op codes into cheaper ones that behave the
+// same, until there is nothing left to rewrite:
+//
+// - a constant loaded into a register only to be stored becomes a store of
+//   the constant
+// - a load straight after a store of the same slot reuses the register
+// - a store straight after a load of the same slot is dropped
+// - stores to slots that are never loaded are dropped
+// - jumps to jumps go straight to where the chain ends, and jumps to the
+//   next op code are dropped
+//
+// Constants and dead code are dealt with on the IR (see optimize); these
+// rewrites are about registers and spill slots, which only exist once code
+// has been generated. Jump targets, function starts and the line table are
+// moved along with the op codes. Runtime errors report the pc of the
+// optimized code.
+pub fn optimize(program: &mut Program) {
+    loop {
+        let mut removed = vec![false; program.op_codes().len()];
+        let mut changed = collapse_jumps(program.op_codes_mut(), &mut removed);
+        changed |= fuse(program.op_codes_mut(), &mut removed);
+        changed |= remove_dead_stores(program.op_codes(), &mut removed);
+        if !changed {
+            return;
+        }
+        compact(program, &removed);
+    }
+}
+
+fn collapse_jumps(op_codes: &mut [OpCode], removed: &mut [bool]) -> bool {
+    let mut changed = false;
+    for pc in 0..op_codes.len() {
+        let (target, register, when) = match &op_codes[pc] {
+            OpCode::Jump { target } => (*target, None, false),
+            OpCode::JumpIfFalse { arg1, target } => (*target, Some(*arg1), false),
+            OpCode::JumpIfTrue { arg1, target } => (*target, Some(*arg1), true),
+            _ => continue,
+        };
+        let end = final_target(op_codes, target, register, when);
+        if matches!(op_codes[pc], OpCode::Jump { .. }) && end == pc + 1 {
+            removed[pc] = true;
+            changed = true;
+        } else if end != target {
+            if let OpCode::Jump { target }
+            | OpCode::JumpIfFalse { target, .. }
+            | OpCode::JumpIfTrue { target, .. } = &mut op_codes[pc]
+            {
+                *target = end;
+            }
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Follows a jump, which for a conditional one is taken when the Bool in
+// register is `when`. The register can't change along the way, so a
+// conditional jump on it at the target is already decided.
+fn final_target(op_codes: &[OpCode], start: usize, register: Option<usize>, when: bool) -> usize {
+    let mut target = start;
+    // A loop of jumps is left alone rather than followed forever
+    let mut seen = HashSet::new();
+    while seen.insert(target) {
+        target = match (op_codes.get(target), register) {
+            (Some(OpCode::Jump { target }), _) => *target,
+            (Some(OpCode::JumpIfFalse { arg1, target }), Some(register)) if *arg1 == register => {
+                if when {
+                    target + 1
+                } else {
+                    *target
+                }
+            }
+            (Some(OpCode::JumpIfTrue { arg1, target }), Some(register)) if *arg1 == register => {
+                if when {
+                    *target
+                } else {
+                    target + 1
+                }
+            }
+            _ => return target,
+        };
+    }
+    start
+}
+
+// Rewrites pairs of op codes. The second of a pair can't be a jump target,
+// as anything jumping there would skip the first.
+fn fuse(op_codes: &mut [OpCode], removed: &mut [bool]) -> bool {
+    let targets = jump_targets(op_codes);
+    let mut changed = false;
+    let mut pc = 0;
+    while pc + 1 < op_codes.len() {
+        if targets.contains(&(pc + 1)) {
+            pc += 1;
+            continue;
+        }
+        let fused = match (&op_codes[pc], &op_codes[pc + 1]) {
+            (load, OpCode::Store { arg1: slot, arg2 })
+                if written_register(load) == Some(*arg2)
+                    && register_is_dead(op_codes, pc + 2, *arg2) =>
+            {
+                store_constant(load, *slot).map(|store| (Some(store), None))
+            }
+            (
+                OpCode::Store { arg1, arg2 },
+                OpCode::Load {
+                    arg1: register,
+                    arg2: slot,
+                },
+            ) if arg1 == slot => {
+                if arg2 == register {
+                    Some((None, None))
+                } else {
+                    Some((
+                        None,
+                        Some(OpCode::Move {
+                            arg1: *register,
+                            arg2: *arg2,
+                        }),
+                    ))
+                }
+            }
+            (
+                OpCode::Load { arg1, arg2 },
+                OpCode::Store {
+                    arg1: slot,
+                    arg2: register,
+                },
+            ) if arg1 == register && arg2 == slot => Some((None, None)),
+            _ => None,
+        };
+        // The first is replaced, and the second replaced or dropped when
+        // there's nothing to replace it with
+        if let Some((first, second)) = fused {
+            if let Some(first) = first {
+                op_codes[pc] = first;
+            }
+            match second {
+                Some(second) => op_codes[pc + 1] = second,
+                None => removed[pc + 1] = true,
+            }
+            changed = true;
+            pc += 2;
+        } else {
+            pc += 1;
+        }
+    }
+    changed
+}
+
+fn store_constant(load: &OpCode, slot: usize) -> Option<OpCode> {
+    let store = match load {
+        OpCode::LoadIntConst { arg2, .. } => OpCode::StoreIntConst {
+            arg1: slot,
+            arg2: *arg2,
+        },
+        OpCode::LoadFloatConst { arg2, .. } => OpCode::StoreFloatConst {
+            arg1: slot,
+            arg2: *arg2,
+        },
+        OpCode::LoadStringConst { arg2, .. } => OpCode::StoreStringConst {
+            arg1: slot,
+            arg2: arg2.clone(),
+        },
+        OpCode::LoadBoolConst { arg2, .. } => OpCode::StoreBoolConst {
+            arg1: slot,
+            arg2: *arg2,
+        },
+        _ => return None,
+    };
+    Some(store)
+}
+
+// Slots live on the VM and outlast the program, but nothing else can read
+// them except the repl, which doesn't optimize
+fn remove_dead_stores(op_codes: &[OpCode], removed: &mut [bool]) -> bool {
+    let loaded: HashSet<usize> = op_codes
+        .iter()
+        .enumerate()
+        .filter_map(|(pc, op_code)| match op_code {
+            OpCode::Load { arg2, .. } if !removed[pc] => Some(*arg2),
+            _ => None,
+        })
+        .collect();
+    let mut changed = false;
+    for (pc, op_code) in op_codes.iter().enumerate() {
+        match op_code {
+            OpCode::Store { arg1, .. }
+            | OpCode::StoreIntConst { arg1, .. }
+            | OpCode::StoreFloatConst { arg1, .. }
+            | OpCode::StoreStringConst { arg1, .. }
+            | OpCode::StoreBoolConst { arg1, .. }
+                if !removed[pc] && !loaded.contains(arg1) =>
+            {
+                removed[pc] = true;
+                changed = true;
+            }
+            _ => {}
+        }
+    }
+    changed
+}
+
+fn jump_targets(op_codes: &[OpCode]) -> HashSet<usize> {
+    op_codes
+        .iter()
+        .filter_map(|op_code| match op_code {
+            OpCode::Jump { target }
+            | OpCode::JumpIfFalse { target, .. }
+            | OpCode::JumpIfTrue { target, .. } => Some(*target),
+            _ => None,
+        })
+        .collect()
+}
+
+// Whether the register is written before it's read again when running on
+// from pc. Anything that jumps is assumed to read it.
+fn register_is_dead(op_codes: &[OpCode], pc: usize, register: usize) -> bool {
+    for op_code in &op_codes[pc..] {
+        if read_registers(op_code).contains(&register) {
+            return false;
+        }
+        match op_code {
+            OpCode::Halt => return true,
+            OpCode::Jump { .. } | OpCode::JumpIfFalse { .. } | OpCode::JumpIfTrue { .. } => {
+                return false
+            }
+            _ => {}
+        }
+        if written_register(op_code) == Some(register) {
+            return true;
+        }
+    }
+    false
+}
+
+fn read_registers(op_code: &OpCode) -> Vec<usize> {
+    match op_code {
+        OpCode::And { arg2, arg3, .. }
+        | OpCode::Or { arg2, arg3, .. }
+        | OpCode::Add { arg2, arg3, .. }
+        | OpCode::Sub { arg2, arg3, .. }
+        | OpCode::Concat { arg2, arg3, .. } => vec![*arg2, *arg3],
+        OpCode::Not { value: register }
+        | OpCode::Move { arg2: register, .. }
+        | OpCode::Store { arg2: register, .. }
+        | OpCode::Print { arg1: register }
+        | OpCode::JumpIfFalse { arg1: register, .. }
+        | OpCode::JumpIfTrue { arg1: register, .. } => vec![*register],
+        OpCode::CallHost { arg2, arg3, .. } => (*arg2..arg2 + arg3).collect(),
+        // The program's result
+        OpCode::Halt => vec![0],
+        _ => Vec::new(),
+    }
+}
+
+fn written_register(op_code: &OpCode) -> Option<usize> {
+    match op_code {
+        OpCode::And { arg1, .. }
+        | OpCode::Or { arg1, .. }
+        | OpCode::Add { arg1, .. }
+        | OpCode::Sub { arg1, .. }
+        | OpCode::Concat { arg1, .. }
+        | OpCode::Not { value: arg1 }
+        | OpCode::Load { arg1, .. }
+        | OpCode::LoadIntConst { arg1, .. }
+        | OpCode::LoadFloatConst { arg1, .. }
+        | OpCode::LoadStringConst { arg1, .. }
+        | OpCode::LoadBoolConst { arg1, .. }
+        | OpCode::Move { arg1, .. }
+        | OpCode::CallHost { arg1, .. } => Some(*arg1),
+        _ => None,
+    }
+}
+
+// Drops the removed op codes. Anything pointing at one of them moves on to
+// the next op code that's kept.
+fn compact(program: &mut Program, removed: &[bool]) {
+    let mut new_pcs = Vec::with_capacity(removed.len() + 1);
+    let mut kept = 0;
+    for removed in removed {
+        new_pcs.push(kept);
+        if !removed {
+            kept += 1;
+        }
+    }
+    new_pcs.push(kept);
+    let new_pc = |pc: usize| new_pcs.get(pc).copied().unwrap_or(pc);
+
+    let kept_op_codes = program.op_codes_mut();
+    let op_codes = std::mem::take(kept_op_codes);
+    for (mut op_code, removed) in op_codes.into_iter().zip(removed) {
+        if *removed {
+            continue;
+        }
+        if let OpCode::Jump { target }
+        | OpCode::JumpIfFalse { target, .. }
+        | OpCode::JumpIfTrue { target, .. } = &mut op_code
+        {
+            *target = new_pc(*target);
+        }
+        kept_op_codes.push(op_code);
+    }
+    for function in &mut program.functions {
+        function.start = new_pc(function.start);
+    }
+    for entry in program.line_table_mut() {
+        entry.pc = new_pc(entry.pc);
+    }
+    // Of statements that now start at the same pc, the last is the one
+    // whose op codes are left
+    program.line_table_mut().dedup_by(|later, earlier| {
+        if later.pc == earlier.pc {
+            *earlier = *later;
+            true
+        } else {
+            false
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Program;
+    use crate::optimize::OptLevel;
+    use crate::testing::{self, Captured};
+    use crate::vm::RunOptions;
+    use crate::{asm, verify, vm};
+
+    #[test]
+    fn peephole_pass_rewrites_assembly() {
+        use crate::Value;
+
+        let output = Captured::default();
+        let run = |program: &Program| {
+            assert_eq!(verify::verify(program), Ok(()));
+            let mut vm = vm::Vm::new();
+            vm.output = output.output();
+            let result = vm.run(program, &RunOptions::default());
+            (result, output.take())
+        };
+
+        let mut program = asm::assemble_program(
+            r#"
+            load_int r1, 5
+            store s0, r1
+            load r2, s0
+            store s0, r2
+            load_bool r1, False
+            jump_if_false r1, 7
+            load_bool r1, True
+            jump_if_false r1, 10
+            load_int r2, 100
+            print r2
+            print r2
+            store s1, r2
+            load r3, s1
+            jump 14
+            move r0, r3
+            halt
+            "#,
+        )
+        .unwrap();
+        let before = run(&program);
+        optimize(&mut program);
+        assert_eq!(run(&program), before);
+        assert_eq!(before, (Ok(Value::Int(5)), "5\n".to_owned()));
+        assert_eq!(
+            asm::disassemble(program.op_codes()),
+            [
+                "store_int s0, 5",
+                "load r2, s0",
+                "load_bool r1, False",
+                "jump_if_false r1, 8",
+                "load_bool r1, True",
+                "jump_if_false r1, 8",
+                "load_int r2, 100",
+                "print r2",
+                "print r2",
+                "move r3, r2",
+                "move r0, r3",
+                "halt",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn jump_loops_are_left_alone() {
+        let mut program = asm::assemble_program("jump 2\nhalt\njump 0").unwrap();
+        optimize(&mut program);
+        assert_eq!(
+            asm::disassemble(program.op_codes()),
+            "jump 2\nhalt\njump 0\n"
+        );
+    }
+
+    #[test]
+    fn peephole_pass_keeps_compiled_programs_equivalent() {
+        let (mut engine, engine_output) = testing::engine(OptLevel::O1);
+        engine.register_fn("id", &["Integer"], "Integer", |args| Ok(args[0].clone()));
+        let sources = [
+            r#"fn main() {
+                let a: Integer = id(1)
+                let b: Integer = add(a, 2)
+                print_integer(b)
+                print_integer(add(b, a))
+            }"#,
+            r#"fn main() {
+                let yes: Bool = not(False)
+                let no: Bool = not(yes)
+                print_bool(and(and(yes, no), or(no, yes)))
+                print_bool(or(or(no, and(yes, yes)), no))
+            }"#,
+            r#"fn main() {
+                let name: String = concat("b", "ee")
+                let s: String = concat(name, "!")
+                let n: Integer = sub(id(1), 2)
+                print_string(s)
+            }"#,
+        ];
+        for source in sources {
+            engine.set_opt_level(OptLevel::O0);
+            let unoptimized = engine.compile(source).unwrap();
+            engine.set_opt_level(OptLevel::O1);
+            let optimized = engine.compile(source).unwrap();
+            assert!(optimized.op_codes().len() < unoptimized.op_codes().len());
+            let result = |program: &Program| {
+                assert_eq!(verify::verify(program), Ok(()));
+                let result = engine.run(program).map_err(|error| error.kind);
+                (result, engine_output.take())
+            };
+            assert_eq!(result(&optimized), result(&unoptimized));
+        }
+    }
+}
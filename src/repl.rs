@@ -0,0 +1,235 @@
+use std::io::{self, BufRead, Write};
+
+use crate::ast::AbstractSyntaxTree;
+use crate::bytecode::Program;
+use crate::code_gen::{code_gen, Function, SymbolTable};
+use crate::diagnostic::Diagnostic;
+use crate::lex::lex_with_spans;
+use crate::parse::{parse_statements, parse_with_spans};
+use crate::span::Span;
+use crate::token::Token;
+use crate::vm::{RunOptions, Value, Vm};
+use crate::{analyze, analyze_document, expression_type};
+
+// Expressions are evaluated by binding them to this variable. It can't be
+// written in source so it never clashes with a user's variables.
+const RESULT_NAME: &str = "$result";
+
+// Every input is compiled as more statements of one long running function,
+// so the symbol table and the VM's variables keep everything defined so far
+pub struct Repl {
+    symbol_table: SymbolTable,
+    vm: Vm,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Repl {
+            symbol_table: SymbolTable {
+                functions: vec![Function {
+                    name: "repl".to_owned(),
+                    variables: Vec::new(),
+                    span: Span::default(),
+                }],
+                constants: Vec::new(),
+                host_functions: Vec::new(),
+            },
+            vm: Vm::new(),
+        }
+    }
+
+    // Returns the value of the input if it was an expression. Nothing is kept
+    // from an input that fails to compile.
+    pub fn eval(&mut self, input: &str) -> Result<Option<String>, Diagnostic> {
+        let (tokens, spans) = lex_with_spans(input);
+        match tokens.first() {
+            None => Ok(None),
+            Some(Token::Const) => {
+                let document = parse_with_spans(tokens, &spans)?;
+                let mut symbol_table = self.symbol_table.clone();
+                analyze_document(document, &mut symbol_table)?;
+                self.symbol_table = symbol_table;
+                Ok(None)
+            }
+            Some(Token::Fn) => Err(Diagnostic::new(
+                "Function definitions are not supported in the repl yet",
+            )),
+            Some(_) => {
+                // Expressions have no span of their own, so errors in one
+                // are reported at the start of the input
+                let input_span = Span {
+                    start: spans[0].start,
+                    end: spans.last().map_or(spans[0].end, |span| span.end),
+                };
+                let mut result = None;
+                for statement in parse_statements(tokens, &spans)? {
+                    result = self.eval_statement(statement, input_span)?;
+                }
+                Ok(result)
+            }
+        }
+    }
+
+    fn eval_statement(
+        &mut self,
+        statement: AbstractSyntaxTree,
+        input_span: Span,
+    ) -> Result<Option<String>, Diagnostic> {
+        match statement {
+            AbstractSyntaxTree::Let { .. } | AbstractSyntaxTree::Comment { .. } => {
+                let mut symbol_table = self.symbol_table.clone();
+                if let AbstractSyntaxTree::Let { name, .. } = &statement {
+                    shadow(&mut symbol_table, name);
+                }
+                analyze(statement.clone(), &mut symbol_table)?;
+                self.execute(&statement, &mut symbol_table)?;
+                self.symbol_table = symbol_table;
+                Ok(None)
+            }
+            AbstractSyntaxTree::Call { ref name, .. } if name.starts_with("print_") => {
+                let mut symbol_table = self.symbol_table.clone();
+                analyze(statement.clone(), &mut symbol_table)?;
+                self.execute(&statement, &mut symbol_table)?;
+                Ok(None)
+            }
+            expression => {
+                // The result variable only exists while the expression runs
+                let mut symbol_table = self.symbol_table.clone();
+                let span = expression.span().unwrap_or(input_span);
+                let type_annot = expression_type(&expression, &symbol_table)
+                    .map_err(|diagnostic| diagnostic.with_span(span))?;
+                let statement = AbstractSyntaxTree::Let {
+                    name: RESULT_NAME.to_owned(),
+                    type_annot,
+                    value: Box::new(expression),
+                    span,
+                };
+                analyze(statement.clone(), &mut symbol_table)?;
+                let slot = symbol_table.functions.last().unwrap().variables.len() - 1;
+                self.execute(&statement, &mut symbol_table)?;
+                let value = self.vm.variables.get(slot).cloned();
+                Ok(value.map(|value| format_value(&value)))
+            }
+        }
+    }
+
+    fn execute(
+        &mut self,
+        statement: &AbstractSyntaxTree,
+        symbol_table: &mut SymbolTable,
+    ) -> Result<(), Diagnostic> {
+        let mut op_codes = Vec::new();
+        code_gen(statement, symbol_table, &mut op_codes)?;
+        // Each input runs on its own, so a runtime error is in the statement
+        // that was just entered
+        let span = statement.span().unwrap_or_default();
+        self.vm
+            .run(&Program::from_op_codes(op_codes), &RunOptions::default())
+            .map(|_| ())
+            .map_err(|error| Diagnostic::new(error.kind.to_string()).with_span(span))
+    }
+}
+
+// Rebinding a variable gives it a new slot. The old one is renamed so that
+// lookups by name only find the new binding.
+fn shadow(symbol_table: &mut SymbolTable, name: &str) {
+    let function = symbol_table.functions.last_mut().unwrap();
+    for variable in function.variables.iter_mut() {
+        if variable.name == name {
+            variable.name = format!("${}", name);
+        }
+    }
+}
+
+// Strings are quoted so they can be told apart from other values
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::String(value) => format!("{:?}", value),
+        value => value.to_string(),
+    }
+}
+
+pub fn run() {
+    let mut repl = Repl::new();
+    let stdin = io::stdin();
+    print!("> ");
+    io::stdout().flush().ok();
+    for line in stdin.lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        match repl.eval(&line) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => {}
+            Err(diagnostic) => eprintln!("error: {}", diagnostic),
+        }
+        print!("> ");
+        io::stdout().flush().ok();
+    }
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repl;
+
+    #[test]
+    fn repl_keeps_definitions_between_inputs() {
+        let mut repl = repl::Repl::new();
+        assert_eq!(repl.eval("let x: Integer = 5"), Ok(None));
+        assert_eq!(repl.eval("add(x, 2)"), Ok(Some("7".to_owned())));
+        assert_eq!(repl.eval("const greeting: String = \"hi\""), Ok(None));
+        assert_eq!(
+            repl.eval("concat(greeting, \"!\")"),
+            Ok(Some("\"hi!\"".to_owned()))
+        );
+        assert_eq!(repl.eval("and(True, False)"), Ok(Some("False".to_owned())));
+
+        // Failed inputs leave the session as it was
+        assert!(repl.eval("let y: String = x").is_err());
+        assert!(repl.eval("y").is_err());
+
+        // Rebinding a name can change its type
+        assert_eq!(repl.eval("let x: String = \"five\""), Ok(None));
+        assert_eq!(repl.eval("x"), Ok(Some("\"five\"".to_owned())));
+        assert_eq!(repl.eval("let z: Integer = sub(7, 2)"), Ok(None));
+        assert_eq!(repl.eval("z"), Ok(Some("5".to_owned())));
+        assert_eq!(repl.eval("let yes: Bool = True"), Ok(None));
+        assert_eq!(repl.eval("not(yes)"), Ok(Some("False".to_owned())));
+    }
+
+    #[test]
+    fn repl_errors_point_into_the_input() {
+        let mut repl = repl::Repl::new();
+        let error = |repl: &mut repl::Repl, input| repl.eval(input).unwrap_err().to_string();
+        assert_eq!(repl.eval("let x: Integer = 5"), Ok(None));
+        assert_eq!(
+            error(&mut repl, "let w: Integer = "),
+            "1:16: Unexpected end of input"
+        );
+        assert_eq!(error(&mut repl, "  add(x, \"a\")"), "1:3: Type mismatch");
+        assert_eq!(error(&mut repl, "  nope"), "1:3: Variable not found: nope");
+        // Runtime errors are reported at the statement that raised them
+        assert_eq!(error(&mut repl, "  sub(0, 1)"), "1:3: integer overflow");
+        assert_eq!(
+            error(&mut repl, "let z: Integer = 1 let y: Integer = sub(x, 9)"),
+            "1:20: integer overflow"
+        );
+    }
+
+    #[test]
+    fn repl_constants_can_be_redefined() {
+        let mut repl = repl::Repl::new();
+        assert_eq!(repl.eval("const base: Integer = 40"), Ok(None));
+        assert_eq!(repl.eval("const answer: Integer = add(base, 2)"), Ok(None));
+        assert_eq!(repl.eval("answer"), Ok(Some("42".to_owned())));
+        assert_eq!(repl.eval("const base: Integer = 1"), Ok(None));
+        assert_eq!(repl.eval("base"), Ok(Some("1".to_owned())));
+    }
+}
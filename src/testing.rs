@@ -0,0 +1,38 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::bytecode::Program;
+use crate::compile_source_with_host_functions;
+use crate::diagnostic::Diagnostic;
+use crate::engine::Engine;
+use crate::optimize::OptLevel;
+use crate::vm::Output;
+
+// Collects what programs print so tests can compare it
+#[derive(Clone, Default)]
+pub struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Captured {
+    pub fn output(&self) -> Output {
+        self.0.clone()
+    }
+
+    // What was printed since the last call
+    pub fn take(&self) -> String {
+        String::from_utf8(std::mem::take(&mut *self.0.borrow_mut())).unwrap()
+    }
+}
+
+// An engine at the given level whose printed output is captured
+pub fn engine(opt_level: OptLevel) -> (Engine, Captured) {
+    let captured = Captured::default();
+    let mut engine = Engine::new();
+    engine.set_output(captured.output());
+    engine.set_opt_level(opt_level);
+    (engine, captured)
+}
+
+// For tests that look at the op codes a program would naively compile to
+pub fn compile_unoptimized(source: &str) -> Result<Program, Diagnostic> {
+    compile_source_with_host_functions(source, Vec::new(), OptLevel::O0)
+}
@@ -1,10 +1,12 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Name { name: String },
     UpName { name: String },
     Int { value: String },
     Float { value: String },
     String { value: String },
+    // Trailing comments follow other code on the same line
+    Comment { value: String, trailing: bool },
     // Groupings
     LeftParen,  // (
     RightParen, // )
@@ -14,6 +16,8 @@ pub enum Token {
     // Other Punctuation
     Colon,
     Equal,
+    At,         // @
+    RightArrow, // ->
     // Keywords (alphabetically):
     Const,
     Fn,
@@ -21,5 +25,26 @@ pub enum Token {
 
     // Invalid code tokens
     UnterminatedString(String),
+    InvalidEscape(String),
+    InvalidNumber(String),
     UnexpectedGrapheme(String),
 }
+
+impl Token {
+    // The message for tokens that represent invalid code
+    pub fn error(&self) -> Option<String> {
+        match self {
+            Token::UnterminatedString(value) => Some(format!("Unterminated string: \"{}", value)),
+            Token::InvalidEscape(escape) => {
+                Some(format!("Invalid escape sequence in string: {}", escape))
+            }
+            Token::InvalidNumber(value) => Some(format!("Invalid number literal: {}", value)),
+            Token::UnexpectedGrapheme(grapheme) => match grapheme.as_str() {
+                "[" | "]" => Some("Lists are not supported yet".to_owned()),
+                "#" => Some("Tuples are not supported yet".to_owned()),
+                _ => Some(format!("Unexpected grapheme: {}", grapheme)),
+            },
+            _ => None,
+        }
+    }
+}
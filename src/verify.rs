@@ -0,0 +1,374 @@
+use std::fmt;
+
+use crate::bytecode::Program;
+use crate::opcode::OpCode;
+use crate::vm::REGISTER_COUNT;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    RegisterOutOfRange { pc: usize, register: usize },
+    SlotOutOfRange { pc: usize, slot: usize },
+    UninitializedSlot { pc: usize, slot: usize },
+    JumpOutOfRange { pc: usize, target: usize },
+    // The function's code runs past the end of the program
+    MissingHalt { function: String },
+    FunctionOutOfRange { function: String },
+    // More than the 16 bits encoded code addresses slots with
+    TooManyVariables { function: String, count: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::RegisterOutOfRange { pc, register } => write!(
+                f,
+                "register r{} at pc {} is out of range, there are {} registers",
+                register, pc, REGISTER_COUNT
+            ),
+            VerifyError::SlotOutOfRange { pc, slot } => {
+                write!(f, "variable slot s{} at pc {} is out of range", slot, pc)
+            }
+            VerifyError::UninitializedSlot { pc, slot } => write!(
+                f,
+                "variable slot s{} is loaded at pc {} before it is stored",
+                slot, pc
+            ),
+            VerifyError::JumpOutOfRange { pc, target } => {
+                write!(
+                    f,
+                    "jump at pc {} to {} is outside of the function",
+                    pc, target
+                )
+            }
+            VerifyError::MissingHalt { function } => {
+                write!(f, "function {} does not end with halt", function)
+            }
+            VerifyError::FunctionOutOfRange { function } => {
+                write!(f, "function {} starts outside of the code", function)
+            }
+            VerifyError::TooManyVariables { function, count } => write!(
+                f,
+                "function {} has {} variables, at most {} are supported",
+                function,
+                count,
+                u16::MAX
+            ),
+        }
+    }
+}
+
+impl VerifyError {
+    // The op code the error is about, if it is about a single one
+    pub fn pc(&self) -> Option<usize> {
+        match self {
+            VerifyError::RegisterOutOfRange { pc, .. }
+            | VerifyError::SlotOutOfRange { pc, .. }
+            | VerifyError::UninitializedSlot { pc, .. }
+            | VerifyError::JumpOutOfRange { pc, .. } => Some(*pc),
+            VerifyError::MissingHalt { .. }
+            | VerifyError::FunctionOutOfRange { .. }
+            | VerifyError::TooManyVariables { .. } => None,
+        }
+    }
+}
+
+// Checks everything the VM relies on without checking itself, so that a
+// verified program can't index out of bounds or load a missing variable.
+// Every path through a function is followed until it halts, and a slot
+// only counts as stored if it is stored on all paths reaching a load.
+pub fn verify(program: &Program) -> Result<(), VerifyError> {
+    for function in &program.functions {
+        let Some(code) = program.op_codes().get(function.start..) else {
+            return Err(VerifyError::FunctionOutOfRange {
+                function: function.name.clone(),
+            });
+        };
+        let variable_count = function.variable_count;
+        if variable_count > u16::MAX as usize {
+            return Err(VerifyError::TooManyVariables {
+                function: function.name.clone(),
+                count: variable_count,
+            });
+        }
+        let missing_halt = || VerifyError::MissingHalt {
+            function: function.name.clone(),
+        };
+        // The slots stored on entry to each block, once it has been reached.
+        // Only the op codes that start a block have one.
+        let leaders = block_leaders(code, function.start);
+        let mut entry_states: Vec<Option<SlotSet>> = vec![None; code.len()];
+        let Some(entry) = entry_states.first_mut() else {
+            return Err(missing_halt());
+        };
+        *entry = Some(SlotSet::new(variable_count));
+        let mut worklist = vec![0];
+        while let Some(block) = worklist.pop() {
+            let mut stored = entry_states[block].clone().unwrap();
+            let mut offset = block;
+            loop {
+                let Some(op_code) = code.get(offset) else {
+                    return Err(missing_halt());
+                };
+                let pc = function.start + offset;
+                let (registers, loaded, store) = operands(op_code);
+                if let Some(&register) = registers.iter().find(|r| **r >= REGISTER_COUNT) {
+                    return Err(VerifyError::RegisterOutOfRange { pc, register });
+                }
+                for slot in loaded.into_iter().chain(store) {
+                    if slot >= variable_count {
+                        return Err(VerifyError::SlotOutOfRange { pc, slot });
+                    }
+                }
+                if let Some(slot) = loaded.filter(|slot| !stored.contains(*slot)) {
+                    return Err(VerifyError::UninitializedSlot { pc, slot });
+                }
+                if let Some(slot) = store {
+                    stored.insert(slot);
+                }
+                let successors = match op_code {
+                    OpCode::Halt => vec![],
+                    OpCode::Jump { target } => vec![*target],
+                    OpCode::JumpIfFalse { target, .. } | OpCode::JumpIfTrue { target, .. } => {
+                        vec![pc + 1, *target]
+                    }
+                    // Carry on through the block with the same state
+                    _ if !leaders.get(offset + 1).unwrap_or(&false) => {
+                        offset += 1;
+                        continue;
+                    }
+                    _ => vec![pc + 1],
+                };
+                for next in successors {
+                    if next < function.start || next > program.op_codes().len() {
+                        return Err(VerifyError::JumpOutOfRange { pc, target: next });
+                    }
+                    let next = next - function.start;
+                    if next == code.len() {
+                        return Err(missing_halt());
+                    }
+                    let changed = match &mut entry_states[next] {
+                        Some(state) => state.intersect(&stored),
+                        state => {
+                            *state = Some(stored.clone());
+                            true
+                        }
+                    };
+                    if changed {
+                        worklist.push(next);
+                    }
+                }
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether each op code starts a block: the entry, the targets of jumps and
+// whatever follows a jump or a halt
+fn block_leaders(code: &[OpCode], start: usize) -> Vec<bool> {
+    let mut leaders = vec![false; code.len()];
+    if let Some(entry) = leaders.first_mut() {
+        *entry = true;
+    }
+    for (offset, op_code) in code.iter().enumerate() {
+        let target = match op_code {
+            OpCode::Jump { target }
+            | OpCode::JumpIfFalse { target, .. }
+            | OpCode::JumpIfTrue { target, .. } => target.checked_sub(start),
+            OpCode::Halt => None,
+            _ => continue,
+        };
+        for leader in [Some(offset + 1), target].into_iter().flatten() {
+            if let Some(leader) = leaders.get_mut(leader) {
+                *leader = true;
+            }
+        }
+    }
+    leaders
+}
+
+// Variable slots, one bit each
+#[derive(Debug, Clone)]
+struct SlotSet {
+    words: Vec<u64>,
+}
+
+impl SlotSet {
+    fn new(count: usize) -> Self {
+        SlotSet {
+            words: vec![0; count.div_ceil(64)],
+        }
+    }
+
+    fn contains(&self, slot: usize) -> bool {
+        self.words[slot / 64] & (1 << (slot % 64)) != 0
+    }
+
+    fn insert(&mut self, slot: usize) {
+        self.words[slot / 64] |= 1 << (slot % 64);
+    }
+
+    // Keeps the slots that are in both, returning whether any were removed
+    fn intersect(&mut self, other: &SlotSet) -> bool {
+        let mut changed = false;
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            changed |= *word & !other != 0;
+            *word &= other;
+        }
+        changed
+    }
+}
+
+// The registers an op code uses, and the variable slots it loads and stores
+fn operands(op_code: &OpCode) -> (Vec<usize>, Option<usize>, Option<usize>) {
+    match op_code {
+        OpCode::And { arg1, arg2, arg3 }
+        | OpCode::Or { arg1, arg2, arg3 }
+        | OpCode::Add { arg1, arg2, arg3 }
+        | OpCode::Sub { arg1, arg2, arg3 }
+        | OpCode::Concat { arg1, arg2, arg3 } => (vec![*arg1, *arg2, *arg3], None, None),
+        OpCode::Not { value: arg1 }
+        | OpCode::Print { arg1 }
+        | OpCode::LoadIntConst { arg1, .. }
+        | OpCode::LoadFloatConst { arg1, .. }
+        | OpCode::LoadStringConst { arg1, .. }
+        | OpCode::LoadBoolConst { arg1, .. }
+        | OpCode::JumpIfFalse { arg1, .. }
+        | OpCode::JumpIfTrue { arg1, .. } => (vec![*arg1], None, None),
+        OpCode::Move { arg1, arg2 } => (vec![*arg1, *arg2], None, None),
+        OpCode::Load { arg1, arg2 } => (vec![*arg1], Some(*arg2), None),
+        OpCode::Store { arg1, arg2 } => (vec![*arg2], None, Some(*arg1)),
+        OpCode::StoreIntConst { arg1, .. }
+        | OpCode::StoreFloatConst { arg1, .. }
+        | OpCode::StoreStringConst { arg1, .. }
+        | OpCode::StoreBoolConst { arg1, .. } => (Vec::new(), None, Some(*arg1)),
+        // Checking the last argument register covers all of them
+        OpCode::CallHost {
+            arg1, arg2, arg3, ..
+        } => match arg3 {
+            0 => (vec![*arg1], None, None),
+            _ => (vec![*arg1, arg2.saturating_add(arg3 - 1)], None, None),
+        },
+        OpCode::Jump { .. } | OpCode::Halt => (Vec::new(), None, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm;
+    use crate::bytecode::Program;
+    use crate::testing::compile_unoptimized;
+    use crate::verify::{verify, VerifyError};
+
+    fn compiled() -> Program {
+        compile_unoptimized(
+            r#"
+            fn main() {
+                let one: Integer = 1
+                let two: Integer = add(one, one)
+                print_integer(two)
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn verify_asm(source: &str) -> Result<(), VerifyError> {
+        verify(&asm::assemble_program(source).unwrap())
+    }
+
+    #[test]
+    fn verifier_accepts_well_formed_programs() {
+        assert_eq!(verify(&compiled()), Ok(()));
+        assert_eq!(verify_asm("load_int r1, 1\nprint r1\nhalt"), Ok(()));
+    }
+
+    #[test]
+    fn verifier_checks_registers_and_slots() {
+        assert_eq!(
+            verify_asm("load_int r1, 1\nadd r0, r1, r32\nhalt"),
+            Err(VerifyError::RegisterOutOfRange {
+                pc: 1,
+                register: 32
+            })
+        );
+        assert_eq!(
+            verify_asm("store_int s0, 1\nload r1, s1\nstore_int s1, 2\nhalt"),
+            Err(VerifyError::UninitializedSlot { pc: 1, slot: 1 })
+        );
+        let mut out_of_range = compiled();
+        out_of_range.functions[0].variable_count = 1;
+        assert_eq!(
+            verify(&out_of_range),
+            Err(VerifyError::SlotOutOfRange { pc: 4, slot: 1 })
+        );
+        let mut too_many_variables = compiled();
+        too_many_variables.functions[0].variable_count = usize::MAX;
+        assert_eq!(
+            verify(&too_many_variables),
+            Err(VerifyError::TooManyVariables {
+                function: "main".to_owned(),
+                count: usize::MAX
+            })
+        );
+    }
+
+    #[test]
+    fn verifier_checks_where_functions_start_and_end() {
+        assert_eq!(
+            verify_asm("load_int r1, 1\nprint r1"),
+            Err(VerifyError::MissingHalt {
+                function: "main".to_owned()
+            })
+        );
+        let mut past_the_end = compiled();
+        past_the_end.functions[0].start = 100;
+        assert_eq!(
+            verify(&past_the_end).unwrap_err().to_string(),
+            "function main starts outside of the code"
+        );
+    }
+
+    #[test]
+    fn verifier_follows_every_path() {
+        // A slot stored on only one path into a block isn't stored after it
+        assert_eq!(
+            verify_asm(
+                "load_bool r1, True\njump_if_false r1, 3\nstore_int s0, 1\nload r2, s0\nhalt"
+            ),
+            Err(VerifyError::UninitializedSlot { pc: 3, slot: 0 })
+        );
+        assert_eq!(
+            verify_asm(
+                "load_bool r1, True\njump_if_true r1, 3\nstore_int s0, 1\nload r2, s0\nhalt"
+            ),
+            Err(VerifyError::UninitializedSlot { pc: 3, slot: 0 })
+        );
+        assert_eq!(
+            verify_asm(
+                "load_bool r1, True\nstore_int s0, 1\njump_if_false r1, 4\nstore_int s0, 2\nload r2, s0\nhalt"
+            ),
+            Ok(())
+        );
+        // Jumping back is fine as long as every path halts
+        assert_eq!(
+            verify_asm("load_bool r1, False\njump_if_false r1, 1\nhalt"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn verifier_checks_jumps() {
+        assert_eq!(
+            verify_asm("jump 7\nhalt"),
+            Err(VerifyError::JumpOutOfRange { pc: 0, target: 7 })
+        );
+        // The path that isn't taken runs off the end
+        assert_eq!(
+            verify_asm("load_bool r1, True\njump_if_true r1, 3\nhalt"),
+            Err(VerifyError::MissingHalt {
+                function: "main".to_owned()
+            })
+        );
+    }
+}
@@ -0,0 +1,779 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::bytecode::Program;
+use crate::code::{Code, Instruction, Op};
+use crate::host::HostFunction;
+use crate::span::Span;
+
+// Cloning a value is O(1), as heap values are shared rather than copied.
+// Nothing can modify a value once it's made, so sharing is never visible.
+// No value refers to another, so reference counting frees everything; a
+// tracing collector waits for closures or mutable references, see todo.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(usize),
+    Float(f64),
+    String(Rc<str>),
+    Bool(bool),
+}
+
+impl Value {
+    // The bee type the value came from
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "Integer",
+            Value::Float(_) => "Float",
+            Value::String(_) => "String",
+            Value::Bool(_) => "Bool",
+        }
+    }
+}
+
+// Converting a value into a Rust type that doesn't match it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionError {
+    pub expected: &'static str,
+    pub found: &'static str,
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected {} but found {}", self.expected, self.found)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value.into())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl TryFrom<Value> for usize {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Int(value) => Ok(value),
+            value => Err(ConversionError {
+                expected: "Integer",
+                found: value.type_name(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Float(value) => Ok(value),
+            value => Err(ConversionError {
+                expected: "Float",
+                found: value.type_name(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::String(value) => Ok(value.to_string()),
+            value => Err(ConversionError {
+                expected: "String",
+                found: value.type_name(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Bool(value) => Ok(value),
+            value => Err(ConversionError {
+                expected: "Bool",
+                found: value.type_name(),
+            }),
+        }
+    }
+}
+
+fn type_error(operation: &str, value: &Value, other: &Value) -> ErrorKind {
+    ErrorKind::TypeError(format!(
+        "cannot {} {} and {}",
+        operation,
+        value.type_name(),
+        other.type_name()
+    ))
+}
+
+impl Operation for Value {
+    fn add(&self, other: &Self) -> Result<Self, ErrorKind> {
+        match (self, other) {
+            (Value::Int(value), Value::Int(other_value)) => value
+                .checked_add(*other_value)
+                .map(Value::Int)
+                .ok_or(ErrorKind::IntegerOverflow),
+            (Value::Float(value), Value::Float(other_value)) => {
+                Ok(Value::Float(value + other_value))
+            }
+            _ => Err(type_error("add", self, other)),
+        }
+    }
+
+    fn sub(&self, other: &Self) -> Result<Self, ErrorKind> {
+        match (self, other) {
+            (Value::Int(value), Value::Int(other_value)) => value
+                .checked_sub(*other_value)
+                .map(Value::Int)
+                .ok_or(ErrorKind::IntegerOverflow),
+            (Value::Float(value), Value::Float(other_value)) => {
+                Ok(Value::Float(value - other_value))
+            }
+            _ => Err(type_error("subtract", self, other)),
+        }
+    }
+
+    fn and(&self, other: &Self) -> Result<Self, ErrorKind> {
+        match (self, other) {
+            (Value::Bool(value), Value::Bool(other_value)) => {
+                Ok(Value::Bool(*value && *other_value))
+            }
+            _ => Err(type_error("and", self, other)),
+        }
+    }
+
+    fn or(&self, other: &Self) -> Result<Self, ErrorKind> {
+        match (self, other) {
+            (Value::Bool(value), Value::Bool(other_value)) => {
+                Ok(Value::Bool(*value || *other_value))
+            }
+            _ => Err(type_error("or", self, other)),
+        }
+    }
+
+    fn not(&self) -> Result<Self, ErrorKind> {
+        match self {
+            Value::Bool(value) => Ok(Value::Bool(!value)),
+            _ => Err(ErrorKind::TypeError(format!(
+                "cannot negate {}",
+                self.type_name()
+            ))),
+        }
+    }
+
+    fn concat(&self, other: &Self) -> Result<Self, ErrorKind> {
+        match (self, other) {
+            (Value::String(value), Value::String(other_value)) => {
+                let mut result = String::with_capacity(value.len() + other_value.len());
+                result.push_str(value);
+                result.push_str(other_value);
+                Ok(Value::String(result.into()))
+            }
+            _ => Err(type_error("concat", self, other)),
+        }
+    }
+}
+
+// How values are shown to users, as opposed to the Debug form
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{:?}", value),
+            Value::String(value) => write!(f, "{}", value),
+            Value::Bool(true) => write!(f, "True"),
+            Value::Bool(false) => write!(f, "False"),
+        }
+    }
+}
+
+pub trait Operation: Sized {
+    fn add(&self, other: &Self) -> Result<Self, ErrorKind>;
+    fn sub(&self, other: &Self) -> Result<Self, ErrorKind>;
+    fn and(&self, other: &Self) -> Result<Self, ErrorKind>;
+    fn or(&self, other: &Self) -> Result<Self, ErrorKind>;
+    fn not(&self) -> Result<Self, ErrorKind>;
+    fn concat(&self, other: &Self) -> Result<Self, ErrorKind>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    TypeError(String),
+    IntegerOverflow,
+    // Raised once the language has division, options and calls
+    DivideByZero,
+    UnwrapFailed,
+    StackOverflow,
+    // Raised by Engine::run when the program fails verification, and by the
+    // VM itself only when running bytecode that wasn't verified
+    InvalidBytecode(String),
+    // The run can be resumed with more fuel or a later deadline
+    OutOfFuel,
+    DeadlineExceeded,
+    HostFunctionNotFound(String),
+    // The program was compiled against a different signature than the
+    // registered function has
+    HostSignatureMismatch(String),
+    // A host function failed or broke its signature
+    HostError(String),
+    // Writing printed output failed
+    OutputError(String),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::TypeError(message) => write!(f, "type error: {}", message),
+            ErrorKind::IntegerOverflow => write!(f, "integer overflow"),
+            ErrorKind::DivideByZero => write!(f, "divide by zero"),
+            ErrorKind::UnwrapFailed => write!(f, "unwrap failed"),
+            ErrorKind::StackOverflow => write!(f, "stack overflow"),
+            ErrorKind::InvalidBytecode(message) => write!(f, "invalid bytecode: {}", message),
+            ErrorKind::OutOfFuel => write!(f, "out of fuel"),
+            ErrorKind::DeadlineExceeded => write!(f, "deadline exceeded"),
+            ErrorKind::HostFunctionNotFound(name) => {
+                write!(f, "host function {} is not registered", name)
+            }
+            ErrorKind::HostSignatureMismatch(message) => write!(f, "host function {}", message),
+            ErrorKind::HostError(message) => write!(f, "host error: {}", message),
+            ErrorKind::OutputError(message) => write!(f, "failed to write output: {}", message),
+        }
+    }
+}
+
+// Where a run failed. Programs are a single function, so this is the only
+// frame there is; errors get a stack trace once functions can call each other.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub pc: usize,
+    pub function: String,
+    // Missing when the program has no line table entry for the pc
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(span) = self.span {
+            write!(f, "{}:{}: ", span.start.line + 1, span.start.column + 1)?;
+        }
+        write!(f, "{} (in {} at pc {})", self.kind, self.function, self.pc)
+    }
+}
+
+// Limits on a single call to run or resume, unlimited by default
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    // The number of op codes that may be executed
+    pub fuel: Option<u64>,
+    pub deadline: Option<Instant>,
+}
+
+// Reading the clock on every op code would dominate simple instructions
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+const INIT: Value = Value::Int(0);
+
+pub const REGISTER_COUNT: usize = 32;
+
+// Where print writes to. It is shared so that whoever supplied it can read
+// back what was written, e.g. with an Rc<RefCell<Vec<u8>>>.
+pub type Output = Rc<RefCell<dyn Write>>;
+
+pub fn stdout() -> Output {
+    Rc::new(RefCell::new(io::stdout()))
+}
+
+// Registers and variables live on the VM rather than in `run` so that state
+// carries over between programs, which is what the repl relies on
+pub struct Vm {
+    pub registers: [Value; REGISTER_COUNT],
+    // The locals of the running function, indexed by slot. Slots that
+    // haven't been stored hold a placeholder, so loading one has to be ruled
+    // out by the verifier.
+    pub variables: Vec<Value>,
+    pub host_functions: HashMap<String, HostFunction>,
+    pub output: Output,
+    // Op codes executed over every run, for benchmarks
+    pub executed: u64,
+    // The next op code to execute, kept so a stopped run can be resumed
+    pc: usize,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Vm {
+            registers: [INIT; REGISTER_COUNT],
+            variables: Vec::new(),
+            host_functions: HashMap::new(),
+            output: stdout(),
+            executed: 0,
+            pc: 0,
+        }
+    }
+
+    // Programs leave their result in r0 when they halt
+    pub fn run(&mut self, program: &Program, options: &RunOptions) -> Result<Value, RuntimeError> {
+        self.pc = 0;
+        // Locals from an earlier run are kept, which is how the repl sees
+        // earlier definitions
+        let variable_count = program
+            .function_at(0)
+            .map_or(0, |function| function.variable_count);
+        if self.variables.len() < variable_count {
+            self.variables.resize(variable_count, INIT);
+        }
+        self.resume(program, options)
+    }
+
+    // Continues from where the last run stopped, which after running out of
+    // fuel or time is the op code that didn't get to execute
+    pub fn resume(
+        &mut self,
+        program: &Program,
+        options: &RunOptions,
+    ) -> Result<Value, RuntimeError> {
+        let code = program.code()?;
+        let fuel = options.fuel.unwrap_or(u64::MAX);
+        let mut executed = 0;
+        let mut pc = self.pc;
+        let halted = loop {
+            if executed >= fuel {
+                break Err(ErrorKind::OutOfFuel);
+            }
+            if executed % DEADLINE_CHECK_INTERVAL == 0
+                && options
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                break Err(ErrorKind::DeadlineExceeded);
+            }
+            let Some(&instruction) = code.instructions.get(pc) else {
+                break Err(ErrorKind::InvalidBytecode(
+                    "ran past the end of the code".to_owned(),
+                ));
+            };
+            executed += 1;
+            match self.step(instruction, code) {
+                Ok(Flow::Next) => pc += 1,
+                Ok(Flow::Jump(target)) => pc = target,
+                Ok(Flow::Halt) => break Ok(()),
+                Err(kind) => break Err(kind),
+            }
+        };
+        self.pc = pc;
+        self.executed += executed;
+        match halted {
+            Ok(()) => Ok(self.registers[0].clone()),
+            Err(kind) => Err(runtime_error(program, kind, pc)),
+        }
+    }
+
+    // Returns where execution continues after the instruction. Registers
+    // were checked when the code was encoded.
+    #[inline(always)]
+    fn step(&mut self, instruction: Instruction, code: &Code) -> Result<Flow, ErrorKind> {
+        let registers = &mut self.registers;
+        let variables = &mut self.variables;
+        let Instruction { op, a, b, c } = instruction;
+        let (a, b, c) = (usize::from(a), usize::from(b), usize::from(c));
+        match op {
+            Op::Add => registers[a] = registers[b].add(&registers[c])?,
+            Op::Sub => registers[a] = registers[b].sub(&registers[c])?,
+            Op::And => registers[a] = registers[b].and(&registers[c])?,
+            Op::Or => registers[a] = registers[b].or(&registers[c])?,
+            Op::Not => registers[a] = registers[a].not()?,
+            Op::Concat => registers[a] = registers[b].concat(&registers[c])?,
+            Op::Load => registers[a] = variable(variables, b)?.clone(),
+            Op::LoadConst => registers[a] = code.constants[b].clone(),
+            Op::LoadBool => registers[a] = Value::Bool(b != 0),
+            Op::Move => registers[a] = registers[b].clone(),
+            Op::Store => *variable(variables, b)? = registers[a].clone(),
+            Op::StoreConst => *variable(variables, b)? = code.constants[c].clone(),
+            Op::StoreBool => *variable(variables, b)? = Value::Bool(c != 0),
+            Op::Print => {
+                writeln!(self.output.borrow_mut(), "{}", registers[a])
+                    .map_err(|error| ErrorKind::OutputError(error.to_string()))?;
+            }
+            Op::CallHost => {
+                let name = &code.host_names[b];
+                let host_function = self
+                    .host_functions
+                    .get(&**name)
+                    .ok_or_else(|| ErrorKind::HostFunctionNotFound(name.to_string()))?;
+                let first = c & 0xff;
+                let args = &registers[first..first + (c >> 8)];
+                let value = (host_function.function)(args).map_err(ErrorKind::HostError)?;
+                if !host_function.returns(&value) {
+                    return Err(ErrorKind::HostError(format!(
+                        "{} returned {} but is declared to return {}",
+                        name,
+                        value.type_name(),
+                        host_function.signature.returns
+                    )));
+                }
+                registers[a] = value;
+            }
+            Op::Jump => return Ok(Flow::Jump(instruction.target())),
+            Op::JumpIfFalse => {
+                if !condition(&registers[a])? {
+                    return Ok(Flow::Jump(instruction.target()));
+                }
+            }
+            Op::JumpIfTrue => {
+                if condition(&registers[a])? {
+                    return Ok(Flow::Jump(instruction.target()));
+                }
+            }
+            Op::Halt => return Ok(Flow::Halt),
+        }
+        Ok(Flow::Next)
+    }
+}
+
+pub(crate) fn runtime_error(program: &Program, kind: ErrorKind, pc: usize) -> RuntimeError {
+    RuntimeError {
+        kind,
+        pc,
+        function: program
+            .function_at(pc)
+            .map_or_else(String::new, |function| function.name.clone()),
+        span: program.span_at(pc),
+    }
+}
+
+enum Flow {
+    Next,
+    Jump(usize),
+    Halt,
+}
+
+fn condition(value: &Value) -> Result<bool, ErrorKind> {
+    match value {
+        Value::Bool(value) => Ok(*value),
+        value => Err(ErrorKind::TypeError(format!(
+            "cannot branch on {}",
+            value.type_name()
+        ))),
+    }
+}
+
+fn variable(variables: &mut [Value], slot: usize) -> Result<&mut Value, ErrorKind> {
+    variables.get_mut(slot).ok_or_else(|| {
+        ErrorKind::InvalidBytecode(format!("variable slot s{} is out of range", slot))
+    })
+}
+
+pub fn interpret(program: &Program, options: &RunOptions) -> Result<Value, RuntimeError> {
+    Vm::new().run(program, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::Program;
+    use crate::testing::compile_unoptimized;
+    use crate::vm::RunOptions;
+    use crate::{asm, bytecode, verify, vm};
+    use std::time::Instant;
+
+    #[test]
+    fn vm_runs_assembly() {
+        let op_codes = asm::assemble(
+            r#"
+            // 7 - 2
+            load_int r1, 7
+            load_int r2, 0b10
+            sub r0, r1, r2
+            store s0, r0
+            load r3, s0
+            move r4, r3
+            store_float s1, 1.5
+            load_string r5, "a\tb"
+            store_string s2, "\u{1F41D}"
+            halt
+            "#,
+        )
+        .unwrap();
+        let mut vm = vm::Vm::new();
+        vm.run(&Program::from_op_codes(op_codes), &RunOptions::default())
+            .unwrap();
+        assert_eq!(vm.registers[4], vm::Value::Int(5));
+        assert_eq!(vm.registers[5], vm::Value::String("a\tb".into()));
+        assert_eq!(vm.variables[1], vm::Value::Float(1.5));
+        assert_eq!(vm.variables[2], vm::Value::String("🐝".into()));
+    }
+
+    #[test]
+    fn runtime_errors_are_values() {
+        let op_codes = asm::assemble(
+            r#"
+            load_int r1, 1
+            load_string r2, "a"
+            add r0, r1, r2
+            halt
+            "#,
+        )
+        .unwrap();
+        let error =
+            vm::interpret(&Program::from_op_codes(op_codes), &RunOptions::default()).unwrap_err();
+        assert_eq!(
+            error.kind,
+            vm::ErrorKind::TypeError("cannot add Integer and String".to_owned())
+        );
+        assert_eq!(
+            (error.pc, error.function.as_str(), error.span),
+            (2, "main", None)
+        );
+    }
+
+    #[test]
+    fn runtime_errors_point_at_the_source() {
+        let program = compile_unoptimized(
+            "fn main() {\n  let one: Integer = 1\n  let two: Integer = sub(one, 2)\n}",
+        )
+        .unwrap();
+        let error = vm::interpret(&program, &RunOptions::default()).unwrap_err();
+        assert_eq!(error.kind, vm::ErrorKind::IntegerOverflow);
+        assert_eq!(error.to_string(), "3:3: integer overflow (in main at pc 3)");
+        // The line table is kept in bytecode files
+        let decoded = bytecode::decode(&bytecode::encode(&program).unwrap()).unwrap();
+        assert_eq!(vm::interpret(&decoded, &RunOptions::default()), Err(error));
+    }
+
+    #[test]
+    fn unverified_slots_are_errors_not_panics() {
+        // Loading a slot before storing it is left to the verifier, but the
+        // VM still won't index out of range
+        let mut unverified = Program::from_op_codes(asm::assemble("load r1, s3\nhalt").unwrap());
+        assert_eq!(
+            verify::verify(&unverified),
+            Err(verify::VerifyError::UninitializedSlot { pc: 0, slot: 3 })
+        );
+        unverified.functions[0].variable_count = 3;
+        assert_eq!(
+            vm::interpret(&unverified, &RunOptions::default())
+                .unwrap_err()
+                .to_string(),
+            "invalid bytecode: variable slot s3 is out of range (in main at pc 0)"
+        );
+    }
+
+    #[test]
+    fn fuel_and_deadline_stop_and_resume() {
+        use crate::vm::{ErrorKind, Value, Vm};
+        let program = asm::assemble_program(
+            "load_int r1, 2\nload_int r2, 3\nadd r0, r1, r2\nstore s0, r0\nhalt",
+        )
+        .unwrap();
+        let fuel = |fuel| RunOptions {
+            fuel: Some(fuel),
+            deadline: None,
+        };
+        let mut vm = Vm::new();
+        let error = vm.run(&program, &fuel(2)).unwrap_err();
+        assert_eq!((error.kind, error.pc), (ErrorKind::OutOfFuel, 2));
+        assert_eq!(vm.registers[2], Value::Int(3));
+        let error = vm.resume(&program, &fuel(2)).unwrap_err();
+        assert_eq!((error.kind, error.pc), (ErrorKind::OutOfFuel, 4));
+        assert_eq!(vm.resume(&program, &fuel(1)), Ok(Value::Int(5)));
+        assert_eq!(vm.variables[0], Value::Int(5));
+        assert_eq!(vm.run(&program, &fuel(5)), Ok(Value::Int(5)));
+
+        let expired = RunOptions {
+            fuel: None,
+            deadline: Some(Instant::now()),
+        };
+        let mut vm = Vm::new();
+        let error = vm.run(&program, &expired).unwrap_err();
+        assert_eq!((error.kind, error.pc), (ErrorKind::DeadlineExceeded, 0));
+        assert_eq!(
+            vm.resume(&program, &RunOptions::default()),
+            Ok(Value::Int(5))
+        );
+    }
+
+    #[test]
+    fn string_values_are_shared_not_copied() {
+        use crate::vm::{Value, Vm};
+        use std::rc::Rc;
+
+        let program = asm::assemble_program(
+            r#"
+            load_string r1, "bee"
+            store s0, r1
+            load r2, s0
+            move r3, r2
+            load_string r4, "bee"
+            concat r0, r3, r4
+            halt
+            "#,
+        )
+        .unwrap();
+        let mut vm = Vm::new();
+        assert_eq!(
+            vm.run(&program, &RunOptions::default()),
+            Ok(Value::from("beebee"))
+        );
+        let Value::String(first) = &vm.registers[1] else {
+            panic!("expected a string");
+        };
+        // Moves, loads and stores share the string, and so does every load
+        // of the same constant
+        for value in [&vm.variables[0], &vm.registers[3], &vm.registers[4]] {
+            let Value::String(value) = value else {
+                panic!("expected a string");
+            };
+            assert!(Rc::ptr_eq(first, value));
+        }
+    }
+
+    #[test]
+    fn strings_are_freed_once_nothing_holds_them() {
+        use crate::code_gen::HostSignature;
+        use crate::host::HostFunction;
+        use crate::vm::{Value, Vm};
+        use std::cell::RefCell;
+        use std::rc::{Rc, Weak};
+
+        // Values can't refer to each other, so there are no cycles and a
+        // string goes as soon as the last register or slot lets go of it
+        let program = asm::assemble_program(
+            r#"
+            load_string r1, "bee"
+            concat r2, r1, r1
+            call_host r3, "watch", r2, 1
+            concat r4, r2, r1
+            store s0, r4
+            call_host r3, "watch", r4, 1
+            concat r2, r1, r1
+            load_int r4, 0
+            halt
+            "#,
+        )
+        .unwrap();
+        let watched: Rc<RefCell<Vec<Weak<str>>>> = Rc::default();
+        let watch = watched.clone();
+        let mut vm = Vm::new();
+        vm.host_functions.insert(
+            "watch".to_owned(),
+            HostFunction {
+                signature: HostSignature {
+                    name: "watch".to_owned(),
+                    binding: "watch".to_owned(),
+                    params: vec!["String".to_owned()],
+                    returns: "Bool".to_owned(),
+                },
+                function: Rc::new(move |args| match args {
+                    [Value::String(value)] => {
+                        watch.borrow_mut().push(Rc::downgrade(value));
+                        Ok(Value::Bool(true))
+                    }
+                    _ => Err("expected a string".to_owned()),
+                }),
+            },
+        );
+        vm.run(&program, &RunOptions::default()).unwrap();
+        let watched = watched.borrow();
+        // The first concat was overwritten, while the second is still in s0
+        assert!(watched[0].upgrade().is_none());
+        assert_eq!(watched[1].upgrade().as_deref(), Some("beebeebee"));
+        drop(vm);
+        assert!(watched[1].upgrade().is_none());
+    }
+
+    #[test]
+    fn values_convert_to_and_from_rust_types() {
+        use crate::vm::{ConversionError, Value};
+
+        assert_eq!(Value::from(5), Value::Int(5));
+        assert_eq!(Value::from("hi"), Value::String("hi".into()));
+        assert_eq!(Value::from(true), Value::Bool(true));
+        assert_eq!(f64::try_from(Value::from(1.5)), Ok(1.5));
+        assert_eq!(String::try_from(Value::from("hi")), Ok("hi".to_owned()));
+        assert_eq!(bool::try_from(Value::Bool(false)), Ok(false));
+        let error = usize::try_from(Value::from("hi")).unwrap_err();
+        assert_eq!(
+            error,
+            ConversionError {
+                expected: "Integer",
+                found: "String"
+            }
+        );
+        assert_eq!(error.to_string(), "expected Integer but found String");
+    }
+
+    #[test]
+    fn logic_ops_only_take_bools() {
+        use crate::Value;
+
+        let run = |source: &str| {
+            vm::interpret(
+                &asm::assemble_program(source).unwrap(),
+                &RunOptions::default(),
+            )
+        };
+        assert_eq!(
+            run("load_bool r1, True\nload_bool r2, False\nor r0, r1, r2\nnot r0\nhalt"),
+            Ok(Value::Bool(false))
+        );
+        // Bools and integers don't mix
+        assert_eq!(
+            run("load_int r1, 5\nnot r1\nhalt").unwrap_err().kind,
+            vm::ErrorKind::TypeError("cannot negate Integer".to_owned())
+        );
+        assert_eq!(
+            run("load_int r1, 1\nload_bool r2, True\nand r0, r1, r2\nhalt")
+                .unwrap_err()
+                .kind,
+            vm::ErrorKind::TypeError("cannot and Integer and Bool".to_owned())
+        );
+        assert_eq!(
+            run("load_int r1, 1\njump_if_true r1, 0\nhalt")
+                .unwrap_err()
+                .kind,
+            vm::ErrorKind::TypeError("cannot branch on Integer".to_owned())
+        );
+    }
+}